@@ -0,0 +1,65 @@
+// Splits a node's messages against what one neighbor knows, as every gossip
+// round does per neighbor, with each kind of `IdSet`, counting what the split
+// allocates. Run with `cargo bench --bench id_sets`.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use fly_io::id_set::{IdSet, IdSetKind};
+
+const MESSAGES: usize = 100_000;
+const ROUNDS: usize = 100;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn set_of(kind: IdSetKind, ids: impl IntoIterator<Item = usize>) -> IdSet {
+    let mut set = IdSet::new(kind);
+    set.extend(ids);
+    set
+}
+
+fn main() {
+    for kind in [IdSetKind::Hash, IdSetKind::Roaring] {
+        // The neighbor has every other message, and none have run out of
+        // hops.
+        let messages = set_of(kind, 0..MESSAGES);
+        let known = set_of(kind, (0..MESSAGES).step_by(2));
+        let exhausted = IdSet::new(kind);
+
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            let (unknown, already_known) = messages.split_by(&known, &exhausted);
+            assert_eq!(unknown.len() + already_known.len(), MESSAGES);
+        }
+        let elapsed = started.elapsed();
+        println!(
+            "{:>7}: {} allocations, {} bytes per split, {:?} per split",
+            format!("{:?}", kind),
+            (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ROUNDS,
+            (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes) / ROUNDS,
+            elapsed / ROUNDS as u32,
+        );
+    }
+}
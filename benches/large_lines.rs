@@ -0,0 +1,70 @@
+// Reads a file of large lines through the transport with std's default
+// buffer and with the server's, counting the reads that reach the file.
+// Run with `cargo bench --bench large_lines`.
+use std::{
+    fs::File,
+    io::{BufReader, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use fly_io::transport::{Transport, DEFAULT_READ_CAPACITY};
+
+const LINES: usize = 500;
+const LINE_BYTES: usize = 256 * 1024;
+const STD_CAPACITY: usize = 8 * 1024;
+
+struct CountingReader<R> {
+    inner: R,
+    reads: Arc<AtomicUsize>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read(buf)
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let path = std::env::temp_dir().join(format!("large-lines-{}.jsonl", std::process::id()));
+    {
+        let mut file = File::create(&path)?;
+        let seen = "1,".repeat(LINE_BYTES / 2);
+        for _ in 0..LINES {
+            writeln!(
+                file,
+                r#"{{"src":"n2","dest":"n1","body":{{"seen":[{}0]}}}}"#,
+                seen
+            )?;
+        }
+    }
+
+    for capacity in [STD_CAPACITY, DEFAULT_READ_CAPACITY] {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let reader = CountingReader {
+            inner: File::open(&path)?,
+            reads: reads.clone(),
+        };
+        let transport =
+            Transport::from_io(BufReader::with_capacity(capacity, reader), std::io::sink());
+
+        let started = Instant::now();
+        let mut lines = 0;
+        while transport.read_line()?.is_some() {
+            lines += 1;
+        }
+        println!(
+            "{:>6} byte buffer: {} lines in {:?}, {} reads",
+            capacity,
+            lines,
+            started.elapsed(),
+            reads.load(Ordering::Relaxed)
+        );
+    }
+
+    std::fs::remove_file(&path)
+}
@@ -0,0 +1,2 @@
+cargo build --bin pn_counter
+maelstrom/maelstrom test -w pn-counter --bin target/debug/pn_counter --node-count 3 --rate 100 --time-limit 20 --nemesis partition
@@ -0,0 +1,2 @@
+cargo build --bin txn
+maelstrom/maelstrom test -w txn-list-append --bin target/debug/txn --node-count 2 --time-limit 20 --rate 100 --consistency-models read-committed
@@ -1,11 +1,20 @@
 use std::{
     collections::{HashMap, HashSet},
     sync::{Arc, RwLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use fly_io::{network::Network, Body, Event, Message};
+use fly_io::{
+    bitset::{decode_bitset, encode_bitset},
+    clock,
+    id_set::{IdSet, IdSetKind},
+    network::Network,
+    protocol::{ErrorCode, Topology, TreeShape},
+    report::{Metrics, NodeReport},
+    vector_clock::VectorClock,
+    Error, Event, Message, Node,
+};
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 
@@ -23,10 +32,22 @@ enum BroadcastPayload {
     },
     Read,
     Topology {
-        topology: HashMap<String, Vec<String>>,
+        topology: Topology,
+    },
+    Replicate {
+        message: usize,
     },
+    ReplicateOk,
     Gossip {
-        seen: HashSet<usize>,
+        bits: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<VectorClock>,
+        // Hops the ids may still travel; absent means unbounded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u8>,
+    },
+    GossipOk {
+        bits: String,
     },
     BroadcastOk,
     ReadOk {
@@ -35,12 +56,287 @@ enum BroadcastPayload {
     TopologyOk,
 }
 
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(450);
+const GOSSIP_ACK_TIMEOUT: Duration = Duration::from_millis(900);
+const GOSSIP_PADDING: usize = 10;
+const MAX_GOSSIP_PADDING: usize = 100;
+// Larger gossips are split so each line stays bounded; see
+// `with_max_ids_per_message`.
+const MAX_IDS_PER_MESSAGE: usize = 1024;
+// How long a quorum broadcast waits for its majority; see
+// `with_quorum_broadcast`.
+const QUORUM_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Clone, Debug)]
 struct BroadcastNode {
     node_id: String,
-    messages: Arc<RwLock<HashSet<usize>>>,
-    neighborhood: Vec<String>,
-    known: Arc<RwLock<HashMap<String, HashSet<usize>>>>,
+    // Every other node in the cluster. It and `neighborhood` are shared so a
+    // reconfigure reaches every clone.
+    peers: Arc<RwLock<Vec<String>>>,
+    // The whole cluster in init order, which a generated neighborhood
+    // depends on.
+    node_ids: Arc<RwLock<Vec<String>>>,
+    messages: Arc<RwLock<IdSet>>,
+    neighborhood: Arc<RwLock<Vec<String>>>,
+    // What each node is known to have.
+    known: Arc<RwLock<HashMap<String, IdSet>>>,
+    // When each message was last gossiped to each neighbor, until the
+    // neighbor is known to have it.
+    unacked: Arc<RwLock<HashMap<(String, usize), Instant>>>,
+    // Already-known ids re-sent with each gossip as anti-entropy, before
+    // adjusting for loss.
+    padding: usize,
+    // Ticked on every broadcast this node accepts and merged with every
+    // clock that arrives on gossip.
+    clock: Arc<RwLock<VectorClock>>,
+    // Hops left for ids that arrived with a TTL, or were accepted here while
+    // `gossip_ttl` is set. Ids at 0 are kept but not gossiped on.
+    ttl: Arc<RwLock<HashMap<usize, u8>>>,
+    neighborhood_shape: Option<TreeShape>,
+    gossip_ttl: Option<u8>,
+    max_ids_per_message: usize,
+    id_sets: IdSetKind,
+    quorum_broadcast: bool,
+    causal_gossip: bool,
+}
+
+impl BroadcastNode {
+    // Hold each broadcast_ok until a majority of the cluster has the message,
+    // replicating it to every other node directly rather than waiting on
+    // gossip. That adds at least one round trip to the slowest node of the
+    // fastest majority to every broadcast, where the default replies at once
+    // and leaves delivery to gossip. Past `QUORUM_TIMEOUT` the broadcast is
+    // answered with a timeout error, though the message stays and keeps
+    // spreading. Off by default.
+    fn with_quorum_broadcast(mut self, quorum_broadcast: bool) -> Self {
+        self.quorum_broadcast = quorum_broadcast;
+        self
+    }
+
+    // Splits a gossip into messages of at most `max_ids_per_message` ids, so
+    // no one line grows with the number of ids sent. Defaults to
+    // `MAX_IDS_PER_MESSAGE`.
+    fn with_max_ids_per_message(mut self, max_ids_per_message: usize) -> Self {
+        assert!(max_ids_per_message > 0, "gossip needs room for an id");
+        self.max_ids_per_message = max_ids_per_message;
+        self
+    }
+
+    // Back the message and known sets with `kind`. Roaring by default; a
+    // hash set allocates two new sets per neighbor every gossip round; `cargo
+    // bench --bench id_sets` compares the two. Only takes effect before
+    // anything is stored.
+    fn with_id_sets(mut self, kind: IdSetKind) -> Self {
+        self.id_sets = kind;
+        self.messages = Arc::new(RwLock::new(IdSet::new(kind)));
+        let node_ids = self.node_ids.read().unwrap().clone();
+        self.known = Arc::new(RwLock::new(
+            node_ids
+                .into_iter()
+                .map(|id| (id, IdSet::new(kind)))
+                .collect(),
+        ));
+        self
+    }
+
+    // Ship this node's vector clock with every gossip, for experimenting with
+    // causal delivery. Plain broadcast doesn't need it, so it's off by
+    // default.
+    fn with_causal_gossip(mut self, causal_gossip: bool) -> Self {
+        self.causal_gossip = causal_gossip;
+        self
+    }
+
+    // Gossip along a generated tree or grid, or to a random half of the
+    // cluster when `None`, the default.
+    fn with_neighborhood_shape(mut self, shape: Option<TreeShape>) -> Self {
+        self.neighborhood_shape = shape;
+        let node_ids = self.node_ids.read().unwrap().clone();
+        *self.neighborhood.write().unwrap() = neighborhood_of(&self.node_id, &node_ids, shape);
+        self
+    }
+
+    // Bounds how many hops an id accepted here travels by gossip, as a safety
+    // net against a topology with cycles. Acks already stop a node re-sending
+    // what a neighbor has, so this only matters if that bookkeeping goes
+    // wrong. `None`, the default, leaves gossip unbounded.
+    fn with_gossip_ttl(mut self, ttl: Option<u8>) -> Self {
+        self.gossip_ttl = ttl;
+        self
+    }
+
+    fn gossip_round(&self, network: &Network<InjectedPayload>) -> anyhow::Result<()> {
+        let neighborhood = self.neighborhood.read().unwrap().clone();
+        let mut exhausted = IdSet::new(self.id_sets);
+        exhausted.extend(
+            self.ttl
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(_, left)| **left == 0)
+                .map(|(m, _)| *m),
+        );
+        for neighbor in &neighborhood {
+            let known = self.known.read().unwrap();
+            let messages = self.messages.read().unwrap();
+            let ttl = self.ttl.read().unwrap();
+            let mut unacked = self.unacked.write().unwrap();
+            let now = network.clock().now();
+            // Gone if a reconfigure dropped the neighbor since the clone above.
+            let Some(known_to_neighbor) = known.get(neighbor) else {
+                continue;
+            };
+            let (unknown, already_known) = messages.split_by(known_to_neighbor, &exhausted);
+
+            let mut notify_of = HashSet::new();
+            let mut resent = 0;
+            for m in unknown.iter() {
+                match unacked.get(&(neighbor.clone(), m)) {
+                    None => {}
+                    Some(sent_at) if now.duration_since(*sent_at) >= GOSSIP_ACK_TIMEOUT => {
+                        resent += 1
+                    }
+                    Some(_) => continue,
+                }
+                notify_of.insert(m);
+            }
+            if notify_of.is_empty() {
+                continue;
+            }
+            for m in &notify_of {
+                unacked.insert((neighbor.clone(), *m), now);
+            }
+
+            // Every resend means an earlier gossip or its ack was lost, so the
+            // sample grows with them.
+            let padding = (self.padding + resent).min(MAX_GOSSIP_PADDING);
+            notify_of.extend(already_known.sample(padding));
+
+            // Ids with the same hops left travel together, as one TTL covers
+            // a whole gossip.
+            let mut by_ttl: HashMap<Option<u8>, Vec<usize>> = HashMap::new();
+            for m in notify_of {
+                by_ttl.entry(ttl.get(&m).copied()).or_default().push(m);
+            }
+
+            // The receiver merges each chunk on its own, so they need no
+            // reassembly. A chunk's bitset counts from its lowest id, so
+            // chunking sorted ids keeps each one as short as its span allows.
+            for (ttl, mut notify_of) in by_ttl {
+                notify_of.sort_unstable();
+                for chunk in notify_of.chunks(self.max_ids_per_message) {
+                    let chunk = chunk.iter().copied().collect();
+                    let message = Message::new(
+                        self.node_id.clone(),
+                        neighbor.clone(),
+                        BroadcastPayload::Gossip {
+                            bits: encode_bitset(&chunk),
+                            clock: self
+                                .causal_gossip
+                                .then(|| self.clock.read().unwrap().clone()),
+                            ttl,
+                        },
+                    );
+                    network
+                        .send(message)
+                        .context(format!("gossip to {}", neighbor))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Records that `peer` has `ids`, however that was learned. Anything
+    // among them still awaiting an ack from `peer` no longer needs one.
+    fn learned(&self, peer: &str, ids: &HashSet<usize>) {
+        let mut known = self.known.write().unwrap();
+        let mut unacked = self.unacked.write().unwrap();
+        for m in ids {
+            unacked.remove(&(peer.to_string(), *m));
+        }
+        known
+            .get_mut(peer)
+            .unwrap_or_else(|| panic!("sender {} not in known nodes", peer))
+            .extend(ids.iter().copied());
+    }
+
+    // Whether, as far as this node can tell, every one of `peers` has every
+    // message it has. That's a belief built from gossip and acks, so it lags
+    // the peers' real state and only covers those that gossip with us.
+    fn converged_with(&self, peers: &[String]) -> bool {
+        let known = self.known.read().unwrap();
+        let messages = self.messages.read().unwrap();
+        peers
+            .iter()
+            .filter(|peer| **peer != self.node_id)
+            .all(|peer| {
+                known
+                    .get(peer)
+                    .is_some_and(|known| messages.is_subset(known))
+            })
+    }
+
+    // Waits out the ack timeout while anything sent is still unacked, so
+    // resends aren't attempted before they're due.
+    fn next_gossip_delay(&self) -> Duration {
+        if self.unacked.read().unwrap().is_empty() {
+            GOSSIP_INTERVAL
+        } else {
+            GOSSIP_ACK_TIMEOUT
+        }
+    }
+
+    // Sends `message` to every peer and waits until a majority of the cluster,
+    // this node included, has acknowledged it.
+    async fn await_quorum(
+        &self,
+        message: usize,
+        network: &Network<InjectedPayload>,
+    ) -> Result<(), Error> {
+        let peers = self.peers.read().unwrap().clone();
+        let cluster = peers.len() + 1;
+        let needed = cluster / 2 + 1;
+        let mut acked = 1;
+        let mut replies = network.request_all(
+            peers
+                .iter()
+                .map(|peer| {
+                    Message::new(
+                        self.node_id.clone(),
+                        peer.clone(),
+                        BroadcastPayload::Replicate { message },
+                    )
+                })
+                .collect(),
+        );
+
+        let clock = network.clock();
+        let deadline = clock.now() + QUORUM_TIMEOUT;
+        while acked < needed {
+            let left = deadline.saturating_duration_since(clock.now());
+            match clock::timeout(clock.as_ref(), left, replies.join_next()).await {
+                Some(Some(Ok((_, Ok(_))))) => acked += 1,
+                Some(Some(Ok((peer, Err(e))))) => {
+                    eprintln!("replicating {} to {} failed: {:#}", message, peer, e);
+                }
+                Some(Some(Err(e))) => eprintln!("replication task failed: {:#}", e),
+                Some(None) => {
+                    return Err(Error::Other(anyhow::anyhow!(
+                        "only {} of {} nodes acknowledged",
+                        acked,
+                        needed
+                    )))
+                }
+                None => {
+                    return Err(Error::Timeout(format!(
+                        "{} of {} acknowledgements",
+                        acked, needed
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -48,31 +344,88 @@ impl fly_io::Node<BroadcastPayload, InjectedPayload> for BroadcastNode {
     fn from_init(
         init: fly_io::protocol::Init,
         network: &fly_io::network::Network<InjectedPayload>,
-    ) -> Self {
-        let net = network.clone();
-        std::thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(450));
-            if net.inject(InjectedPayload::Gossip).is_err() {
-                break;
-            }
-        });
+    ) -> anyhow::Result<Self> {
+        network.inject_after(InjectedPayload::Gossip, GOSSIP_INTERVAL);
 
-        let mut nodes = init.node_ids.clone();
-        nodes.shuffle(&mut rand::thread_rng());
-        let neighborhood_size = (nodes.len() / 2) + 1;
-        let neighborhood = nodes[..neighborhood_size].to_vec();
+        anyhow::ensure!(!init.node_ids.is_empty(), "init contained no node ids");
+        let neighborhood = neighborhood_of(&init.node_id, &init.node_ids, None);
 
-        Self {
+        Ok(Self {
+            peers: Arc::new(RwLock::new(peers_of(&init.node_id, &init.node_ids))),
+            node_ids: Arc::new(RwLock::new(init.node_ids.clone())),
             node_id: init.node_id,
-            messages: Arc::new(RwLock::new(HashSet::new())),
-            neighborhood,
+            messages: Arc::new(RwLock::new(IdSet::new(IdSetKind::default()))),
+            neighborhood: Arc::new(RwLock::new(neighborhood)),
             known: Arc::new(RwLock::new(
                 init.node_ids
                     .into_iter()
-                    .map(|id| (id, HashSet::new()))
+                    .map(|id| (id, IdSet::new(IdSetKind::default())))
                     .collect(),
             )),
+            unacked: Arc::new(RwLock::new(HashMap::new())),
+            padding: GOSSIP_PADDING,
+            clock: Arc::new(RwLock::new(VectorClock::new())),
+            ttl: Arc::new(RwLock::new(HashMap::new())),
+            max_ids_per_message: MAX_IDS_PER_MESSAGE,
+            id_sets: IdSetKind::default(),
+            quorum_broadcast: false,
+            causal_gossip: false,
+            neighborhood_shape: None,
+            gossip_ttl: None,
+        })
+    }
+
+    fn debug_dump(&self) -> serde_json::Value {
+        let neighborhood = self.neighborhood.read().unwrap().clone();
+        serde_json::json!({
+            "messages": self.messages.read().unwrap().len(),
+            "unacked": self.unacked.read().unwrap().len(),
+            "converged": self.converged_with(&neighborhood),
+            "neighborhood": neighborhood,
+        })
+    }
+
+    fn report(&self, report: &mut NodeReport) {
+        let neighborhood = self.neighborhood.read().unwrap().clone();
+        report.record("converged", self.converged_with(&neighborhood));
+    }
+
+    fn metrics(&self, network: &Network<InjectedPayload>) -> Metrics {
+        let neighborhood = self.neighborhood.read().unwrap().clone();
+        let mut metrics = network.metrics();
+        metrics.converged = Some(self.converged_with(&neighborhood));
+        metrics.cache_sizes.extend([
+            ("messages".to_string(), self.messages.read().unwrap().len()),
+            ("unacked".to_string(), self.unacked.read().unwrap().len()),
+        ]);
+        metrics
+    }
+
+    // Gossips with a neighborhood drawn from the new cluster from the next
+    // round on. What's known about nodes that stay is kept; nodes that left
+    // are forgotten, along with anything still unacked by them.
+    fn on_reconfigure(
+        &mut self,
+        node_ids: &[String],
+        _network: &Network<InjectedPayload>,
+    ) -> anyhow::Result<()> {
+        *self.peers.write().unwrap() = peers_of(&self.node_id, node_ids);
+        *self.node_ids.write().unwrap() = node_ids.to_vec();
+        *self.neighborhood.write().unwrap() =
+            neighborhood_of(&self.node_id, node_ids, self.neighborhood_shape);
+
+        let mut known = self.known.write().unwrap();
+        known.retain(|id, _| node_ids.contains(id));
+        for id in node_ids {
+            known
+                .entry(id.clone())
+                .or_insert_with(|| IdSet::new(self.id_sets));
         }
+        self.unacked
+            .write()
+            .unwrap()
+            .retain(|(neighbor, _), _| node_ids.contains(neighbor));
+        Ok(())
     }
 
     async fn step(
@@ -81,73 +434,102 @@ impl fly_io::Node<BroadcastPayload, InjectedPayload> for BroadcastNode {
         network: &Network<InjectedPayload>,
     ) -> anyhow::Result<()> {
         match input {
+            Event::Error(error) => {
+                eprintln!("error from {}: {:?}", error.src, error.body.payload);
+            }
+            // Handed to `on_storage` instead.
             Event::Storage(_) => {}
             fly_io::Event::Injected(event) => match event {
+                // The next round is only scheduled once this one is done, so
+                // rounds can't pile up behind a slow network. Once the network
+                // shuts down `inject_after` stops scheduling and the chain ends.
                 InjectedPayload::Gossip => {
-                    for neighbor in &self.neighborhood {
-                        let known = self.known.read().unwrap();
-                        let messages = self.messages.read().unwrap();
-                        let known_to_neighbor = &known[neighbor];
-                        let (already_known, mut notify_of): (HashSet<_>, HashSet<_>) = messages
-                            .iter()
-                            .copied()
-                            .partition(|m| known_to_neighbor.contains(m));
-
-                        notify_of.extend(already_known.iter().enumerate().filter_map(|(i, m)| {
-                            if i < 10 {
-                                Some(m)
-                            } else {
-                                None
-                            }
-                        }));
-
-                        let message = Message {
-                            src: self.node_id.clone(),
-                            dst: neighbor.clone(),
-                            body: Body {
-                                id: None,
-                                in_reply_to: None,
-                                payload: BroadcastPayload::Gossip { seen: notify_of },
-                            },
-                        };
-                        network
-                            .send(message)
-                            .context(format!("gossip to {}", neighbor))?;
-                    }
+                    let round = self.gossip_round(network);
+                    network.inject_after(InjectedPayload::Gossip, self.next_gossip_delay());
+                    round?;
                 }
             },
             fly_io::Event::Message(input) => {
                 let mut reply = input.into_reply();
                 match reply.body.payload {
-                    BroadcastPayload::Gossip { seen } => {
-                        let mut known = self.known.write().unwrap();
+                    BroadcastPayload::Gossip { bits, clock, ttl } => {
+                        let seen = decode_bitset(&bits).context("decoding gossip")?;
+                        if let Some(clock) = clock {
+                            let mut ours = self.clock.write().unwrap();
+                            if ours.concurrent_with(&clock) {
+                                dbg!("CONCURRENT GOSSIP", &reply.dst);
+                            }
+                            ours.merge(&clock);
+                        }
+                        self.learned(&reply.dst, &seen);
                         let mut messages = self.messages.write().unwrap();
-                        known
-                            .get_mut(&reply.dst)
-                            .unwrap_or_else(|| panic!("sender {} not in known nodes", reply.dst))
-                            .extend(seen.clone());
 
-                        messages.extend(seen);
+                        // Receiving is one hop. An id heard along several
+                        // paths keeps the most hops it was given, and one
+                        // already held without a TTL stays unbounded.
+                        if let Some(ttl) = ttl {
+                            let mut hops = self.ttl.write().unwrap();
+                            for m in &seen {
+                                if messages.contains(*m) && !hops.contains_key(m) {
+                                    continue;
+                                }
+                                let left = hops.entry(*m).or_default();
+                                *left = (*left).max(ttl.saturating_sub(1));
+                            }
+                        }
+                        messages.extend(seen.iter().copied());
+
+                        reply.body.payload = BroadcastPayload::GossipOk { bits };
+                        network.send(reply).context("acking gossip")?;
+                    }
+                    BroadcastPayload::GossipOk { bits } => {
+                        let acked = decode_bitset(&bits).context("decoding gossip ack")?;
+                        self.learned(&reply.dst, &acked);
                     }
                     BroadcastPayload::Broadcast { message } => {
-                        let mut messages = self.messages.write().unwrap();
-                        messages.insert(message);
+                        if self.messages.write().unwrap().insert(message) {
+                            self.clock.write().unwrap().increment(&self.node_id);
+                            if let Some(ttl) = self.gossip_ttl {
+                                self.ttl.write().unwrap().insert(message, ttl);
+                            }
+                        }
+                        if self.quorum_broadcast {
+                            if let Err(e) = self.await_quorum(message, network).await {
+                                let code = e.code().unwrap_or(ErrorCode::Crash);
+                                let error = serde_json::json!({
+                                    "type": "error",
+                                    "code": usize::from(code),
+                                    "text": format!("{:#}", e),
+                                });
+                                let error = Message::new(reply.src, reply.dst, error)
+                                    .with_in_reply_to(reply.body.in_reply_to);
+                                network.send(error).context("sending broadcast error")?;
+                                return Ok(());
+                            }
+                        }
                         reply.body.payload = BroadcastPayload::BroadcastOk;
                         network.send(reply).context("sending broadcast reply")?;
                     }
                     BroadcastPayload::Read => {
-                        let messages = self.messages.read().unwrap().clone();
+                        let messages = self.messages.read().unwrap().iter().collect();
                         reply.body.payload = BroadcastPayload::ReadOk { messages };
                         network.send(reply).context("sending read reply")?;
                     }
-                    BroadcastPayload::Topology { topology: _ } => {
-                        // self.neighborhood = topology
-                        //     .remove(&self.node_id)
-                        //     .unwrap_or_else(|| panic!("node not in topology {}", self.node_id));
+                    BroadcastPayload::Topology { topology } => {
+                        if !topology.is_connected() {
+                            eprintln!("received a disconnected topology: {:?}", topology);
+                        }
+                        // self.neighborhood = topology.neighbors(&self.node_id).to_vec();
 
                         reply.body.payload = BroadcastPayload::TopologyOk;
                         network.send(reply).context("sending topology reply")?;
                     }
+                    BroadcastPayload::Replicate { message } => {
+                        self.messages.write().unwrap().insert(message);
+                        reply.body.payload = BroadcastPayload::ReplicateOk;
+                        network.send(reply).context("acking replicate")?;
+                    }
+                    BroadcastPayload::ReplicateOk => {}
                     BroadcastPayload::BroadcastOk => {}
                     BroadcastPayload::ReadOk { .. } => {}
                     BroadcastPayload::TopologyOk => {}
@@ -159,6 +541,461 @@ impl fly_io::Node<BroadcastPayload, InjectedPayload> for BroadcastNode {
     }
 }
 
+fn peers_of(node_id: &str, node_ids: &[String]) -> Vec<String> {
+    node_ids
+        .iter()
+        .filter(|id| *id != node_id)
+        .cloned()
+        .collect()
+}
+
+fn neighborhood_of(node_id: &str, node_ids: &[String], shape: Option<TreeShape>) -> Vec<String> {
+    match shape {
+        Some(shape) => Topology::spanning(shape, node_ids)
+            .neighbors(node_id)
+            .to_vec(),
+        None => {
+            let mut nodes = node_ids.to_vec();
+            nodes.shuffle(&mut rand::thread_rng());
+            let neighborhood_size = (nodes.len() / 2) + 1;
+            nodes[..neighborhood_size].to_vec()
+        }
+    }
+}
+
 fn main() -> anyhow::Result<()> {
-    fly_io::server::Server::<InjectedPayload>::new().serve::<BroadcastNode, BroadcastPayload>()
+    fly_io::server::Server::<InjectedPayload>::new().serve_with(|init, network| {
+        Ok(BroadcastNode::from_init(init, network)?
+            .with_quorum_broadcast(false)
+            .with_causal_gossip(false)
+            .with_max_ids_per_message(MAX_IDS_PER_MESSAGE)
+            .with_id_sets(IdSetKind::Roaring)
+            .with_neighborhood_shape(Some(TreeShape::Grid))
+            .with_gossip_ttl(None))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use fly_io::{clock::MockClock, protocol::UntypedMessage, testing::Cluster};
+
+    use super::*;
+
+    type Nodes = Cluster<BroadcastNode, BroadcastPayload, InjectedPayload>;
+
+    fn broadcast_to(dst: &str, message: usize) -> Message<BroadcastPayload> {
+        let mut request = Message::new("c1", dst, BroadcastPayload::Broadcast { message });
+        request.body.id = Some(1);
+        request
+    }
+
+    // Three quorum-broadcasting nodes on `clock`, with replicate requests
+    // from n1 dropped if `isolate_n1`.
+    fn quorum_cluster(clock: &Arc<MockClock>, isolate_n1: bool) -> Nodes {
+        Cluster::start_with(
+            &["n1", "n2", "n3"],
+            |node_id, network| {
+                let network = network.with_clock(clock.clone());
+                if !(isolate_n1 && node_id == "n1") {
+                    return network;
+                }
+                network.with_outbound_filter(Arc::new(|message: UntypedMessage| {
+                    (message.kind() != Some("replicate")).then_some(message)
+                }))
+            },
+            |init, network| Ok(BroadcastNode::from_init(init, network)?.with_quorum_broadcast(true)),
+        )
+        .unwrap()
+    }
+
+    fn broadcast(message: usize) -> Message<BroadcastPayload> {
+        broadcast_to("n1", message)
+    }
+
+    #[tokio::test]
+    async fn quorum_broadcast_replies_once_a_majority_has_the_message() {
+        let clock = Arc::new(MockClock::new());
+        let mut cluster = quorum_cluster(&clock, false);
+        cluster.send(broadcast(7));
+        cluster.settle().await;
+
+        let replies = cluster.take_outside();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), Some("broadcast_ok"));
+        for node_id in ["n2", "n3"] {
+            let node = cluster.node(node_id);
+            assert!(node.messages.read().unwrap().contains(7));
+        }
+    }
+
+    #[tokio::test]
+    async fn quorum_broadcast_times_out_without_a_majority() {
+        let clock = Arc::new(MockClock::new());
+        let mut cluster = quorum_cluster(&clock, true);
+        cluster.send(broadcast(7));
+        cluster.settle().await;
+        assert!(cluster.take_outside().is_empty());
+
+        clock.advance(QUORUM_TIMEOUT);
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), Some("error"));
+        assert_eq!(
+            replies[0].body.payload["code"],
+            usize::from(ErrorCode::Timeout)
+        );
+        assert!(cluster.node("n1").messages.read().unwrap().contains(7));
+        // The replicate requests given up on aren't left registered.
+        assert!(cluster.network("n1").pending_requests().is_empty());
+    }
+
+    // n2 acks nothing, but gossips 7 to n1 itself, which settles what n1
+    // sent it just as well.
+    #[tokio::test]
+    async fn gossip_from_a_neighbor_settles_what_it_was_sent() {
+        let clock = Arc::new(MockClock::new());
+        let mut cluster: Nodes = Cluster::start_with(
+            &["n1", "n2"],
+            |node_id, network| {
+                let network = network.with_clock(clock.clone());
+                if node_id != "n2" {
+                    return network;
+                }
+                network.with_outbound_filter(Arc::new(|message: UntypedMessage| {
+                    (message.kind() != Some("gossip_ok")).then_some(message)
+                }))
+            },
+            BroadcastNode::from_init,
+        )
+        .unwrap();
+        cluster.send(broadcast_to("n1", 7));
+        cluster.send(broadcast_to("n2", 7));
+        cluster.settle().await;
+
+        clock.advance(GOSSIP_INTERVAL);
+        cluster.settle().await;
+        let n1 = cluster.node("n1");
+        assert!(n1.known.read().unwrap()["n2"].contains(7));
+        assert!(n1.unacked.read().unwrap().is_empty());
+    }
+
+    // Gossiping nodes on `clock`, built with `shape` and `ttl`.
+    fn gossip_cluster(
+        clock: &Arc<MockClock>,
+        node_ids: &[&str],
+        shape: Option<TreeShape>,
+        ttl: Option<u8>,
+    ) -> Nodes {
+        Cluster::start_with(
+            node_ids,
+            |_, network| network.with_clock(clock.clone()),
+            |init, network| {
+                Ok(BroadcastNode::from_init(init, network)?
+                    .with_neighborhood_shape(shape)
+                    .with_gossip_ttl(ttl))
+            },
+        )
+        .unwrap()
+    }
+
+    async fn gossip_rounds(cluster: &mut Nodes, clock: &MockClock, rounds: usize) {
+        cluster.settle().await;
+        for _ in 0..rounds {
+            clock.advance(GOSSIP_ACK_TIMEOUT);
+            cluster.settle().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn grid_shape_gossips_with_grid_neighbors_only() {
+        let clock = Arc::new(MockClock::new());
+        let mut cluster = gossip_cluster(
+            &clock,
+            &["n1", "n2", "n3", "n4"],
+            Some(TreeShape::Grid),
+            None,
+        );
+        // A 2x2 grid: n1 and n4 sit in opposite corners.
+        assert_eq!(
+            *cluster.node("n1").neighborhood.read().unwrap(),
+            ["n2", "n3"]
+        );
+        assert_eq!(
+            *cluster.node("n4").neighborhood.read().unwrap(),
+            ["n2", "n3"]
+        );
+
+        cluster.send(broadcast_to("n1", 7));
+        gossip_rounds(&mut cluster, &clock, 3).await;
+        for node_id in ["n2", "n3", "n4"] {
+            assert!(cluster.node(node_id).messages.read().unwrap().contains(7));
+        }
+        // n4 learned it from its neighbors, never from n1 directly.
+        assert!(cluster.node("n1").known.read().unwrap()["n4"].is_empty());
+    }
+
+    #[tokio::test]
+    async fn no_shape_gossips_with_a_random_majority() {
+        let clock = Arc::new(MockClock::new());
+        let cluster = gossip_cluster(&clock, &["n1", "n2", "n3", "n4", "n5"], None, None);
+        assert_eq!(cluster.node("n1").neighborhood.read().unwrap().len(), 3);
+    }
+
+    // In the binary tree n1 -> {n2, n3}, n2 -> n4, an id accepted at n4 with
+    // one hop to go reaches n2 and stops there.
+    #[tokio::test]
+    async fn gossip_ttl_bounds_how_far_an_id_travels() {
+        let nodes = ["n1", "n2", "n3", "n4"];
+        let clock = Arc::new(MockClock::new());
+        let mut bounded = gossip_cluster(&clock, &nodes, Some(TreeShape::Binary), Some(1));
+        bounded.send(broadcast_to("n4", 7));
+        gossip_rounds(&mut bounded, &clock, 3).await;
+        assert!(bounded.node("n2").messages.read().unwrap().contains(7));
+        for node_id in ["n1", "n3"] {
+            assert!(!bounded.node(node_id).messages.read().unwrap().contains(7));
+        }
+
+        let clock = Arc::new(MockClock::new());
+        let mut unbounded = gossip_cluster(&clock, &nodes, Some(TreeShape::Binary), None);
+        unbounded.send(broadcast_to("n4", 7));
+        gossip_rounds(&mut unbounded, &clock, 3).await;
+        for node_id in nodes {
+            assert!(unbounded.node(node_id).messages.read().unwrap().contains(7));
+        }
+    }
+
+    // Every node loses every other gossip it sends to each neighbor, so ids only get through
+    // because unacked gossip is sent again.
+    #[tokio::test]
+    async fn gossip_converges_when_half_of_it_is_dropped() {
+        let clock = Arc::new(MockClock::new());
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut cluster: Nodes = Cluster::start_with(
+            &["n1", "n2", "n3"],
+            |_, network| {
+                let sent: Mutex<HashMap<String, usize>> = Mutex::default();
+                let dropped = dropped.clone();
+                network
+                    .with_clock(clock.clone())
+                    .with_outbound_filter(Arc::new(move |message: UntypedMessage| {
+                        if message.kind() != Some("gossip") {
+                            return Some(message);
+                        }
+                        let mut sent = sent.lock().unwrap();
+                        let to_dst = sent.entry(message.dst.clone()).or_default();
+                        *to_dst += 1;
+                        if to_dst.is_multiple_of(2) {
+                            return Some(message);
+                        }
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                        None
+                    }))
+            },
+            |init, network| {
+                Ok(BroadcastNode::from_init(init, network)?
+                    .with_neighborhood_shape(Some(TreeShape::Binary)))
+            },
+        )
+        .unwrap();
+        for message in 1..=4 {
+            cluster.send(broadcast_to("n1", message));
+        }
+        gossip_rounds(&mut cluster, &clock, 8).await;
+
+        assert!(dropped.load(Ordering::Relaxed) > 0);
+        for node_id in ["n2", "n3"] {
+            let messages = cluster.node(node_id).messages.read().unwrap();
+            for message in 1..=4 {
+                assert!(
+                    messages.contains(message),
+                    "{} missing {}",
+                    node_id,
+                    message
+                );
+            }
+        }
+    }
+
+    // n2's clock catches up with n1's broadcasts once they're gossiped, and
+    // only when the clock is shipped.
+    #[tokio::test]
+    async fn causal_gossip_ships_the_vector_clock() {
+        for causal in [false, true] {
+            let clock = Arc::new(MockClock::new());
+            let mut cluster: Nodes = Cluster::start_with(
+                &["n1", "n2"],
+                |_, network| network.with_clock(clock.clone()),
+                |init, network| {
+                    Ok(BroadcastNode::from_init(init, network)?
+                        .with_neighborhood_shape(Some(TreeShape::Binary))
+                        .with_causal_gossip(causal))
+                },
+            )
+            .unwrap();
+            cluster.send(broadcast_to("n1", 7));
+            cluster.send(broadcast_to("n1", 8));
+            gossip_rounds(&mut cluster, &clock, 2).await;
+
+            assert!(cluster.node("n2").messages.read().unwrap().contains(8));
+            let seen = cluster.node("n2").clock.read().unwrap().get("n1");
+            assert_eq!(seen, if causal { 2 } else { 0 });
+        }
+    }
+
+    // The ids n1 sends n2 in each gossip of its first round.
+    async fn first_gossip_chunks(max_ids_per_message: usize, ids: usize) -> Vec<usize> {
+        let clock = Arc::new(MockClock::new());
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let mut cluster: Nodes = Cluster::start_with(
+            &["n1", "n2"],
+            |node_id, network| {
+                let network = network.with_clock(clock.clone());
+                if node_id != "n1" {
+                    return network;
+                }
+                let chunks = chunks.clone();
+                network.with_outbound_filter(Arc::new(move |message: UntypedMessage| {
+                    if message.kind() == Some("gossip") {
+                        let bits = message.body.payload["bits"].as_str().unwrap();
+                        chunks
+                            .lock()
+                            .unwrap()
+                            .push(decode_bitset(bits).unwrap().len());
+                    }
+                    Some(message)
+                }))
+            },
+            |init, network| {
+                Ok(BroadcastNode::from_init(init, network)?
+                    .with_neighborhood_shape(Some(TreeShape::Binary))
+                    .with_max_ids_per_message(max_ids_per_message))
+            },
+        )
+        .unwrap();
+        for message in 0..ids {
+            cluster.send(broadcast_to("n1", 1_000 + message));
+        }
+        cluster.settle().await;
+        clock.advance(GOSSIP_INTERVAL);
+        cluster.settle().await;
+
+        let chunks = chunks.lock().unwrap().clone();
+        chunks
+    }
+
+    #[tokio::test]
+    async fn gossip_is_chunked_at_max_ids_per_message() {
+        assert_eq!(first_gossip_chunks(3, 3).await, [3]);
+        assert_eq!(first_gossip_chunks(3, 4).await, [3, 1]);
+        assert_eq!(first_gossip_chunks(3, 7).await, [3, 3, 1]);
+    }
+
+    // n1 gossips with n2 and n3, which only gossip with n1: each node
+    // believes it's converged with its neighborhood once gossip settles,
+    // while n2 can't tell what n3 has.
+    #[tokio::test]
+    async fn convergence_is_detected_once_gossip_settles() {
+        let nodes = ["n1", "n2", "n3"];
+        let clock = Arc::new(MockClock::new());
+        let mut cluster = gossip_cluster(&clock, &nodes, Some(TreeShape::Binary), None);
+        let converged = |cluster: &Nodes, node_id: &str| {
+            let node = cluster.node(node_id);
+            let neighborhood = node.neighborhood.read().unwrap().clone();
+            node.converged_with(&neighborhood)
+        };
+        for node_id in nodes {
+            assert!(converged(&cluster, node_id));
+        }
+
+        cluster.send(broadcast_to("n1", 7));
+        cluster.settle().await;
+        assert!(!converged(&cluster, "n1"));
+
+        gossip_rounds(&mut cluster, &clock, 2).await;
+        for node_id in nodes {
+            assert!(converged(&cluster, node_id), "{} not converged", node_id);
+        }
+        let everyone: Vec<String> = nodes.iter().map(|id| id.to_string()).collect();
+        assert!(!cluster.node("n2").converged_with(&everyone));
+    }
+
+    // Gossip around the ring n1 -> n2 -> n3 -> n4 -> n1 with two hops to go
+    // from n1 stops at n3, and once it has nothing more is sent.
+    #[tokio::test]
+    async fn gossip_ttl_stops_ids_going_round_a_cycle() {
+        let nodes = ["n1", "n2", "n3", "n4"];
+        let clock = Arc::new(MockClock::new());
+        let mut cluster = gossip_cluster(&clock, &nodes, None, Some(2));
+        for (i, node_id) in nodes.iter().enumerate() {
+            let next = nodes[(i + 1) % nodes.len()].to_string();
+            *cluster.node(node_id).neighborhood.write().unwrap() = vec![next];
+        }
+
+        cluster.send(broadcast_to("n1", 7));
+        gossip_rounds(&mut cluster, &clock, 4).await;
+        for node_id in ["n2", "n3"] {
+            assert!(cluster.node(node_id).messages.read().unwrap().contains(7));
+        }
+        assert!(!cluster.node("n4").messages.read().unwrap().contains(7));
+        assert_eq!(cluster.node("n3").ttl.read().unwrap()[&7], 0);
+
+        for _ in 0..3 {
+            clock.advance(GOSSIP_ACK_TIMEOUT);
+            assert_eq!(cluster.settle().await, 0);
+        }
+    }
+
+    // n1 gossips to its binary-tree children until a reconfigure drops n3 and
+    // n4, and only to n2 after.
+    #[tokio::test]
+    async fn shrinking_the_cluster_updates_gossip_targets() {
+        let clock = Arc::new(MockClock::new());
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let mut cluster: Nodes = Cluster::start_with(
+            &["n1", "n2", "n3", "n4"],
+            |node_id, network| {
+                let network = network.with_clock(clock.clone());
+                if node_id != "n1" {
+                    return network;
+                }
+                let targets = targets.clone();
+                network.with_outbound_filter(Arc::new(move |message: UntypedMessage| {
+                    if message.kind() == Some("gossip") {
+                        targets.lock().unwrap().push(message.dst.clone());
+                    }
+                    Some(message)
+                }))
+            },
+            |init, network| {
+                Ok(BroadcastNode::from_init(init, network)?
+                    .with_neighborhood_shape(Some(TreeShape::Binary)))
+            },
+        )
+        .unwrap();
+        let gossiped_to = |targets: &Mutex<Vec<String>>| {
+            let mut targets = std::mem::take(&mut *targets.lock().unwrap());
+            targets.sort();
+            targets.dedup();
+            targets
+        };
+
+        cluster.send(broadcast_to("n1", 1));
+        gossip_rounds(&mut cluster, &clock, 1).await;
+        assert_eq!(gossiped_to(&targets), ["n2", "n3"]);
+
+        let shrunk: Vec<String> = ["n1", "n2"].map(String::from).to_vec();
+        let mut n1 = cluster.node("n1").clone();
+        n1.on_reconfigure(&shrunk, cluster.network("n1")).unwrap();
+        assert_eq!(*n1.neighborhood.read().unwrap(), ["n2"]);
+
+        cluster.send(broadcast_to("n1", 2));
+        gossip_rounds(&mut cluster, &clock, 1).await;
+        assert_eq!(gossiped_to(&targets), ["n2"]);
+    }
 }
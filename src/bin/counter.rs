@@ -1,7 +1,10 @@
 use anyhow::Context;
 use fly_io::{
     network::Network,
+    protocol::NodeRole,
+    retry::Retry,
     service::{SequentialStore, Storage},
+    Node,
 };
 use serde::{Deserialize, Serialize};
 
@@ -18,6 +21,7 @@ enum CounterPayload {
 #[derive(Debug, Clone)]
 struct CounterNode {
     storage: SequentialStore,
+    role: NodeRole,
 }
 
 impl CounterNode {
@@ -25,46 +29,63 @@ impl CounterNode {
         "value".to_string()
     }
 
-    pub async fn add_to_current_value(
-        &self,
-        network: &Network,
-        delta: usize,
-    ) -> anyhow::Result<usize> {
-        let mut new_value: usize;
-        loop {
-            let current_value = self
-                .storage
-                .read(Self::storage_key(), network)
-                .await
-                .context("reading value from storage")?;
+    // As a replica the node forwards adds to its primary, e.g. so only the
+    // first node contends on the key with `NodeRole::from_init`. Every node
+    // is a primary by default.
+    fn with_role(mut self, role: NodeRole) -> Self {
+        self.role = role;
+        self
+    }
 
-            new_value = current_value + delta;
-            if self
+    // Like `read_cas`, but a retryable failure of the initial read, such as
+    // seq-kv reporting itself temporarily unavailable, backs off and starts
+    // over instead of failing the update.
+    async fn update<F>(&self, network: &Network, compute: F) -> anyhow::Result<usize>
+    where
+        F: Fn(Option<usize>) -> usize + Send + Sync,
+    {
+        let mut retry = Retry::with_policy(network.retry_policy().clone());
+        loop {
+            match self
                 .storage
-                .compare_and_store(Self::storage_key(), current_value, new_value, network)
+                .read_cas(Self::storage_key(), &compute, network)
                 .await
-                .context("adding delta")
-                .is_ok()
             {
-                return Ok(new_value);
-            };
+                Err(e) if retry.should_retry(&e) => {
+                    dbg!("SEQ-KV UPDATE FAILED", retry.failures());
+                    retry.backoff().await;
+                }
+                result => return Ok(result?),
+            }
         }
     }
+
+    pub async fn add_to_current_value(
+        &self,
+        network: &Network,
+        delta: usize,
+    ) -> anyhow::Result<usize> {
+        self.update(network, |current| current.unwrap_or(0) + delta)
+            .await
+            .context("adding delta")
+    }
 }
 
 #[async_trait::async_trait]
 impl fly_io::Node<CounterPayload> for CounterNode {
-    fn from_init(init: fly_io::protocol::Init, network: &Network) -> Self {
+    fn from_init(init: fly_io::protocol::Init, network: &Network) -> anyhow::Result<Self> {
         let result = Self {
             storage: SequentialStore::new(init.node_id),
+            role: NodeRole::Primary,
         };
 
-        result
-            .storage
-            .write(Self::storage_key(), 0, network)
-            .expect("failed to initialize storage");
+        // Creates the key on the first step, once init_ok is out: Maelstrom
+        // wants init_ok before anything else the node sends.
+        network
+            .inject(())
+            .context("scheduling storage initialization")?;
 
-        result
+        Ok(result)
     }
 
     async fn step(
@@ -73,9 +94,28 @@ impl fly_io::Node<CounterPayload> for CounterNode {
         network: &Network,
     ) -> anyhow::Result<()> {
         match event {
+            fly_io::Event::Error(error) => {
+                eprintln!("error from {}: {:?}", error.src, error.body.payload);
+            }
+            // Handed to `on_storage` instead.
             fly_io::Event::Storage(_) => {}
-            fly_io::Event::Injected(_) => {}
+            // Only creates the key if it's missing, so a restarted node doesn't
+            // reset the count, and retries until the store is reachable.
+            fly_io::Event::Injected(()) => {
+                self.update(network, |current| current.unwrap_or(0))
+                    .await
+                    .context("initializing storage")?;
+            }
             fly_io::Event::Message(message) => {
+                if let (NodeRole::Replica { primary }, CounterPayload::Add { .. }) =
+                    (&self.role, &message.body.payload)
+                {
+                    return network
+                        .forward(message, primary)
+                        .await
+                        .context("forwarding add to primary");
+                }
+
                 let mut reply = message.into_reply();
                 match reply.body.payload {
                     CounterPayload::Add { delta } => {
@@ -88,11 +128,13 @@ impl fly_io::Node<CounterPayload> for CounterNode {
                         network.send(reply).context("sending add_ok reply")?;
                     }
                     CounterPayload::Read => {
+                        // Until the init task has created the key, the count is 0.
                         let value = self
                             .storage
-                            .read(Self::storage_key(), network)
+                            .try_read(Self::storage_key(), network)
                             .await
-                            .context("reading value from storage")?;
+                            .context("reading value from storage")?
+                            .unwrap_or(0);
 
                         reply.body.payload = CounterPayload::ReadOk { value };
                         network.send(reply).context("sending read reply")?;
@@ -108,5 +150,57 @@ impl fly_io::Node<CounterPayload> for CounterNode {
 }
 
 fn main() -> anyhow::Result<()> {
-    fly_io::server::Server::new().serve::<CounterNode, CounterPayload>()
+    fly_io::server::Server::new().serve_with(|init, network| {
+        Ok(CounterNode::from_init(init, network)?.with_role(NodeRole::Primary))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use fly_io::{mock_store::MockStore, testing::Cluster, Message};
+
+    use super::*;
+
+    fn request(dst: &str, id: usize, payload: CounterPayload) -> Message<CounterPayload> {
+        let mut request = Message::new("c1", dst, payload);
+        request.body.id = Some(id);
+        request
+    }
+
+    // n2 is a replica of n1, so its add is applied by n1 and acked by n2.
+    #[tokio::test]
+    async fn replica_forwards_adds_to_the_primary() {
+        let store = MockStore::new();
+        let mut cluster = Cluster::start_with(
+            &["n1", "n2"],
+            |_, network| store.install(network),
+            |init, network| {
+                let role = NodeRole::from_init(&init);
+                Ok(CounterNode::from_init(init, network)?.with_role(role))
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            cluster.node("n2").role,
+            NodeRole::Replica {
+                primary: "n1".to_string()
+            }
+        );
+
+        cluster.send(request("n2", 1, CounterPayload::Add { delta: 3 }));
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            (replies[0].src.as_str(), replies[0].kind()),
+            ("n2", Some("add_ok"))
+        );
+        assert_eq!(replies[0].body.in_reply_to, Some(1));
+        assert_eq!(store.value("seq-kv", "value"), Some(serde_json::json!(3)));
+
+        cluster.send(request("n2", 2, CounterPayload::Read));
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        assert_eq!(replies[0].body.payload["value"], 3);
+    }
 }
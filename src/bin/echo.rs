@@ -14,8 +14,11 @@ pub struct EchoNode {}
 
 #[async_trait::async_trait]
 impl fly_io::Node<EchoPayload> for EchoNode {
-    fn from_init(_init: fly_io::protocol::Init, _network: &fly_io::network::Network) -> Self {
-        EchoNode {}
+    fn from_init(
+        _init: fly_io::protocol::Init,
+        _network: &fly_io::network::Network,
+    ) -> anyhow::Result<Self> {
+        Ok(EchoNode {})
     }
 
     async fn step(
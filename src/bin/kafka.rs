@@ -1,49 +1,258 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{self, Poll},
+    time::Duration,
 };
 
 use anyhow::Context;
+use dashmap::DashMap;
 use fly_io::{
+    lru::LruCache,
     network::Network,
-    service::{LinearStore, SequentialStore, Storage},
-    Event,
+    report::{Metrics, NodeReport},
+    retry::Retry,
+    service::{CachedStore, CasConflict, LinearStore, SequentialStore, Storage, TimestampOracle},
+    shard::{ClassStrategy, Classes},
+    Error, Event, Message, Node,
+};
+use futures::{
+    future,
+    stream::{self, BoxStream, Stream, StreamExt},
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 type Topic = String;
 type Offset = usize;
-type Entry = usize;
-type Log = Vec<Entry>;
+// serde_json keeps integers up to u64::MAX as exact integers all the way
+// through `serde_json::Value`; only larger ones fall back to f64. Its
+// arbitrary_precision feature would lift that limit but breaks numbers inside
+// the flattened message bodies, so entries are pinned to u64.
+type Entry = u64;
+type Version = u64;
+// Every entry carries the version it was appended at.
+type Log<E> = Vec<(Version, E)>;
 type CommitOffsets = HashMap<String, Offset>;
 
+// A log as it sits in lin-kv: the entries kept, and the offset of the first
+// of them, since retention trims the front. With the `gzip-logs` feature it is
+// stored as a base64 string of the gzipped JSON instead. gzip output is
+// deterministic for the same input, so re-encoding a log read back from the
+// store yields the exact value a CAS needs to match.
+#[derive(Debug, Clone)]
+struct StoredLog<E> {
+    base: Offset,
+    entries: Log<E>,
+}
+
+// `StoredLog`'s shape before compression; `L` is borrowed when writing.
+#[derive(Serialize, Deserialize)]
+struct LogRecord<L> {
+    base: Offset,
+    entries: L,
+}
+
+impl<E> StoredLog<E> {
+    // The offset the next append gets.
+    fn end(&self) -> Offset {
+        self.base + self.entries.len()
+    }
+
+    fn at(&self, offset: Offset) -> Option<&(Version, E)> {
+        self.entries.get(offset.checked_sub(self.base)?)
+    }
+
+    // Drops every entry below `offset`.
+    fn trim_to(&mut self, offset: Offset) {
+        let trimmed = offset.saturating_sub(self.base).min(self.entries.len());
+        self.entries.drain(..trimmed);
+        self.base += trimmed;
+    }
+}
+
+impl<E> Default for StoredLog<E> {
+    fn default() -> Self {
+        Self {
+            base: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<E> Deref for StoredLog<E> {
+    type Target = Log<E>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl<E> DerefMut for StoredLog<E> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.entries
+    }
+}
+
+impl<E: Serialize> Serialize for StoredLog<E> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let record = LogRecord {
+            base: self.base,
+            entries: &self.entries,
+        };
+        #[cfg(feature = "gzip-logs")]
+        {
+            use base64::Engine;
+            use std::io::Write;
+
+            let json = serde_json::to_vec(&record).map_err(serde::ser::Error::custom)?;
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(&json)
+                .map_err(serde::ser::Error::custom)?;
+            let compressed = encoder.finish().map_err(serde::ser::Error::custom)?;
+            base64::engine::general_purpose::STANDARD
+                .encode(compressed)
+                .serialize(serializer)
+        }
+        #[cfg(not(feature = "gzip-logs"))]
+        record.serialize(serializer)
+    }
+}
+
+impl<'de, E: DeserializeOwned> Deserialize<'de> for StoredLog<E> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[cfg(feature = "gzip-logs")]
+        let record: LogRecord<Log<E>> = {
+            use base64::Engine;
+            use std::io::Read;
+
+            let encoded = String::deserialize(deserializer)?;
+            let compressed = base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(serde::de::Error::custom)?;
+            let mut json = Vec::new();
+            flate2::read::GzDecoder::new(compressed.as_slice())
+                .read_to_end(&mut json)
+                .map_err(serde::de::Error::custom)?;
+            serde_json::from_slice(&json).map_err(serde::de::Error::custom)?
+        };
+        #[cfg(not(feature = "gzip-logs"))]
+        let record = LogRecord::<Log<E>>::deserialize(deserializer)?;
+        Ok(Self {
+            base: record.base,
+            entries: record.entries,
+        })
+    }
+}
+
+// One node's share of a topic's log, with sharded logs on. Only its owner
+// appends to it, so appends to a hot topic from different nodes CAS different
+// keys. The shard at index `i` of `n` hands out offsets congruent to `i` mod
+// `n` and none below `next`, so shards never collide and each one's offsets
+// grow. Any node may raise `next`, which is how a poll makes sure no shard
+// can still assign an offset below what it returns. Retention trims entries
+// below `base`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredShard<E> {
+    next: Offset,
+    base: Offset,
+    entries: Vec<(Offset, Version, E)>,
+}
+
+impl<E> StoredShard<E> {
+    // The offset the next append gets as shard `index` of `shards`.
+    fn next_offset(&self, index: usize, shards: usize) -> Offset {
+        self.next + (index + shards - self.next % shards) % shards
+    }
+}
+
+impl<E> Default for StoredShard<E> {
+    fn default() -> Self {
+        Self {
+            next: 0,
+            base: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+// Up to `POLL_BATCH` entries from `offset` on across `shards`, in offset
+// order.
+fn merge_shards<E: Clone>(
+    shards: &[StoredShard<E>],
+    offset: Offset,
+) -> Vec<(Offset, (Version, E))> {
+    let mut merged: Vec<(Offset, (Version, E))> = shards
+        .iter()
+        .flat_map(|shard| &shard.entries)
+        .filter(|(at, _, _)| *at >= offset)
+        .map(|(at, version, entry)| (*at, (*version, entry.clone())))
+        .collect();
+    merged.sort_by_key(|(at, _)| *at);
+    merged.truncate(POLL_BATCH);
+    merged
+}
+
+const LEADER_SUSPICION_THRESHOLD: Duration = Duration::from_secs(5);
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(5);
+const POLL_BATCH: usize = 3;
+// Entries are never rewritten once appended, so a cached entry can't go stale;
+// an evicted one is simply read from lin-kv again.
+const ENTRY_CACHE_CAPACITY: usize = 4096;
+type EntryCache<E> = LruCache<(Topic, Offset), (Version, E)>;
+
 struct StorageKey {}
 impl StorageKey {
     fn log(topic: &str) -> String {
         format!("{}/log", topic)
     }
 
+    fn shard(topic: &str, node_id: &str) -> String {
+        format!("{}/log/{}", topic, node_id)
+    }
+
     fn commit() -> String {
         "commits".to_string()
     }
 }
 
+// Anything the workload can send as a message. Maelstrom only sends integers,
+// so `Entry` is the default.
+trait LogEntry: Serialize + DeserializeOwned + Clone + Debug + Send + Sync + 'static {}
+impl<E> LogEntry for E where E: Serialize + DeserializeOwned + Clone + Debug + Send + Sync + 'static {}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum KafkaPayload {
+enum KafkaPayload<E = Entry> {
     Send {
         key: Topic,
-        msg: usize,
+        msg: E,
     },
     SendOk {
         offset: Offset,
     },
     Poll {
         offsets: HashMap<Topic, Offset>,
+        // Asks for the polled topics' committed offsets in the reply too.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        include_committed: bool,
     },
     PollOk {
-        msgs: HashMap<Topic, Vec<(Offset, Entry)>>,
+        msgs: HashMap<Topic, Vec<(Offset, E)>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        committed: Option<HashMap<Topic, Offset>>,
+        // Topics polled below what retention kept, with their first offset.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        trimmed: HashMap<Topic, Offset>,
     },
     CommitOffsets {
         offsets: HashMap<Topic, Offset>,
@@ -57,21 +266,250 @@ enum KafkaPayload {
     },
 }
 
+// The cluster and the classes its topics are spread over, which are rebuilt
+// together whenever the cluster changes.
+#[derive(Clone, Debug)]
+struct Membership {
+    // Sorted, so every node picks the same leader for a class.
+    node_ids: Vec<String>,
+    classes: Classes,
+}
+
+impl Membership {
+    fn new(mut node_ids: Vec<String>, strategy: ClassStrategy) -> Self {
+        node_ids.sort();
+        let classes = Classes::new(strategy, node_ids.len());
+        Self { node_ids, classes }
+    }
+
+    // Every topic belongs to exactly one class, and every class is led by one
+    // node. Only the class leader appends to a topic's log, so offsets for a
+    // topic are assigned in a single place and the lin-kv CAS only contends
+    // between the leader's own concurrent appends.
+    fn class_of(&self, topic: &str) -> usize {
+        self.classes.class_of(topic)
+    }
+
+    // Which of how many shards `node_id` owns, with sharded logs on.
+    fn shard_of(&self, node_id: &str) -> (usize, usize) {
+        let index = self
+            .node_ids
+            .iter()
+            .position(|node| node == node_id)
+            .expect("node owns no shard");
+        (index, self.node_ids.len())
+    }
+}
+
+// Where an append landed, and how many times its CAS lost to another write
+// on the way there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Appended {
+    offset: Offset,
+    cas_failures: usize,
+}
+
 #[derive(Clone)]
-struct KafkaNode {
+struct KafkaNode<E = Entry> {
+    node_id: String,
+    membership: Arc<RwLock<Membership>>,
+    class_strategy: ClassStrategy,
     linear_store: LinearStore,
+    // The logs this node appends to, through a cache of its own writes. Only
+    // a topic's class leader appends, so its last append is usually still
+    // the log's current value and the next one needn't read it first. When
+    // another node did append, the CAS fails with the current log instead.
+    log_store: CachedStore<LinearStore>,
     sequential_store: SequentialStore,
+    oracle: TimestampOracle,
+    snapshot_polls: bool,
+    verify_offsets: bool,
+    failover_leaders: bool,
+    log_retention: Option<usize>,
+    sharded_logs: bool,
+    // Set once the first compaction round is scheduled, on the first append
+    // with retention on; each round schedules the next.
+    compaction_scheduled: Arc<AtomicBool>,
+    last_offsets: Arc<RwLock<HashMap<Topic, Offset>>>,
+    entries: Arc<Mutex<EntryCache<E>>>,
+    // Appends to the same topic take turns here, so this node's own appends
+    // don't race each other's CAS. Different topics still run in parallel.
+    append_locks: Arc<DashMap<Topic, Arc<tokio::sync::Mutex<()>>>>,
+    // The first offset each topic's log still holds, as of the last read.
+    log_starts: Arc<DashMap<Topic, Offset>>,
     pub cas_failures: Arc<RwLock<usize>>,
+    // Topics whose stored log, read back at shutdown, ends before an offset
+    // this node handed out for it.
+    lost_offsets: Arc<RwLock<usize>>,
     pub total_appends: Arc<RwLock<usize>>,
+    _entry: PhantomData<E>,
 }
 
-impl KafkaNode {
-    pub fn new(node_id: String) -> Self {
+impl<E> KafkaNode<E>
+where
+    E: LogEntry,
+{
+    pub fn new(node_id: String, node_ids: Vec<String>) -> Self {
         Self {
+            node_id: node_id.clone(),
+            membership: Arc::new(RwLock::new(Membership::new(
+                node_ids,
+                ClassStrategy::Consistent,
+            ))),
+            class_strategy: ClassStrategy::Consistent,
             linear_store: LinearStore::new(node_id.clone()),
+            log_store: CachedStore::new(LinearStore::new(node_id.clone())),
             sequential_store: SequentialStore::new(node_id.clone()),
+            oracle: TimestampOracle::new(node_id.clone()),
+            snapshot_polls: false,
+            verify_offsets: false,
+            failover_leaders: false,
+            log_retention: None,
+            sharded_logs: false,
+            compaction_scheduled: Arc::new(AtomicBool::new(false)),
+            last_offsets: Arc::new(RwLock::new(HashMap::new())),
+            entries: Arc::new(Mutex::new(LruCache::new(ENTRY_CACHE_CAPACITY))),
+            append_locks: Arc::new(DashMap::new()),
+            log_starts: Arc::new(DashMap::new()),
             cas_failures: Arc::new(RwLock::new(0)),
+            lost_offsets: Arc::new(RwLock::new(0)),
             total_appends: Arc::new(RwLock::new(0)),
+            _entry: PhantomData,
+        }
+    }
+
+    // How topics are spread over classes; see `ClassStrategy`. Consistent by
+    // default, so a reconfigure only moves the topics of the classes that
+    // came or went.
+    fn with_class_strategy(mut self, strategy: ClassStrategy) -> Self {
+        self.class_strategy = strategy;
+        let node_ids = self.membership.read().unwrap().node_ids.clone();
+        *self.membership.write().unwrap() = Membership::new(node_ids, strategy);
+        self
+    }
+
+    // Version every append with a lin-tso timestamp and have polls read every
+    // topic as of one, so a poll sees all topics at the same point in time.
+    // Costs a lin-tso round trip per append and per poll. Off by default.
+    fn with_snapshot_polls(mut self, snapshot_polls: bool) -> Self {
+        self.snapshot_polls = snapshot_polls;
+        self
+    }
+
+    // Report offsets handed out for a topic that don't grow; see
+    // `verify_offset`. Off by default.
+    fn with_verify_offsets(mut self, verify_offsets: bool) -> Self {
+        self.verify_offsets = verify_offsets;
+        self
+    }
+
+    // Move a class to another node while its leader seems down; see
+    // `class_leader`. Off by default.
+    fn with_failover_leaders(mut self, failover_leaders: bool) -> Self {
+        self.failover_leaders = failover_leaders;
+        self
+    }
+
+    // How many entries below a topic's committed offset its log keeps. Every
+    // `COMPACTION_INTERVAL` a class leader trims the logs it appends to down
+    // to that; `None`, the default, keeps logs whole. Polls below the trimmed
+    // front get the topic's first remaining offset back in `trimmed` instead
+    // of messages.
+    fn with_log_retention(mut self, retention: Option<usize>) -> Self {
+        self.log_retention = retention;
+        self
+    }
+
+    // Split every topic's log into a shard per node, which each node appends
+    // its own sends to instead of forwarding them to the class leader; see
+    // `StoredShard`. Appends from different nodes then never race for one
+    // key, but a poll reads every shard of a topic and may have to raise the
+    // ones lagging behind. Shards are fixed to the cluster at init, so a
+    // reconfigure that changes it is refused. Off by default.
+    fn with_sharded_logs(mut self, sharded_logs: bool) -> Self {
+        self.sharded_logs = sharded_logs;
+        self
+    }
+
+    // With failover on, a class whose leader hasn't been heard from within
+    // `LEADER_SUSPICION_THRESHOLD` moves to the next node in sorted order that
+    // has been, wrapping around; this node always counts as live. Peers that
+    // are merely quiet get suspected too, and two nodes can briefly both act as
+    // a class's leader, but appends stay correct since the log CAS serializes
+    // them anyway.
+    fn class_leader(&self, topic: &str, network: &Network) -> String {
+        let membership = self.membership.read().unwrap();
+        let node_ids = &membership.node_ids;
+        let class = membership.class_of(topic);
+        if !self.failover_leaders {
+            return node_ids[class].clone();
+        }
+
+        let suspected = network.suspected_down(LEADER_SUSPICION_THRESHOLD);
+        (0..node_ids.len())
+            .map(|i| &node_ids[(class + i) % node_ids.len()])
+            .find(|node| **node == self.node_id || !suspected.contains(node))
+            .unwrap_or(&self.node_id)
+            .clone()
+    }
+
+    async fn send_to_class_leader(
+        &mut self,
+        topic: Topic,
+        entry: E,
+        network: &Network,
+    ) -> anyhow::Result<Offset> {
+        let leader = self.class_leader(&topic, network);
+        // Leading the topic ourselves, so skip the round trip through
+        // Maelstrom and append directly.
+        if network.is_self(&leader) {
+            let appended = self.append_entry(topic, entry, network).await?;
+            return Ok(appended.offset);
+        }
+
+        let message = Message::new(
+            self.node_id.clone(),
+            leader.clone(),
+            KafkaPayload::Send {
+                key: topic,
+                msg: entry,
+            },
+        );
+
+        let response = network
+            .request(message)
+            .await
+            .context(format!("forwarding send to class leader {}", leader))?;
+
+        match response.body.payload {
+            KafkaPayload::SendOk { offset } => Ok(offset),
+            payload => Err(anyhow::anyhow!(
+                "unexpected reply from class leader {}: {:?}",
+                leader,
+                payload
+            )),
+        }
+    }
+
+    // Offsets this node hands out for a topic must keep growing. Sends that
+    // complete concurrently can be checked out of order, so a report is worth
+    // a look at the log rather than proof of a bug.
+    fn verify_offset(&self, topic: &Topic, offset: Offset) {
+        if !self.verify_offsets {
+            return;
+        }
+
+        let mut last_offsets = self.last_offsets.write().unwrap();
+        match last_offsets.get(topic) {
+            Some(last) if offset <= *last => {
+                eprintln!(
+                    "OFFSET NOT MONOTONIC: topic {} handed out {} after {}",
+                    topic, offset, last
+                );
+            }
+            _ => {
+                last_offsets.insert(topic.clone(), offset);
+            }
         }
     }
 
@@ -85,136 +523,629 @@ impl KafkaNode {
         T: Send + Serialize + DeserializeOwned + Default + Clone,
         STORAGE: Storage<()> + Sync,
     {
-        if let Ok(value) = storage.read::<T>(key.clone(), network).await {
-            return Ok(value);
-        };
+        self.read_or_create_with(key, storage, T::default, network)
+            .await
+    }
 
-        let value = T::default();
-        if storage
-            .compare_and_store(key, value.clone(), value.clone(), network)
+    // Like `read_or_create`, but a missing key starts out as `init()` rather
+    // than the type's default. `init` only runs when the key is missing.
+    pub async fn read_or_create_with<T, STORAGE, F>(
+        &self,
+        key: String,
+        storage: &STORAGE,
+        init: F,
+        network: &Network,
+    ) -> anyhow::Result<T>
+    where
+        T: Send + Serialize + DeserializeOwned + Clone,
+        STORAGE: Storage<()> + Sync,
+        F: FnOnce() -> T + Send,
+    {
+        if let Some(value) = storage
+            .try_read::<T>(key.clone(), network)
             .await
-            .is_ok()
+            .context("reading value")?
         {
             return Ok(value);
         };
 
-        panic!("could not read or set value");
+        let value = init();
+        match storage
+            .compare_and_store(key.clone(), value.clone(), value.clone(), network)
+            .await
+        {
+            Ok(()) => Ok(value),
+            // Another node created it since the read, and its value stands.
+            Err(Error::CasConflict(_)) => storage
+                .read(key, network)
+                .await
+                .context("reading value created concurrently"),
+            Err(e) => Err(e).context("creating value"),
+        }
     }
 
     async fn append_entry(
         &mut self,
         topic: String,
-        entry: Entry,
+        entry: E,
         network: &Network,
-    ) -> anyhow::Result<Offset> {
-        let key = StorageKey::log(&topic);
+    ) -> anyhow::Result<Appended> {
+        if self.log_retention.is_some() && !self.compaction_scheduled.swap(true, Ordering::Relaxed)
+        {
+            network.inject_after((), COMPACTION_INTERVAL);
+        }
+        // Every storage RPC this append issues shares one id scope in traces.
+        let scope = network.id_scope();
+        let network = &*scope;
+        let lock = self.append_locks.entry(topic.clone()).or_default().clone();
+        let _guard = lock.lock().await;
 
         *self.total_appends.write().unwrap() += 1;
+        if !self.sharded_logs {
+            let key = StorageKey::log(&topic);
+            return self
+                .append_with(topic, key, entry, network, |log: &StoredLog<E>, entry| {
+                    let mut appended = log.clone();
+                    appended.push(entry);
+                    (log.end(), appended)
+                })
+                .await;
+        }
+
+        let (index, shards) = self.membership.read().unwrap().shard_of(&self.node_id);
+        let key = StorageKey::shard(&topic, &self.node_id);
+        self.append_with(
+            topic,
+            key,
+            entry,
+            network,
+            |shard: &StoredShard<E>, (version, entry)| {
+                let offset = shard.next_offset(index, shards);
+                let mut appended = shard.clone();
+                appended.entries.push((offset, version, entry));
+                appended.next = offset + 1;
+                (offset, appended)
+            },
+        )
+        .await
+    }
+
+    // CASes `entry` onto the log under `key`, which `append` places it in,
+    // retrying on whatever the CAS lost to.
+    async fn append_with<L, F>(
+        &self,
+        topic: Topic,
+        key: String,
+        entry: E,
+        network: &Network,
+        append: F,
+    ) -> anyhow::Result<Appended>
+    where
+        L: Serialize + DeserializeOwned + Default + Clone + Send + Sync,
+        F: Fn(&L, (Version, E)) -> (Offset, L),
+    {
+        let mut log = self
+            .read_or_create::<L, _>(key.clone(), &self.log_store, network)
+            .await
+            .context("reading log")?;
+        let mut retry = Retry::with_policy(network.retry_policy().clone());
+        let mut cas_failures = 0;
         loop {
-            let mut log = self
-                .read_or_create::<Log, _>(key.clone(), &self.linear_store, network)
+            let version = self.next_version(network).await?;
+            let (offset, appended) = append(&log, (version, entry.clone()));
+
+            match self
+                .log_store
+                .cas_or_current(key.clone(), log, appended, network)
                 .await
-                .context("reading log")?;
+            {
+                Ok(Ok(())) => {
+                    self.entries
+                        .lock()
+                        .unwrap()
+                        .put((topic, offset), (version, entry));
+                    return Ok(Appended {
+                        offset,
+                        cas_failures,
+                    });
+                }
+                Ok(Err(CasConflict { current })) => {
+                    let conflict = fly_io::Error::CasConflict(key.clone());
+                    if !retry.should_retry(&conflict) {
+                        return Err(conflict.into());
+                    }
+                    log = current;
+                }
+                Err(e) => {
+                    if !retry.should_retry(&e) {
+                        return Err(e).context("appending to log");
+                    }
+                    log = self
+                        .read_or_create::<L, _>(key.clone(), &self.log_store, network)
+                        .await
+                        .context("reading log")?;
+                }
+            }
 
-            let offset = log.len();
-            log.push(entry);
+            cas_failures += 1;
+            *self.cas_failures.write().unwrap() += 1;
+            retry.backoff().await;
+        }
+    }
 
+    // Trims the logs this node has appended to down to `retention` entries
+    // below their committed offset, or with sharded logs its own shards. The
+    // trim is a CAS on the log like an append, so the two can't lose each
+    // other's writes.
+    async fn compact_logs(&self, retention: usize, network: &Network) -> anyhow::Result<()> {
+        let commits = self
+            .sequential_store
+            .try_read::<CommitOffsets>(StorageKey::commit(), network)
+            .await
+            .context("reading commits for compaction")?
+            .unwrap_or_default();
+        let topics: Vec<Topic> = self
+            .append_locks
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for topic in topics {
+            let Some(committed) = commits.get(&topic) else {
+                continue;
+            };
+            let keep_from = committed.saturating_sub(retention);
+            // A topic's start is the furthest any shard was trimmed, which
+            // says nothing about this node's own shard.
+            if self.sharded_logs {
+                self.log_store
+                    .read_cas(
+                        StorageKey::shard(&topic, &self.node_id),
+                        |shard: Option<StoredShard<E>>| {
+                            let mut shard = shard.unwrap_or_default();
+                            shard.entries.retain(|(offset, _, _)| *offset >= keep_from);
+                            shard.base = shard.base.max(keep_from);
+                            shard
+                        },
+                        network,
+                    )
+                    .await
+                    .context(format!("compacting shard for {}", topic))?;
+                continue;
+            }
             if self
-                .linear_store
-                .compare_and_store(key.clone(), log[..log.len() - 1].to_vec(), log, network)
-                .await
-                .is_ok()
+                .log_starts
+                .get(&topic)
+                .is_some_and(|start| *start >= keep_from)
             {
-                return Ok(offset);
+                continue;
             }
 
-            *self.cas_failures.write().unwrap() += 1;
+            let log = self
+                .log_store
+                .read_cas(
+                    StorageKey::log(&topic),
+                    |log: Option<StoredLog<E>>| {
+                        let mut log = log.unwrap_or_default();
+                        log.trim_to(keep_from);
+                        log
+                    },
+                    network,
+                )
+                .await
+                .context(format!("compacting log for {}", topic))?;
+            self.log_starts.insert(topic, log.base);
         }
+        Ok(())
     }
 
+    // Snapshot polls version every append with a timestamp from lin-tso, taken
+    // after the log was read. Anything already in the log got its timestamp
+    // before that read, so versions only ever grow along a log.
+    async fn next_version(&self, network: &Network) -> anyhow::Result<Version> {
+        if !self.snapshot_polls {
+            return Ok(0);
+        }
+
+        self.oracle
+            .timestamp(network)
+            .await
+            .context("fetching append version")
+    }
+
+    // The timestamp a poll reads every topic as of, if snapshot polls are on.
+    // Entries versioned after it are hidden so every topic reflects the same
+    // point in time. The snapshot can still be stale: an append that took its
+    // version before the poll but lands after the poll read the log is
+    // missing, so the view lags by at most one append round trip.
+    async fn read_version(&self, network: &Network) -> anyhow::Result<Option<Version>> {
+        if !self.snapshot_polls {
+            return Ok(None);
+        }
+
+        let ts = self
+            .oracle
+            .timestamp(network)
+            .await
+            .context("fetching poll version")?;
+        Ok(Some(ts))
+    }
+
+    // Serves a batch from `LogStream`, keeping only what was appended at or
+    // before `read_version`. Nothing is served below a trimmed front, even
+    // while the entry cache still has it.
     async fn select_entries(
         &self,
         topic: String,
         requested_offset: Offset,
+        read_version: Option<Version>,
         network: &Network,
-    ) -> Option<Vec<(Offset, Entry)>> {
-        let Ok(log) = self
-            .linear_store
-            .read::<Log>(StorageKey::log(&topic), network)
-            .await
-        else {
+    ) -> Option<Vec<(Offset, E)>> {
+        if self
+            .log_starts
+            .get(&topic)
+            .is_some_and(|start| requested_offset < *start)
+        {
             return None;
-        };
+        }
+        if self.sharded_logs {
+            return self
+                .select_from_shards(topic, requested_offset, read_version, network)
+                .await;
+        }
+
+        let selected = LogStream::new(self, topic, requested_offset, POLL_BATCH, network)
+            .take_while(|(_, (version, _))| {
+                future::ready(read_version.is_none_or(|read| *version <= read))
+            })
+            .map(|(offset, (_, entry))| (offset, entry))
+            .collect::<Vec<_>>()
+            .await;
 
-        if log.len() <= requested_offset {
+        if selected.is_empty() {
             return None;
         }
 
-        let n_logs = std::cmp::min(3, log.len() - requested_offset);
-        let selected = log[requested_offset..requested_offset + n_logs]
-            .iter()
-            .cloned()
-            .enumerate()
-            .map(|(i, entry)| (requested_offset + i, entry))
-            .collect::<Vec<_>>();
-
         Some(selected)
     }
+
+    // `select_entries` with sharded logs: every shard of the topic is read and
+    // merged in offset order. An offset is only served once every shard's
+    // `next` is past it, since until then a shard could still assign a lower
+    // one, so shards lagging behind the entries wanted are raised first. A
+    // raise reads the shard again, so an append that landed meanwhile is
+    // merged in rather than skipped.
+    async fn select_from_shards(
+        &self,
+        topic: Topic,
+        requested_offset: Offset,
+        read_version: Option<Version>,
+        network: &Network,
+    ) -> Option<Vec<(Offset, E)>> {
+        let owners = self.membership.read().unwrap().node_ids.clone();
+        let mut shards = Vec::with_capacity(owners.len());
+        for owner in &owners {
+            let shard = self
+                .linear_store
+                .try_read::<StoredShard<E>>(StorageKey::shard(&topic, owner), network)
+                .await
+                .ok()?
+                .unwrap_or_default();
+            shards.push(shard);
+        }
+        let start = shards.iter().map(|shard| shard.base).max()?;
+        self.log_starts.insert(topic.clone(), start);
+        if requested_offset < start {
+            return None;
+        }
+
+        let wanted = merge_shards(&shards, requested_offset).last()?.0 + 1;
+        for (owner, shard) in owners.iter().zip(shards.iter_mut()) {
+            if shard.next >= wanted {
+                continue;
+            }
+            *shard = self
+                .linear_store
+                .read_cas(
+                    StorageKey::shard(&topic, owner),
+                    |shard: Option<StoredShard<E>>| {
+                        let mut shard = shard.unwrap_or_default();
+                        shard.next = shard.next.max(wanted);
+                        shard
+                    },
+                    network,
+                )
+                .await
+                .ok()?;
+        }
+
+        let settled = shards.iter().map(|shard| shard.next).min()?;
+        let selected: Vec<(Offset, E)> = merge_shards(&shards, requested_offset)
+            .into_iter()
+            .take_while(|(offset, (version, _))| {
+                *offset < settled && read_version.is_none_or(|read| *version <= read)
+            })
+            .map(|(offset, (_, entry))| (offset, entry))
+            .collect();
+        (!selected.is_empty()).then_some(selected)
+    }
 }
 
-impl Drop for KafkaNode {
-    fn drop(&mut self) {
-        let cas_failures = *self.cas_failures.read().unwrap();
-        let total_appends = *self.total_appends.read().unwrap();
-        eprintln!(
-            "CAS FAILURES: {} / TOTAL APPENDS: {}",
-            cas_failures, total_appends
-        );
+// Up to `limit` of a topic's entries from some offset on, in order. Each one
+// comes from the entry cache while it has it; at the first miss the log is
+// read from lin-kv once and serves the rest, and only the entries the stream
+// can still yield are cached from it, so one poll can't flush the cache. The
+// stream ends at the end of that read, below its trimmed front, or right away
+// if the read fails, so it never yields past a gap.
+struct LogStream<'a, E> {
+    inner: BoxStream<'a, (Offset, (Version, E))>,
+}
+
+impl<'a, E> LogStream<'a, E>
+where
+    E: LogEntry,
+{
+    fn new(
+        node: &'a KafkaNode<E>,
+        topic: Topic,
+        offset: Offset,
+        limit: usize,
+        network: &'a Network,
+    ) -> Self {
+        let end = offset.saturating_add(limit);
+        let cursor = (offset, None::<StoredLog<E>>);
+        let inner = stream::unfold(cursor, move |(offset, mut log)| {
+            let topic = topic.clone();
+            async move {
+                if offset >= end {
+                    return None;
+                }
+                let cached = node
+                    .entries
+                    .lock()
+                    .unwrap()
+                    .get(&(topic.clone(), offset))
+                    .cloned();
+                if let Some(entry) = cached {
+                    return Some(((offset, entry), (offset + 1, log)));
+                }
+
+                if log.is_none() {
+                    let read = node
+                        .linear_store
+                        .read::<StoredLog<E>>(StorageKey::log(&topic), network)
+                        .await
+                        .ok()?;
+                    node.log_starts.insert(topic.clone(), read.base);
+                    let mut entries = node.entries.lock().unwrap();
+                    let skip = offset.saturating_sub(read.base);
+                    let window = read.iter().enumerate().skip(skip).take(end - offset);
+                    for (i, entry) in window {
+                        entries.put((topic.clone(), read.base + i), entry.clone());
+                    }
+                    log = Some(read);
+                }
+
+                let entry = log.as_ref()?.at(offset)?.clone();
+                Some(((offset, entry), (offset + 1, log)))
+            }
+        })
+        .boxed();
+        Self { inner }
+    }
+}
+
+impl<E> Stream for LogStream<'_, E> {
+    type Item = (Offset, (Version, E));
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
     }
 }
 
 #[async_trait::async_trait]
-impl fly_io::Node<KafkaPayload> for KafkaNode {
-    fn from_init(init: fly_io::protocol::Init, _network: &Network) -> Self {
-        Self::new(init.node_id)
+impl<E> fly_io::Node<KafkaPayload<E>> for KafkaNode<E>
+where
+    E: LogEntry,
+{
+    fn from_init(init: fly_io::protocol::Init, _network: &Network) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            init.node_ids.contains(&init.node_id),
+            "node {} missing from init node ids",
+            init.node_id
+        );
+        Ok(Self::new(init.node_id, init.node_ids))
+    }
+
+    fn report(&self, report: &mut NodeReport) {
+        report.record("cas_failures", *self.cas_failures.read().unwrap());
+        report.record("total_appends", *self.total_appends.read().unwrap());
+        if self.verify_offsets {
+            report.record("lost_offsets", *self.lost_offsets.read().unwrap());
+        }
+    }
+
+    // With offsets verified, reads back every log this node handed offsets
+    // out of, since only the stored log shows whether a send's offset held.
+    async fn finalize(&mut self, network: &Network) -> anyhow::Result<()> {
+        if !self.verify_offsets {
+            return Ok(());
+        }
+
+        let last_offsets = self.last_offsets.read().unwrap().clone();
+        for (topic, last) in last_offsets {
+            let end = if self.sharded_logs {
+                self.linear_store
+                    .try_read::<StoredShard<E>>(StorageKey::shard(&topic, &self.node_id), network)
+                    .await
+                    .context(format!("reading back shard for {}", topic))?
+                    .map_or(0, |shard| shard.next)
+            } else {
+                self.linear_store
+                    .try_read::<StoredLog<E>>(StorageKey::log(&topic), network)
+                    .await
+                    .context(format!("reading back log for {}", topic))?
+                    .map_or(0, |log| log.end())
+            };
+            if last >= end {
+                eprintln!(
+                    "OFFSET LOST: topic {} handed out {} but its log ends at {}",
+                    topic, last, end
+                );
+                *self.lost_offsets.write().unwrap() += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Topics move to the leaders of the new classes from the next send on.
+    // Appends already under way finish where they started; the log CAS keeps
+    // them correct while old and new leaders overlap. Sharded logs have no
+    // leaders, but their offsets depend on the shard count, so there the
+    // cluster can't change.
+    fn on_reconfigure(&mut self, node_ids: &[String], _network: &Network) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            node_ids.contains(&self.node_id),
+            "node {} missing from reconfigured node ids",
+            self.node_id
+        );
+        let membership = Membership::new(node_ids.to_vec(), self.class_strategy);
+        anyhow::ensure!(
+            !self.sharded_logs || membership.node_ids == self.membership.read().unwrap().node_ids,
+            "sharded logs can't follow a reconfigure to {:?}",
+            node_ids
+        );
+        *self.membership.write().unwrap() = membership;
+        Ok(())
+    }
+
+    fn metrics(&self, network: &Network) -> Metrics {
+        let mut metrics = network.metrics();
+        metrics.cas_failures = Some(*self.cas_failures.read().unwrap());
+        metrics.cache_sizes.extend([
+            ("entries".to_string(), self.entries.lock().unwrap().len()),
+            ("log_starts".to_string(), self.log_starts.len()),
+        ]);
+        metrics
+    }
+
+    fn debug_dump(&self) -> serde_json::Value {
+        serde_json::json!({
+            "cached_entries": self.entries.lock().unwrap().len(),
+            "cas_failures": *self.cas_failures.read().unwrap(),
+            "total_appends": *self.total_appends.read().unwrap(),
+        })
     }
 
     async fn step(
         &mut self,
-        event: Event<KafkaPayload, ()>,
+        event: Event<KafkaPayload<E>, ()>,
         network: &Network,
     ) -> anyhow::Result<()> {
         match event {
+            Event::Error(error) => {
+                eprintln!("error from {}: {:?}", error.src, error.body.payload);
+            }
+            // Handed to `on_storage` instead.
             Event::Storage(_) => {}
-            Event::Injected(_) => {}
+            // Compaction rounds, each scheduled once the last is done.
+            Event::Injected(()) => {
+                if let Some(retention) = self.log_retention {
+                    let compacted = self.compact_logs(retention, network).await;
+                    network.inject_after((), COMPACTION_INTERVAL);
+                    compacted?;
+                }
+            }
             Event::Message(message) => {
                 let mut reply = message.into_reply();
                 if let Some(payload) = match reply.body.payload {
                     KafkaPayload::Send { key, msg } => {
-                        let offset = self
-                            .append_entry(key, msg, network)
-                            .await
-                            .context("adding message")?;
+                        let offset = if self.sharded_logs {
+                            self.append_entry(key.clone(), msg, network)
+                                .await
+                                .map(|appended| appended.offset)
+                        } else {
+                            self.send_to_class_leader(key.clone(), msg, network).await
+                        }
+                        .context("adding message")?;
+                        self.verify_offset(&key, offset);
 
                         Some(KafkaPayload::SendOk { offset })
                     }
                     KafkaPayload::SendOk { .. } => None,
-                    KafkaPayload::Poll { offsets } => {
+                    KafkaPayload::Poll {
+                        offsets,
+                        include_committed,
+                    } => {
+                        let committed = if include_committed {
+                            let commits = self
+                                .read_or_create::<CommitOffsets, _>(
+                                    StorageKey::commit(),
+                                    &self.sequential_store,
+                                    network,
+                                )
+                                .await
+                                .context("reading commits for poll")?;
+                            Some(
+                                commits
+                                    .into_iter()
+                                    .filter(|(topic, _)| offsets.contains_key(topic))
+                                    .collect(),
+                            )
+                        } else {
+                            None
+                        };
+                        let read_version = self.read_version(network).await?;
                         let mut result = HashMap::new();
+                        let mut trimmed = HashMap::new();
                         for (topic, requested_offset) in offsets.into_iter() {
-                            if let Some(selected) = self
-                                .select_entries(topic.clone(), requested_offset, network)
+                            match self
+                                .select_entries(
+                                    topic.clone(),
+                                    requested_offset,
+                                    read_version,
+                                    network,
+                                )
                                 .await
                             {
-                                result.insert(topic, selected);
+                                Some(selected) => {
+                                    result.insert(topic, selected);
+                                }
+                                None => {
+                                    let start = self.log_starts.get(&topic).map(|start| *start);
+                                    if let Some(start) =
+                                        start.filter(|start| requested_offset < *start)
+                                    {
+                                        trimmed.insert(topic, start);
+                                    }
+                                }
                             }
                         }
-                        Some(KafkaPayload::PollOk { msgs: result })
+                        Some(KafkaPayload::PollOk {
+                            msgs: result,
+                            committed,
+                            trimmed,
+                        })
                     }
                     KafkaPayload::PollOk { .. } => None,
+                    // Every topic's commit lives in one map, so a commit is one
+                    // read and one CAS however many topics it covers. Offsets
+                    // only move forward, and topics not named are kept.
                     KafkaPayload::CommitOffsets { offsets } => {
                         self.sequential_store
-                            .write(StorageKey::commit(), offsets, network)?;
+                            .read_cas(
+                                StorageKey::commit(),
+                                |current: Option<CommitOffsets>| {
+                                    let mut commits = current.unwrap_or_default();
+                                    for (topic, offset) in &offsets {
+                                        let committed = commits.entry(topic.clone()).or_default();
+                                        *committed = (*committed).max(*offset);
+                                    }
+                                    commits
+                                },
+                                network,
+                            )
+                            .await
+                            .context("committing offsets")?;
                         Some(KafkaPayload::CommitOffsetsOk)
                     }
                     KafkaPayload::CommitOffsetsOk => None,
@@ -228,9 +1159,10 @@ impl fly_io::Node<KafkaPayload> for KafkaNode {
                             .await
                             .context("reading commits")?;
 
+                        // An empty key list asks for every committed topic.
                         let commits = commits
                             .into_iter()
-                            .filter(|(topic, _)| keys.contains(topic))
+                            .filter(|(topic, _)| keys.is_empty() || keys.contains(topic))
                             .collect();
 
                         Some(KafkaPayload::ListCommittedOffsetsOk { offsets: commits })
@@ -247,5 +1179,812 @@ impl fly_io::Node<KafkaPayload> for KafkaNode {
 }
 
 fn main() -> anyhow::Result<()> {
-    fly_io::server::Server::new().serve::<KafkaNode, KafkaPayload>()
+    fly_io::server::Server::new().serve_with(|init, network| {
+        Ok(KafkaNode::<Entry>::from_init(init, network)?
+            .with_class_strategy(ClassStrategy::Consistent)
+            .with_snapshot_polls(false)
+            .with_verify_offsets(false)
+            .with_failover_leaders(false)
+            .with_log_retention(None)
+            .with_sharded_logs(true))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use fly_io::{
+        clock::MockClock, mock_store::MockStore, protocol::UntypedMessage, testing::Cluster,
+    };
+
+    use super::*;
+
+    type Nodes = Cluster<KafkaNode, KafkaPayload>;
+
+    // Nodes sharing `store`, with time on `clock`.
+    fn cluster(node_ids: &[&str], store: &MockStore, clock: &Arc<MockClock>) -> Nodes {
+        cluster_with(node_ids, store, clock, |node| node)
+    }
+
+    // Like `cluster`, with `options` applied to every node.
+    fn cluster_with(
+        node_ids: &[&str],
+        store: &MockStore,
+        clock: &Arc<MockClock>,
+        options: impl Fn(KafkaNode) -> KafkaNode,
+    ) -> Nodes {
+        Cluster::start_with(
+            node_ids,
+            |_, network| store.install(network.with_clock(clock.clone())),
+            |init, network| Ok(options(KafkaNode::from_init(init, network)?)),
+        )
+        .unwrap()
+    }
+
+    fn request(id: usize, payload: KafkaPayload) -> Message<KafkaPayload> {
+        let mut request = Message::new("c1", "n1", payload);
+        request.body.id = Some(id);
+        request
+    }
+
+    fn send(id: usize, topic: &str, msg: Entry) -> Message<KafkaPayload> {
+        request(
+            id,
+            KafkaPayload::Send {
+                key: topic.to_string(),
+                msg,
+            },
+        )
+    }
+
+    // Appends `1` to topic `k` once from each of `node_ids` at the same time,
+    // running the clock until all of them are done.
+    async fn append_concurrently(
+        cluster: &Nodes,
+        clock: &MockClock,
+        node_ids: &[&str],
+    ) -> Vec<Appended> {
+        let appends: Vec<_> = node_ids
+            .iter()
+            .map(|node_id| {
+                let mut node = cluster.node(node_id).clone();
+                let network = cluster.network(node_id).clone();
+                tokio::spawn(async move { node.append_entry("k".to_string(), 1, &network).await })
+            })
+            .collect();
+        // The losers' re-reads wait on the clock too.
+        while !appends.iter().all(|append| append.is_finished()) {
+            clock.advance(Duration::from_millis(10));
+            tokio::task::yield_now().await;
+        }
+
+        let mut appended = Vec::new();
+        for append in appends {
+            appended.push(append.await.unwrap().unwrap());
+        }
+        appended.sort_by_key(|appended| appended.offset);
+        appended
+    }
+
+    #[tokio::test]
+    async fn append_reports_the_cas_races_it_lost() {
+        let clock = Arc::new(MockClock::new());
+        // Both appends read the empty log before either writes.
+        let store = MockStore::new().with_latency("read", Duration::from_millis(10));
+        let cluster = cluster(&["n1", "n2"], &store, &clock);
+
+        let appended = append_concurrently(&cluster, &clock, &["n1", "n2"]).await;
+        assert_eq!(
+            appended,
+            [
+                Appended {
+                    offset: 0,
+                    cas_failures: 0
+                },
+                Appended {
+                    offset: 1,
+                    cas_failures: 1
+                },
+            ]
+        );
+    }
+
+    // The same four appends to one topic race each other's CAS from four
+    // nodes, but take turns on the topic's lock from one.
+    #[tokio::test]
+    async fn appends_to_a_topic_on_one_node_dont_contend() {
+        let nodes = ["n1", "n2", "n3", "n4"];
+        let failures = |appended: &[Appended]| -> usize {
+            appended.iter().map(|appended| appended.cas_failures).sum()
+        };
+        let clock = Arc::new(MockClock::new());
+        let appends = |from: &'static [&'static str]| {
+            let store = MockStore::new().with_latency("read", Duration::from_millis(10));
+            let cluster = cluster(&nodes, &store, &clock);
+            let clock = clock.clone();
+            async move { append_concurrently(&cluster, &clock, from).await }
+        };
+
+        let spread = appends(&["n1", "n2", "n3", "n4"]).await;
+        assert!(failures(&spread) > 0);
+
+        let local = appends(&["n1"; 4]).await;
+        let offsets: Vec<Offset> = local.iter().map(|appended| appended.offset).collect();
+        assert_eq!(offsets, [0, 1, 2, 3]);
+        assert_eq!(failures(&local), 0);
+    }
+
+    // Four nodes append to one hot topic at once. Sharing the topic's log
+    // their CASes race; with a shard each none do, and the offsets stay apart.
+    #[tokio::test]
+    async fn sharded_logs_keep_appends_from_different_nodes_from_racing() {
+        let nodes = ["n1", "n2", "n3", "n4"];
+        let clock = Arc::new(MockClock::new());
+        let mut failures = Vec::new();
+        for sharded in [false, true] {
+            let store = MockStore::new().with_latency("read", Duration::from_millis(10));
+            let cluster = cluster_with(&nodes, &store, &clock, move |node| {
+                node.with_sharded_logs(sharded)
+            });
+            let appended = append_concurrently(&cluster, &clock, &nodes).await;
+            let offsets: BTreeSet<Offset> =
+                appended.iter().map(|appended| appended.offset).collect();
+            assert_eq!(offsets.len(), nodes.len());
+            let cas_failures: usize = nodes
+                .iter()
+                .map(|node_id| *cluster.node(node_id).cas_failures.read().unwrap())
+                .sum();
+            failures.push(cas_failures);
+        }
+        assert!(failures[0] > 0, "{:?}", failures);
+        assert_eq!(failures[1], 0);
+    }
+
+    // The offsets sends were acknowledged with, in request order.
+    fn sent_offsets(cluster: &mut Nodes) -> Vec<u64> {
+        let mut replies = cluster.take_outside();
+        replies.sort_by_key(|reply| reply.body.in_reply_to);
+        replies
+            .iter()
+            .map(|reply| reply.body.payload["offset"].as_u64().unwrap())
+            .collect()
+    }
+
+    // Polls `k` from `offset` on n1 and returns the offsets it got back.
+    async fn poll_offsets(cluster: &mut Nodes, id: usize, offset: Offset) -> Vec<u64> {
+        cluster.send(request(
+            id,
+            KafkaPayload::Poll {
+                offsets: HashMap::from([("k".to_string(), offset)]),
+                include_committed: false,
+            },
+        ));
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        replies[0].body.payload["msgs"]["k"]
+            .as_array()
+            .map(|msgs| msgs.iter().map(|msg| msg[0].as_u64().unwrap()).collect())
+            .unwrap_or_default()
+    }
+
+    // Each node appends to its own shard, with offsets from its own residue.
+    // A poll merges the shards in order and raises the lagging ones, so an
+    // offset it has served is never assigned below afterwards.
+    #[tokio::test]
+    async fn polls_merge_shards_in_offset_order() {
+        let nodes = ["n1", "n2", "n3"];
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster_with(&nodes, &store, &clock, |node| node.with_sharded_logs(true));
+        for (id, node_id) in [(1, "n2"), (2, "n1"), (3, "n3")] {
+            let mut message = send(id, "k", id as Entry);
+            message.dst = node_id.to_string();
+            cluster.send(message);
+        }
+        cluster.settle().await;
+        assert_eq!(sent_offsets(&mut cluster), [1, 0, 2]);
+        assert_eq!(poll_offsets(&mut cluster, 4, 0).await, [0, 1, 2]);
+
+        // n1 and n2 were raised past 2 by the poll, so the next sends land
+        // above it. Their first CAS loses to the raise and backs off on the
+        // clock.
+        let mut message = send(5, "k", 5);
+        message.dst = "n2".to_string();
+        cluster.send(message);
+        cluster.send(send(6, "k", 6));
+        for _ in 0..10 {
+            clock.advance(Duration::from_millis(100));
+            cluster.settle().await;
+        }
+        assert_eq!(sent_offsets(&mut cluster), [4, 3]);
+        assert_eq!(poll_offsets(&mut cluster, 7, 1).await, [1, 2, 3]);
+        assert_eq!(poll_offsets(&mut cluster, 8, 3).await, [3, 4]);
+    }
+
+    // Sends for one topic reach every node at once, and all of them end up
+    // at its class leader, which hands out offsets one append at a time.
+    #[tokio::test]
+    async fn class_leader_assigns_a_topics_offsets_in_turn() {
+        let nodes = ["n1", "n2", "n3"];
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("read", Duration::from_millis(10));
+        let mut cluster = cluster(&nodes, &store, &clock);
+        for (id, node_id) in nodes.iter().enumerate() {
+            let mut message = send(id + 1, "k", 10 + id as Entry);
+            message.dst = node_id.to_string();
+            cluster.send(message);
+        }
+
+        let mut replies = Vec::new();
+        for _ in 0..100 {
+            cluster.settle().await;
+            replies.extend(cluster.take_outside());
+            if replies.len() == nodes.len() {
+                break;
+            }
+            clock.advance(Duration::from_millis(10));
+        }
+        let mut offsets: Vec<u64> = replies
+            .iter()
+            .map(|reply| reply.body.payload["offset"].as_u64().unwrap())
+            .collect();
+        offsets.sort_unstable();
+        assert_eq!(offsets, [0, 1, 2]);
+        let cas_failures: usize = nodes
+            .iter()
+            .map(|node_id| *cluster.node(node_id).cas_failures.read().unwrap())
+            .sum();
+        assert_eq!(cas_failures, 0);
+    }
+
+    // The log the first append wrote is still cached for the second, which
+    // finishes without waiting out a read.
+    #[tokio::test]
+    async fn appends_after_the_first_dont_read_the_log() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("read", Duration::from_millis(10));
+        let cluster = cluster(&["n1"], &store, &clock);
+        append_concurrently(&cluster, &clock, &["n1"]).await;
+
+        let mut node = cluster.node("n1").clone();
+        let network = cluster.network("n1").clone();
+        let append =
+            tokio::spawn(async move { node.append_entry("k".to_string(), 2, &network).await });
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(append.is_finished());
+        assert_eq!(
+            append.await.unwrap().unwrap(),
+            Appended {
+                offset: 1,
+                cas_failures: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_offsets_tracks_what_each_topic_handed_out() {
+        let clock = Arc::new(MockClock::new());
+        for verify in [false, true] {
+            let store = MockStore::new();
+            let mut cluster = cluster_with(&["n1"], &store, &clock, move |node| {
+                node.with_verify_offsets(verify)
+            });
+            cluster.send(send(1, "k", 10));
+            cluster.settle().await;
+            cluster.send(send(2, "k", 11));
+            cluster.settle().await;
+            assert_eq!(cluster.take_outside().len(), 2);
+
+            let last_offsets = cluster.node("n1").last_offsets.read().unwrap().clone();
+            let expected = if verify {
+                HashMap::from([("k".to_string(), 1)])
+            } else {
+                HashMap::new()
+            };
+            assert_eq!(last_offsets, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_polls_version_appends_by_timestamp() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster_with(&["n1"], &store, &clock, |node| {
+            node.with_snapshot_polls(true)
+        });
+        cluster.send(send(1, "k", 10));
+        cluster.settle().await;
+        cluster.send(send(2, "k", 11));
+        cluster.settle().await;
+
+        let versions: Vec<Version> = {
+            let mut entries = cluster.node("n1").entries.lock().unwrap();
+            (0..2)
+                .map(|offset| entries.get(&("k".to_string(), offset)).unwrap().0)
+                .collect()
+        };
+        assert!(0 < versions[0] && versions[0] < versions[1]);
+
+        cluster.take_outside();
+        cluster.send(request(
+            3,
+            KafkaPayload::Poll {
+                offsets: HashMap::from([("k".to_string(), 0)]),
+                include_committed: false,
+            },
+        ));
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        assert_eq!(
+            replies[0].body.payload["msgs"]["k"],
+            serde_json::json!([[0, 10], [1, 11]])
+        );
+    }
+
+    #[tokio::test]
+    async fn failover_moves_a_class_off_a_silent_leader() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        for failover in [false, true] {
+            let cluster = cluster_with(&["n1", "n2"], &store, &clock, move |node| {
+                node.with_failover_leaders(failover)
+            });
+            let n1 = cluster.node("n1");
+            let network = cluster.network("n1");
+            let topic = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|topic| n1.membership.read().unwrap().class_of(topic) == 1)
+                .unwrap();
+            network.track_peers(["n2".to_string()]);
+            assert_eq!(n1.class_leader(&topic, network), "n2");
+
+            clock.advance(LEADER_SUSPICION_THRESHOLD + Duration::from_millis(1));
+            let expected = if failover { "n1" } else { "n2" };
+            assert_eq!(n1.class_leader(&topic, network), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn log_retention_trims_logs_below_the_commit() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster_with(&["n1"], &store, &clock, |node| {
+            node.with_log_retention(Some(1))
+        });
+        for id in 1..=4 {
+            cluster.send(send(id, "k", id as Entry));
+            cluster.settle().await;
+        }
+        cluster.send(request(
+            5,
+            KafkaPayload::CommitOffsets {
+                offsets: HashMap::from([("k".to_string(), 3)]),
+            },
+        ));
+        cluster.settle().await;
+
+        clock.advance(COMPACTION_INTERVAL);
+        cluster.settle().await;
+        let n1 = cluster.node("n1");
+        assert_eq!(n1.log_starts.get("k").map(|start| *start), Some(2));
+    }
+
+    #[tokio::test]
+    async fn compaction_is_scheduled_only_with_retention() {
+        let clock = Arc::new(MockClock::new());
+        for retention in [None, Some(1)] {
+            let store = MockStore::new();
+            let mut cluster = cluster_with(&["n1"], &store, &clock, move |node| {
+                node.with_log_retention(retention)
+            });
+            let n1 = cluster.node("n1").clone();
+            assert!(!n1.compaction_scheduled.load(Ordering::Relaxed));
+
+            cluster.send(send(1, "k", 10));
+            cluster.settle().await;
+            assert_eq!(
+                n1.compaction_scheduled.load(Ordering::Relaxed),
+                retention.is_some()
+            );
+        }
+    }
+
+    // A poll below the trim point gets the first offset still kept instead of
+    // entries, while offsets from there on read as before.
+    #[tokio::test]
+    async fn polls_below_the_trim_point_report_it_and_newer_offsets_still_read() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster_with(&["n1"], &store, &clock, |node| {
+            node.with_log_retention(Some(1))
+        });
+        for id in 1..=4 {
+            cluster.send(send(id, "k", id as Entry * 10));
+            cluster.settle().await;
+        }
+        cluster.send(request(
+            5,
+            KafkaPayload::CommitOffsets {
+                offsets: HashMap::from([("k".to_string(), 3)]),
+            },
+        ));
+        cluster.settle().await;
+        clock.advance(COMPACTION_INTERVAL);
+        cluster.settle().await;
+        cluster.take_outside();
+
+        for (id, offset) in [(6, 0), (7, 2)] {
+            cluster.send(request(
+                id,
+                KafkaPayload::Poll {
+                    offsets: HashMap::from([("k".to_string(), offset)]),
+                    include_committed: false,
+                },
+            ));
+            cluster.settle().await;
+        }
+        let polls: Vec<KafkaPayload> = cluster
+            .take_outside()
+            .into_iter()
+            .map(|reply| {
+                let reply: Message<KafkaPayload> =
+                    serde_json::from_value(serde_json::to_value(&reply).unwrap()).unwrap();
+                reply.body.payload
+            })
+            .collect();
+        let [KafkaPayload::PollOk { msgs, trimmed, .. }, KafkaPayload::PollOk {
+            msgs: newer,
+            trimmed: none_trimmed,
+            ..
+        }] = &polls[..]
+        else {
+            panic!("expected two poll_oks, got {:?}", polls);
+        };
+        assert!(msgs.is_empty());
+        assert_eq!(*trimmed, HashMap::from([("k".to_string(), 2)]));
+        assert_eq!(newer["k"], [(2, 30), (3, 40)]);
+        assert!(none_trimmed.is_empty());
+    }
+
+    // The entry crosses the wire, the flattened message body and the store
+    // without passing through an f64.
+    #[tokio::test]
+    async fn largest_entry_round_trips_exactly() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster(&["n1"], &store, &clock);
+        cluster.send(send(1, "k", u64::MAX));
+        cluster.settle().await;
+        // Polled from the log, not the entry cache.
+        cluster
+            .node("n1")
+            .entries
+            .lock()
+            .unwrap()
+            .remove(&("k".to_string(), 0));
+        cluster.send(request(
+            2,
+            KafkaPayload::Poll {
+                offsets: HashMap::from([("k".to_string(), 0)]),
+                include_committed: false,
+            },
+        ));
+        cluster.settle().await;
+
+        let replies = cluster.take_outside();
+        let poll: Message<KafkaPayload> =
+            serde_json::from_value(serde_json::to_value(&replies[1]).unwrap()).unwrap();
+        let KafkaPayload::PollOk { msgs, .. } = poll.body.payload else {
+            panic!("expected poll_ok, got {:?}", poll.body.payload);
+        };
+        assert_eq!(msgs["k"], [(0, u64::MAX)]);
+    }
+
+    // Offset 0 is cached and the rest of the batch isn't: the poll serves the
+    // prefix from the cache and reads the log once for the tail.
+    #[tokio::test]
+    async fn poll_reads_the_log_once_for_an_uncached_tail() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut cluster: Nodes = Cluster::start_with(
+            &["n1"],
+            |_, network| {
+                let reads = reads.clone();
+                let network = network
+                    .with_clock(clock.clone())
+                    .with_outbound_filter(Arc::new(move |message: UntypedMessage| {
+                        if message.dst == "lin-kv" && message.kind() == Some("read") {
+                            reads.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Some(message)
+                    }));
+                store.install(network)
+            },
+            KafkaNode::from_init,
+        )
+        .unwrap();
+        for id in 1..=5 {
+            cluster.send(send(id, "k", 10 + id as Entry));
+            cluster.settle().await;
+        }
+        {
+            let mut entries = cluster.node("n1").entries.lock().unwrap();
+            for offset in 1..5 {
+                entries.remove(&("k".to_string(), offset));
+            }
+        }
+
+        cluster.take_outside();
+        reads.store(0, Ordering::Relaxed);
+        cluster.send(request(
+            6,
+            KafkaPayload::Poll {
+                offsets: HashMap::from([("k".to_string(), 0)]),
+                include_committed: false,
+            },
+        ));
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        assert_eq!(
+            replies[0].body.payload["msgs"]["k"],
+            serde_json::json!([[0, 11], [1, 12], [2, 13]])
+        );
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+    }
+
+    // Three entries in the log and a stray one cached at offset 4: a stream
+    // from 0 yields the log's three and stops at the gap, and caches no more
+    // of the log than the entries it was allowed to yield.
+    #[tokio::test]
+    async fn log_stream_yields_contiguous_entries_up_to_a_gap() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster(&["n1"], &store, &clock);
+        for id in 1..=3 {
+            cluster.send(send(id, "k", 10 + id as Entry));
+            cluster.settle().await;
+        }
+        let n1 = cluster.node("n1").clone();
+        let network = cluster.network("n1").clone();
+        let key = |offset: Offset| ("k".to_string(), offset);
+        let stream = |limit: usize| {
+            LogStream::new(&n1, "k".to_string(), 0, limit, &network)
+                .map(|(offset, (_, entry))| (offset, entry))
+                .collect::<Vec<_>>()
+        };
+
+        *n1.entries.lock().unwrap() = LruCache::new(16);
+        n1.entries.lock().unwrap().put(key(4), (0, 99));
+        assert_eq!(stream(10).await, [(0, 11), (1, 12), (2, 13)]);
+
+        *n1.entries.lock().unwrap() = LruCache::new(16);
+        assert_eq!(stream(2).await, [(0, 11), (1, 12)]);
+        let entries = n1.entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(!entries.contains(&key(2)));
+    }
+
+    // Entries pushed out of the cache are read from the log again, which
+    // doesn't grow the cache past its capacity.
+    #[tokio::test]
+    async fn evicted_entries_are_read_from_the_log_again() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster(&["n1"], &store, &clock);
+        let n1 = cluster.node("n1").clone();
+        *n1.entries.lock().unwrap() = LruCache::new(2);
+        for id in 1..=3 {
+            cluster.send(send(id, "k", 10 + id as Entry));
+            cluster.settle().await;
+        }
+        let key = |offset: Offset| ("k".to_string(), offset);
+        assert!(!n1.entries.lock().unwrap().contains(&key(0)));
+
+        cluster.take_outside();
+        cluster.send(request(
+            4,
+            KafkaPayload::Poll {
+                offsets: HashMap::from([("k".to_string(), 0)]),
+                include_committed: false,
+            },
+        ));
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        assert_eq!(
+            replies[0].body.payload["msgs"]["k"],
+            serde_json::json!([[0, 11], [1, 12], [2, 13]])
+        );
+        assert_eq!(n1.entries.lock().unwrap().len(), 2);
+    }
+
+    // Under modulo numeric topics go round the nodes in order, and still do
+    // after a reconfigure.
+    #[tokio::test]
+    async fn class_strategy_picks_the_class_leader() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let cluster = cluster_with(&["n1", "n2", "n3"], &store, &clock, |node| {
+            node.with_class_strategy(ClassStrategy::Modulo)
+        });
+        let mut n1 = cluster.node("n1").clone();
+        let network = cluster.network("n1");
+        let leaders = |n1: &KafkaNode| -> Vec<String> {
+            (0..4)
+                .map(|topic| n1.class_leader(&topic.to_string(), network))
+                .collect()
+        };
+        assert_eq!(leaders(&n1), ["n1", "n2", "n3", "n1"]);
+
+        n1.on_reconfigure(&["n1".to_string(), "n2".to_string()], network)
+            .unwrap();
+        assert_eq!(leaders(&n1), ["n1", "n2", "n1", "n2"]);
+    }
+
+    // Shrinking the cluster by its last node only moves that node's topics;
+    // every other topic keeps the leader it had.
+    #[tokio::test]
+    async fn shrinking_the_cluster_moves_only_the_removed_nodes_topics() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let cluster = cluster(&["n1", "n2", "n3", "n4"], &store, &clock);
+        let mut n1 = cluster.node("n1").clone();
+        let network = cluster.network("n1");
+        let topics: Vec<String> = (0..200).map(|topic| topic.to_string()).collect();
+        let leaders = |n1: &KafkaNode| -> Vec<String> {
+            topics
+                .iter()
+                .map(|topic| n1.class_leader(topic, network))
+                .collect()
+        };
+        let before = leaders(&n1);
+        assert!(before.iter().any(|leader| leader == "n4"));
+
+        let shrunk: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+        n1.on_reconfigure(&shrunk, network).unwrap();
+        let after = leaders(&n1);
+        for (before, after) in before.iter().zip(&after) {
+            assert!(shrunk.contains(after));
+            if before != "n4" {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    // Topic 0 is n1's own class, so n1 appends it without a message to
+    // itself or anyone else, where topic 1 is forwarded to n2.
+    #[tokio::test]
+    async fn send_for_the_local_class_is_appended_without_forwarding() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let mut cluster: Nodes = Cluster::start_with(
+            &["n1", "n2"],
+            |_, network| {
+                let forwarded = forwarded.clone();
+                let network = network
+                    .with_clock(clock.clone())
+                    .with_outbound_filter(Arc::new(move |message: UntypedMessage| {
+                        if message.kind() == Some("send") {
+                            forwarded.lock().unwrap().push(message.dst.clone());
+                        }
+                        Some(message)
+                    }));
+                store.install(network)
+            },
+            |init, network| {
+                Ok(KafkaNode::from_init(init, network)?.with_class_strategy(ClassStrategy::Modulo))
+            },
+        )
+        .unwrap();
+
+        cluster.send(send(1, "0", 10));
+        cluster.settle().await;
+        assert!(forwarded.lock().unwrap().is_empty());
+        cluster.send(send(2, "1", 11));
+        cluster.settle().await;
+        assert_eq!(*forwarded.lock().unwrap(), ["n2"]);
+
+        let offsets: Vec<serde_json::Value> = cluster
+            .take_outside()
+            .iter()
+            .map(|reply| reply.body.payload["offset"].clone())
+            .collect();
+        assert_eq!(offsets, [serde_json::json!(0), serde_json::json!(0)]);
+    }
+
+    #[tokio::test]
+    async fn read_or_create_with_only_initializes_a_missing_key() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let cluster = cluster(&["n1"], &store, &clock);
+        let n1 = cluster.node("n1");
+        let network = cluster.network("n1");
+
+        let created: u64 = n1
+            .read_or_create_with("seeded".to_string(), &n1.linear_store, || 5, network)
+            .await
+            .unwrap();
+        assert_eq!(created, 5);
+        assert_eq!(store.value("lin-kv", "seeded"), Some(serde_json::json!(5)));
+
+        let read: u64 = n1
+            .read_or_create_with(
+                "seeded".to_string(),
+                &n1.linear_store,
+                || panic!("initialized a key that exists"),
+                network,
+            )
+            .await
+            .unwrap();
+        assert_eq!(read, 5);
+        let defaulted: u64 = n1
+            .read_or_create("other".to_string(), &n1.linear_store, network)
+            .await
+            .unwrap();
+        assert_eq!(defaulted, 0);
+    }
+
+    // Non-numeric topics used to panic the node when it picked their class.
+    #[tokio::test]
+    async fn non_numeric_topic_is_sent_and_polled() {
+        let clock = Arc::new(MockClock::new());
+        for strategy in [
+            ClassStrategy::Modulo,
+            ClassStrategy::Hash,
+            ClassStrategy::Consistent,
+        ] {
+            let store = MockStore::new();
+            let mut cluster = cluster_with(&["n1", "n2"], &store, &clock, move |node| {
+                node.with_class_strategy(strategy)
+            });
+
+            cluster.send(send(1, "abc", 7));
+            cluster.settle().await;
+            cluster.send(request(
+                2,
+                KafkaPayload::Poll {
+                    offsets: HashMap::from([("abc".to_string(), 0)]),
+                    include_committed: false,
+                },
+            ));
+            cluster.settle().await;
+            let replies = cluster.take_outside();
+            assert_eq!(replies[0].kind(), Some("send_ok"), "{:?}", strategy);
+            assert_eq!(
+                replies[1].body.payload["msgs"]["abc"][0][1],
+                serde_json::json!(7)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn finalize_reads_back_the_offsets_handed_out() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let mut cluster = cluster_with(&["n1"], &store, &clock, |node| {
+            node.with_verify_offsets(true)
+        });
+        cluster.send(send(1, "k", 10));
+        cluster.send(send(2, "k", 11));
+        cluster.settle().await;
+
+        let mut n1 = cluster.node("n1").clone();
+        n1.finalize(cluster.network("n1")).await.unwrap();
+        assert_eq!(*n1.lost_offsets.read().unwrap(), 0);
+
+        // As if a send had been acknowledged with an offset the log lost.
+        n1.last_offsets.write().unwrap().insert("k".to_string(), 2);
+        n1.finalize(cluster.network("n1")).await.unwrap();
+        assert_eq!(*n1.lost_offsets.read().unwrap(), 1);
+    }
 }
@@ -0,0 +1,217 @@
+use anyhow::Context;
+use fly_io::{
+    network::Network,
+    service::{SequentialStore, Storage},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum PnCounterPayload {
+    Add { delta: i64 },
+    AddOk,
+    Read,
+    ReadOk { value: i64 },
+}
+
+// Each node owns a grow-only accumulator for increments and another for
+// decrements, so writers never contend with other nodes. The counter value is
+// the sum of every node's increments minus the sum of every node's decrements.
+#[derive(Debug, Clone, Copy)]
+enum Side {
+    Positive,
+    Negative,
+}
+
+#[derive(Debug, Clone)]
+struct PnCounterNode {
+    node_id: String,
+    node_ids: Vec<String>,
+    storage: SequentialStore,
+}
+
+impl PnCounterNode {
+    fn storage_key(node_id: &str, side: Side) -> String {
+        match side {
+            Side::Positive => format!("counter/{}/p", node_id),
+            Side::Negative => format!("counter/{}/n", node_id),
+        }
+    }
+
+    async fn read_accumulator(
+        &self,
+        node_id: &str,
+        side: Side,
+        network: &Network,
+    ) -> anyhow::Result<u64> {
+        let value = self
+            .storage
+            .try_read(Self::storage_key(node_id, side), network)
+            .await
+            .context("reading accumulator")?;
+        Ok(value.unwrap_or(0))
+    }
+
+    async fn add_to_accumulator(
+        &self,
+        side: Side,
+        amount: u64,
+        network: &Network,
+    ) -> anyhow::Result<()> {
+        let key = Self::storage_key(&self.node_id, side);
+        self.storage
+            .read_cas(
+                key,
+                |current: Option<u64>| current.unwrap_or(0) + amount,
+                network,
+            )
+            .await
+            .context("adding to accumulator")?;
+        Ok(())
+    }
+
+    async fn add(&self, delta: i64, network: &Network) -> anyhow::Result<()> {
+        let side = if delta < 0 {
+            Side::Negative
+        } else {
+            Side::Positive
+        };
+
+        self.add_to_accumulator(side, delta.unsigned_abs(), network)
+            .await
+    }
+
+    async fn value(&self, network: &Network) -> anyhow::Result<i64> {
+        let mut value: i64 = 0;
+        for node_id in &self.node_ids {
+            let positive = self
+                .read_accumulator(node_id, Side::Positive, network)
+                .await?;
+            let negative = self
+                .read_accumulator(node_id, Side::Negative, network)
+                .await?;
+            value += positive as i64 - negative as i64;
+        }
+        Ok(value)
+    }
+}
+
+#[async_trait::async_trait]
+impl fly_io::Node<PnCounterPayload> for PnCounterNode {
+    fn from_init(init: fly_io::protocol::Init, _network: &Network) -> anyhow::Result<Self> {
+        Ok(Self {
+            storage: SequentialStore::new(init.node_id.clone()),
+            node_id: init.node_id,
+            node_ids: init.node_ids,
+        })
+    }
+
+    async fn step(
+        &mut self,
+        event: fly_io::Event<PnCounterPayload>,
+        network: &Network,
+    ) -> anyhow::Result<()> {
+        match event {
+            fly_io::Event::Error(error) => {
+                eprintln!("error from {}: {:?}", error.src, error.body.payload);
+            }
+            // Handed to `on_storage` instead.
+            fly_io::Event::Storage(_) => {}
+            fly_io::Event::Injected(_) => {}
+            fly_io::Event::Message(message) => {
+                let mut reply = message.into_reply();
+                match reply.body.payload {
+                    PnCounterPayload::Add { delta } => {
+                        self.add(delta, network)
+                            .await
+                            .context("adding delta to store")?;
+
+                        reply.body.payload = PnCounterPayload::AddOk;
+                        network.send(reply).context("sending add_ok reply")?;
+                    }
+                    PnCounterPayload::Read => {
+                        let value = self.value(network).await.context("reading counter")?;
+
+                        reply.body.payload = PnCounterPayload::ReadOk { value };
+                        network.send(reply).context("sending read reply")?;
+                    }
+                    PnCounterPayload::AddOk => {}
+                    PnCounterPayload::ReadOk { .. } => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    fly_io::server::Server::new().serve::<PnCounterNode, PnCounterPayload>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fly_io::{mock_store::MockStore, retry::RetryPolicy, testing::Cluster, Message, Node};
+
+    use super::*;
+
+    fn request(dst: &str, id: usize, payload: PnCounterPayload) -> Message<PnCounterPayload> {
+        let mut request = Message::new("c1", dst, payload);
+        request.body.id = Some(id);
+        request
+    }
+
+    #[tokio::test]
+    async fn sums_increments_and_decrements_across_nodes() {
+        let store = MockStore::new();
+        let mut cluster = Cluster::start_with(
+            &["n1", "n2"],
+            // Adds racing on a node's key retry at once rather than after
+            // a real backoff.
+            |_, network| {
+                store.install(
+                    network
+                        .with_retry_policy(RetryPolicy::default().with_max_backoff(Duration::ZERO)),
+                )
+            },
+            PnCounterNode::from_init,
+        )
+        .unwrap();
+
+        let adds = [("n1", 5), ("n2", -3), ("n1", -4), ("n2", 7), ("n1", 2)];
+        for (id, (dst, delta)) in (1..).zip(adds) {
+            cluster.send(request(dst, id, PnCounterPayload::Add { delta }));
+        }
+        cluster.settle().await;
+        assert_eq!(cluster.take_outside().len(), adds.len());
+
+        // Each side of each node only ever grows.
+        for (key, value) in [
+            ("counter/n1/p", 7),
+            ("counter/n1/n", 4),
+            ("counter/n2/p", 7),
+            ("counter/n2/n", 3),
+        ] {
+            assert_eq!(
+                store.value("seq-kv", key),
+                Some(serde_json::json!(value)),
+                "{}",
+                key
+            );
+        }
+
+        for dst in ["n1", "n2"] {
+            cluster.send(request(dst, 10, PnCounterPayload::Read));
+        }
+        cluster.settle().await;
+        let replies = cluster.take_outside();
+        assert_eq!(replies.len(), 2);
+        for reply in replies {
+            assert_eq!(reply.kind(), Some("read_ok"));
+            assert_eq!(reply.body.payload["value"], 7);
+        }
+    }
+}
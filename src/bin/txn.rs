@@ -0,0 +1,166 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Context;
+use fly_io::{
+    network::Network,
+    retry::Retry,
+    service::{KeyConflict, LinearStore, Storage},
+};
+use serde::{Deserialize, Serialize};
+
+type Key = usize;
+type Value = usize;
+
+// Maelstrom encodes operations as `["append", key, value]` and
+// `["r", key, null]`, the read filling in the list on the way back.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Op(String, Key, serde_json::Value);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TxnPayload {
+    Txn { txn: Vec<Op> },
+    TxnOk { txn: Vec<Op> },
+}
+
+// Every attempt reads all the keys it touches, applies the operations locally
+// and then writes every changed key back with `multi_cas`. Any conflict aborts
+// the attempt, rolls back the keys it already wrote and retries from fresh
+// reads. The keys are written one at a time, though, so until that rollback
+// lands other transactions can read the aborted attempt's writes: this is
+// read uncommitted. Only the keys a transaction writes are checked at commit,
+// so one that just reads can keep a value that was later rolled back.
+#[derive(Debug, Clone)]
+struct TxnNode {
+    storage: LinearStore,
+}
+
+impl TxnNode {
+    fn storage_key(key: Key) -> String {
+        format!("txn/{}", key)
+    }
+
+    async fn read_list(&self, key: Key, network: &Network) -> anyhow::Result<Vec<Value>> {
+        let list = self
+            .storage
+            .try_read(Self::storage_key(key), network)
+            .await
+            .context(format!("reading key {}", key))?;
+        Ok(list.unwrap_or_default())
+    }
+
+    async fn attempt(
+        &self,
+        txn: &[Op],
+        network: &Network,
+    ) -> anyhow::Result<Result<Vec<Op>, KeyConflict>> {
+        let keys: BTreeSet<Key> = txn.iter().map(|Op(_, key, _)| *key).collect();
+
+        let mut original = HashMap::new();
+        for key in keys {
+            original.insert(key, self.read_list(key, network).await?);
+        }
+
+        let mut state = original.clone();
+        let mut completed = Vec::with_capacity(txn.len());
+        for Op(f, key, value) in txn {
+            let list = state.entry(*key).or_default();
+            match f.as_str() {
+                "r" => completed.push(Op(
+                    f.clone(),
+                    *key,
+                    serde_json::to_value(&*list).context("serializing read")?,
+                )),
+                "append" => {
+                    let value: Value = serde_json::from_value(value.clone())
+                        .context("deserializing appended value")?;
+                    list.push(value);
+                    completed.push(Op(f.clone(), *key, value.into()));
+                }
+                other => anyhow::bail!("unknown txn operation {}", other),
+            }
+        }
+
+        let changed = state
+            .into_iter()
+            .filter(|(key, list)| original[key] != *list)
+            .map(|(key, list)| (Self::storage_key(key), original[&key].clone(), list))
+            .collect();
+
+        Ok(self
+            .storage
+            .multi_cas(changed, network)
+            .await
+            .context("writing txn")?
+            .map(|()| completed))
+    }
+
+    async fn transact(&self, txn: Vec<Op>, network: &Network) -> anyhow::Result<Vec<Op>> {
+        let mut retry = Retry::with_policy(network.retry_policy().clone());
+        loop {
+            match self.attempt(&txn, network).await? {
+                Ok(completed) => return Ok(completed),
+                Err(KeyConflict { key, unrolled }) if !unrolled.is_empty() => {
+                    // A retry would read this attempt's writes back as
+                    // committed and apply them twice.
+                    anyhow::bail!(
+                        "aborting txn: conflict on {}, and {:?} could not be rolled back",
+                        key,
+                        unrolled
+                    );
+                }
+                Err(KeyConflict { key, .. }) => {
+                    dbg!("TXN CONFLICT", &key, retry.failures());
+                    let conflict = fly_io::Error::CasConflict(key);
+                    if !retry.should_retry(&conflict) {
+                        return Err(conflict).context("aborting txn");
+                    }
+                    retry.backoff().await;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl fly_io::Node<TxnPayload> for TxnNode {
+    fn from_init(init: fly_io::protocol::Init, _network: &Network) -> anyhow::Result<Self> {
+        Ok(Self {
+            storage: LinearStore::new(init.node_id),
+        })
+    }
+
+    async fn step(
+        &mut self,
+        event: fly_io::Event<TxnPayload>,
+        network: &Network,
+    ) -> anyhow::Result<()> {
+        match event {
+            fly_io::Event::Error(error) => {
+                eprintln!("error from {}: {:?}", error.src, error.body.payload);
+            }
+            // Handed to `on_storage` instead.
+            fly_io::Event::Storage(_) => {}
+            fly_io::Event::Injected(_) => {}
+            fly_io::Event::Message(message) => {
+                let mut reply = message.into_reply();
+                match reply.body.payload {
+                    TxnPayload::Txn { txn } => {
+                        let txn = self.transact(txn, network).await.context("running txn")?;
+
+                        reply.body.payload = TxnPayload::TxnOk { txn };
+                        network.send(reply).context("sending txn_ok reply")?;
+                    }
+                    TxnPayload::TxnOk { .. } => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    fly_io::server::Server::new().serve::<TxnNode, TxnPayload>()
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use fly_io::{
+    ids::{IdGenerator, IdStrategy},
+    Node,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum UniqueIdsPayload {
+    Generate,
+    GenerateOk { id: serde_json::Value },
+}
+
+#[derive(Clone, Debug)]
+pub struct UniqueIdsNode {
+    node_id: String,
+    node_index: usize,
+    ids: Arc<IdGenerator>,
+}
+
+impl UniqueIdsNode {
+    // Snowflakes by default.
+    fn with_id_strategy(mut self, strategy: IdStrategy) -> Self {
+        self.ids = Arc::new(IdGenerator::new(
+            strategy,
+            self.node_id.clone(),
+            self.node_index,
+        ));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl fly_io::Node<UniqueIdsPayload> for UniqueIdsNode {
+    fn from_init(
+        init: fly_io::protocol::Init,
+        _network: &fly_io::network::Network,
+    ) -> anyhow::Result<Self> {
+        let node_index = init
+            .node_ids
+            .iter()
+            .position(|id| *id == init.node_id)
+            .context("node id missing from init node ids")?;
+
+        Ok(UniqueIdsNode {
+            ids: Arc::new(IdGenerator::new(
+                IdStrategy::default(),
+                init.node_id.clone(),
+                node_index,
+            )),
+            node_id: init.node_id,
+            node_index,
+        })
+    }
+
+    async fn step(
+        &mut self,
+        input: fly_io::Event<UniqueIdsPayload>,
+        network: &fly_io::network::Network,
+    ) -> anyhow::Result<()> {
+        let fly_io::Event::Message(input) = input else {
+            return Ok(());
+        };
+
+        let mut reply = input.into_reply();
+        match reply.body.payload {
+            UniqueIdsPayload::Generate => {
+                reply.body.payload = UniqueIdsPayload::GenerateOk {
+                    id: self.ids.next_id(),
+                };
+                network.send(reply).context("sending generate_ok message")?;
+            }
+            UniqueIdsPayload::GenerateOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    fly_io::server::Server::new().serve_with(|init, network| {
+        Ok(UniqueIdsNode::from_init(init, network)?.with_id_strategy(IdStrategy::Snowflake))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use fly_io::{testing::Cluster, Message};
+
+    use super::*;
+
+    // Ids handed out by two nodes using `strategy`, 50 from each.
+    async fn generate(strategy: IdStrategy) -> Vec<serde_json::Value> {
+        let mut cluster: Cluster<UniqueIdsNode, UniqueIdsPayload> =
+            Cluster::start(&["n1", "n2"], |init, network| {
+                Ok(UniqueIdsNode::from_init(init, network)?.with_id_strategy(strategy))
+            })
+            .unwrap();
+        for id in 0..100 {
+            let dst = if id % 2 == 0 { "n1" } else { "n2" };
+            let mut request = Message::new("c1", dst, UniqueIdsPayload::Generate);
+            request.body.id = Some(id + 1);
+            cluster.send(request);
+        }
+        cluster.settle().await;
+
+        let ids: Vec<_> = cluster
+            .take_outside()
+            .into_iter()
+            .map(|reply| reply.body.payload["id"].clone())
+            .collect();
+        assert_eq!(ids.len(), 100);
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 100);
+        ids
+    }
+
+    #[tokio::test]
+    async fn every_strategy_hands_out_unique_ids() {
+        let snowflakes = generate(IdStrategy::Snowflake).await;
+        assert!(snowflakes.iter().all(|id| id.is_u64()));
+
+        let counters = generate(IdStrategy::Counter).await;
+        assert!(counters.contains(&serde_json::json!("n1-0")));
+        assert!(counters.contains(&serde_json::json!("n2-49")));
+
+        let uuids = generate(IdStrategy::Uuid).await;
+        assert!(uuids
+            .iter()
+            .all(|id| id.as_str().is_some_and(|id| id.len() == 36)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+// Packs a set of ids into a bitset (bit `i` of the byte string is set when
+// `base + i` is in the set, `base` being the smallest id) and base64-encodes
+// it so it can travel inside a JSON string, as `<base>:<bits>` or just the
+// bits when `base` is 0. For dense ranges of ids this is roughly eight ids per
+// byte instead of a few bytes per id in a JSON array, however high the range
+// starts. When the bitset would come out longer than that array, e.g. for a
+// few ids far apart, the ids are sent as the array instead, so the encoding is
+// never much bigger than the set itself.
+pub fn encode_bitset(ids: &HashSet<usize>) -> String {
+    let (Some(&base), Some(&max)) = (ids.iter().min(), ids.iter().max()) else {
+        return String::new();
+    };
+
+    let prefix = match base {
+        0 => String::new(),
+        base => format!("{}:", base),
+    };
+    let bytes = (max - base) / 8 + 1;
+    let list_len = ids.iter().map(|id| digits(*id) + 1).sum::<usize>() + 1;
+    if prefix.len() + bytes.div_ceil(3) * 4 > list_len {
+        let mut ids: Vec<usize> = ids.iter().copied().collect();
+        ids.sort_unstable();
+        return serde_json::to_string(&ids).expect("serializing ids");
+    }
+
+    let mut bytes = vec![0u8; bytes];
+    for id in ids {
+        let bit = id - base;
+        bytes[bit / 8] |= 1 << (bit % 8);
+    }
+
+    prefix + &STANDARD.encode(bytes)
+}
+
+// Reads any form `encode_bitset` writes; an id list starts with `[` and a
+// base is followed by `:`, neither of which base64 uses.
+pub fn decode_bitset(bits: &str) -> anyhow::Result<HashSet<usize>> {
+    if bits.starts_with('[') {
+        let ids: Vec<usize> = serde_json::from_str(bits).context("decoding id list")?;
+        return Ok(ids.into_iter().collect());
+    }
+
+    let (base, bits) = match bits.split_once(':') {
+        Some((base, bits)) => (base.parse().context("decoding bitset base")?, bits),
+        None => (0, bits),
+    };
+    let bytes = STANDARD.decode(bits).context("decoding bitset")?;
+
+    Ok(bytes
+        .iter()
+        .enumerate()
+        .flat_map(|(i, byte)| {
+            (0..8)
+                .filter(move |bit| byte & (1 << bit) != 0)
+                .map(move |bit| base + i * 8 + bit)
+        })
+        .collect())
+}
+
+fn digits(id: usize) -> usize {
+    id.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(ids: HashSet<usize>) -> String {
+        let encoded = encode_bitset(&ids);
+        assert_eq!(decode_bitset(&encoded).unwrap(), ids);
+        encoded
+    }
+
+    #[test]
+    fn dense_ids_travel_as_a_bitset() {
+        let encoded = round_trip((0..1000).collect());
+        assert!(!encoded.starts_with('['));
+        // 1000 bits is 125 bytes, 168 characters of base64.
+        assert_eq!(encoded.len(), 168);
+    }
+
+    #[test]
+    fn high_ids_cost_no_more_than_low_ones() {
+        let low = round_trip((0..1000).collect());
+        let high = round_trip((1_000_000..1_001_000).collect());
+        assert_eq!(high, format!("1000000:{}", low));
+    }
+
+    #[test]
+    fn sparse_ids_travel_as_a_list() {
+        let encoded = round_trip(HashSet::from([3, 1 << 40, usize::MAX]));
+        // A bitset up to the largest id couldn't even be allocated.
+        assert_eq!(encoded, format!("[3,{},{}]", 1usize << 40, usize::MAX));
+    }
+
+    #[test]
+    fn encoding_is_never_longer_than_the_id_list() {
+        for ids in [
+            HashSet::from([0]),
+            HashSet::from([7, 8]),
+            (0..64).step_by(9).collect(),
+            (0..10_000).step_by(100).collect(),
+            HashSet::from([1_000_000]),
+        ] {
+            let list_len = serde_json::to_string(&ids).unwrap().len();
+            let encoded = round_trip(ids);
+            assert!(encoded.len() <= list_len, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn empty_set_round_trips() {
+        assert_eq!(round_trip(HashSet::new()), "");
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use tokio::sync::Notify;
+
+// Where the network's timers and timestamps get the time from.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    // Blocks the calling thread, so only timer threads should call it.
+    fn sleep(&self, duration: Duration);
+    // `sleep` for tasks on the runtime, counted from the call rather than
+    // from when the future is first polled.
+    fn delay(&self, duration: Duration) -> BoxFuture<'_, ()>;
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'_, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// `future`'s output, or `None` if `duration` passes on `clock` first.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = clock.delay(duration) => None,
+    }
+}
+
+// Only moves when `advance` is called. Sleepers wake once the clock has been
+// advanced past their deadline.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+    advanced: Condvar,
+    // Wakes `delay`s, as `advanced` does `sleep`s.
+    advanced_tasks: Notify,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+            advanced: Condvar::new(),
+            advanced_tasks: Notify::new(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+        self.advanced.notify_all();
+        self.advanced_tasks.notify_waiters();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut offset = self.offset.lock().unwrap();
+        let deadline = *offset + duration;
+        while *offset < deadline {
+            offset = self.advanced.wait(offset).unwrap();
+        }
+    }
+
+    fn delay(&self, duration: Duration) -> BoxFuture<'_, ()> {
+        let deadline = *self.offset.lock().unwrap() + duration;
+        Box::pin(async move {
+            loop {
+                // Created before checking, so an advance in between isn't missed.
+                let advanced = self.advanced_tasks.notified();
+                if *self.offset.lock().unwrap() >= deadline {
+                    return;
+                }
+                advanced.await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn delay_waits_for_the_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let delayed = tokio::spawn({
+            let clock = clock.clone();
+            async move { clock.delay(Duration::from_secs(5)).await }
+        });
+        tokio::task::yield_now().await;
+
+        clock.advance(Duration::from_secs(3));
+        tokio::task::yield_now().await;
+        assert!(!delayed.is_finished());
+
+        clock.advance(Duration::from_secs(2));
+        delayed.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delay_counts_from_the_call() {
+        let clock = MockClock::new();
+        let delay = clock.delay(Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        delay.await;
+    }
+
+    #[test]
+    fn sleep_wakes_once_advanced_past_the_deadline() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let sleeper = std::thread::spawn({
+            let clock = clock.clone();
+            move || clock.sleep(Duration::from_secs(1))
+        });
+
+        while !sleeper.is_finished() {
+            clock.advance(Duration::from_millis(100));
+            std::thread::yield_now();
+        }
+        assert!(clock.elapsed(start) >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn timeout_fires_on_the_clock() {
+        let clock = MockClock::new();
+        let never = std::future::pending::<()>();
+        let timed = timeout(&clock, Duration::from_secs(1), never);
+        tokio::pin!(timed);
+
+        assert!(futures::poll!(timed.as_mut()).is_pending());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timed.await, None);
+        assert_eq!(
+            timeout(&clock, Duration::from_secs(1), async { 7 }).await,
+            Some(7)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+// Turns messages into the lines a `Transport` carries and back.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<String>;
+    fn decode<T: DeserializeOwned>(&self, line: &str) -> anyhow::Result<T>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<String> {
+        serde_json::to_string(value).context("encoding json")
+    }
+
+    fn decode<T: DeserializeOwned>(&self, line: &str) -> anyhow::Result<T> {
+        serde_json::from_str(line).context("decoding json")
+    }
+}
+
+// CBOR is binary and may contain newlines, so each frame is base64 encoded to
+// keep one message per line.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<String> {
+        use base64::Engine;
+
+        let bytes = serde_cbor::to_vec(value).context("encoding cbor")?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, line: &str) -> anyhow::Result<T> {
+        use base64::Engine;
+
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(line)
+            .context("decoding cbor frame")?;
+        serde_cbor::from_slice(&bytes).context("decoding cbor")
+    }
+}
+
+// The codec a `Network` speaks. Maelstrom only understands JSON, so anything
+// else is for peers on a custom transport.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec for WireFormat {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<String> {
+        match self {
+            Self::Json => Json.encode(value),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, line: &str) -> anyhow::Result<T> {
+        match self {
+            Self::Json => Json.decode(line),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor.decode(line),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::marker::PhantomData;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    network::Network,
+    protocol::Init,
+    report::{Metrics, NodeReport},
+    service::StoragePayload,
+    Body, Event, Message, Node,
+};
+
+// `Node` is generic over its payload, so nodes with different payloads can't
+// sit behind one `dyn Node`. An `ErasedNode` takes every message with its
+// payload still as the JSON the network read, and the wrapper built by `erase`
+// decodes it into the node's own payload type before stepping. That costs an
+// extra pass through `serde_json::Value` per message, which is why the
+// monomorphized `Server::serve` stays the default.
+#[async_trait::async_trait]
+pub trait ErasedNode<IP>: Send
+where
+    IP: Clone,
+{
+    fn handled_types(&self) -> &'static [&'static str];
+    fn debug_dump(&self) -> serde_json::Value;
+    fn report(&self, report: &mut NodeReport);
+    fn metrics(&self, network: &Network<IP>) -> Metrics;
+    fn capabilities(&self) -> Option<serde_json::Value>;
+    fn on_reconfigure(&mut self, node_ids: &[String], network: &Network<IP>) -> anyhow::Result<()>;
+
+    async fn step(
+        &mut self,
+        event: Event<serde_json::Value, IP>,
+        network: &Network<IP>,
+    ) -> anyhow::Result<()>;
+
+    async fn on_storage(
+        &mut self,
+        message: Message<StoragePayload>,
+        network: &Network<IP>,
+    ) -> anyhow::Result<()>;
+
+    async fn finalize(&mut self, network: &Network<IP>) -> anyhow::Result<()>;
+
+    fn clone_box(&self) -> Box<dyn ErasedNode<IP>>;
+}
+
+struct Erased<N, P> {
+    node: N,
+    _payload: PhantomData<fn() -> P>,
+}
+
+// Builds `N` from `init` and erases its payload type, for use in the factory
+// given to `Server::serve_dyn`.
+pub fn erase<N, P, IP>(init: Init, network: &Network<IP>) -> anyhow::Result<Box<dyn ErasedNode<IP>>>
+where
+    N: Node<P, IP> + Clone + Send + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Clone + Send + Sync + 'static,
+{
+    let node = N::from_init(init, network)?;
+    Ok(Box::new(Erased {
+        node,
+        _payload: PhantomData,
+    }))
+}
+
+#[async_trait::async_trait]
+impl<N, P, IP> ErasedNode<IP> for Erased<N, P>
+where
+    N: Node<P, IP> + Clone + Send + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Clone + Send + Sync + 'static,
+{
+    fn handled_types(&self) -> &'static [&'static str] {
+        N::handled_types()
+    }
+
+    fn debug_dump(&self) -> serde_json::Value {
+        self.node.debug_dump()
+    }
+
+    fn report(&self, report: &mut NodeReport) {
+        self.node.report(report)
+    }
+
+    fn metrics(&self, network: &Network<IP>) -> Metrics {
+        self.node.metrics(network)
+    }
+
+    fn capabilities(&self) -> Option<serde_json::Value> {
+        self.node.capabilities()
+    }
+
+    fn on_reconfigure(&mut self, node_ids: &[String], network: &Network<IP>) -> anyhow::Result<()> {
+        self.node.on_reconfigure(node_ids, network)
+    }
+
+    async fn step(
+        &mut self,
+        event: Event<serde_json::Value, IP>,
+        network: &Network<IP>,
+    ) -> anyhow::Result<()> {
+        let event = match event {
+            Event::Message(message) => {
+                let payload = serde_json::from_value(message.body.payload)
+                    .context("could not deserialize payload into the node's type")?;
+                Event::Message(Message {
+                    src: message.src,
+                    dst: message.dst,
+                    body: Body {
+                        id: message.body.id,
+                        in_reply_to: message.body.in_reply_to,
+                        payload,
+                    },
+                })
+            }
+            Event::Injected(payload) => Event::Injected(payload),
+            Event::Storage(message) => Event::Storage(message),
+            Event::Error(error) => Event::Error(error),
+        };
+        self.node.step(event, network).await
+    }
+
+    async fn on_storage(
+        &mut self,
+        message: Message<StoragePayload>,
+        network: &Network<IP>,
+    ) -> anyhow::Result<()> {
+        self.node.on_storage(message, network).await
+    }
+
+    async fn finalize(&mut self, network: &Network<IP>) -> anyhow::Result<()> {
+        self.node.finalize(network).await
+    }
+
+    fn clone_box(&self) -> Box<dyn ErasedNode<IP>> {
+        Box::new(Erased {
+            node: self.node.clone(),
+            _payload: PhantomData,
+        })
+    }
+}
+
+// What `Server::serve_dyn` runs. It's only ever built from a factory, never
+// through `from_init`.
+pub(crate) struct DynNode<IP>(pub(crate) Box<dyn ErasedNode<IP>>);
+
+impl<IP> Clone for DynNode<IP>
+where
+    IP: Clone,
+{
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+#[async_trait::async_trait]
+impl<IP> Node<serde_json::Value, IP> for DynNode<IP>
+where
+    IP: Clone + Send + Sync + 'static,
+{
+    fn from_init(_init: Init, _network: &Network<IP>) -> anyhow::Result<Self> {
+        anyhow::bail!("dynamic nodes are built by the factory given to serve_dyn")
+    }
+
+    fn debug_dump(&self) -> serde_json::Value {
+        self.0.debug_dump()
+    }
+
+    fn report(&self, report: &mut NodeReport) {
+        self.0.report(report)
+    }
+
+    fn metrics(&self, network: &Network<IP>) -> Metrics {
+        self.0.metrics(network)
+    }
+
+    fn capabilities(&self) -> Option<serde_json::Value> {
+        self.0.capabilities()
+    }
+
+    fn on_reconfigure(&mut self, node_ids: &[String], network: &Network<IP>) -> anyhow::Result<()> {
+        self.0.on_reconfigure(node_ids, network)
+    }
+
+    async fn step(
+        &mut self,
+        event: Event<serde_json::Value, IP>,
+        network: &Network<IP>,
+    ) -> anyhow::Result<()> {
+        self.0.step(event, network).await
+    }
+
+    async fn on_storage(
+        &mut self,
+        message: Message<StoragePayload>,
+        network: &Network<IP>,
+    ) -> anyhow::Result<()> {
+        self.0.on_storage(message, network).await
+    }
+
+    async fn finalize(&mut self, network: &Network<IP>) -> anyhow::Result<()> {
+        self.0.finalize(network).await
+    }
+}
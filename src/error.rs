@@ -0,0 +1,144 @@
+use crate::protocol::ErrorCode;
+
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+// The failure kinds callers branch on, returned by `Network` and `Storage`
+// methods. Failures that fit none of them are `Other`; in an
+// `anyhow::Error` chain, use `Error::find` to get one back out.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("timed out: {0}")]
+    Timeout(String),
+    #[error("compare-and-set precondition failed: {0}")]
+    CasConflict(String),
+    #[error("key does not exist: {0}")]
+    KeyNotFound(String),
+    #[error("deserialization failed")]
+    Deserialize(#[source] Source),
+    #[error("transport failed")]
+    Transport(#[from] std::io::Error),
+    // A reply that fits none of the above: any other error code, or a
+    // message that isn't the reply we asked for.
+    #[error("protocol error {code:?}: {text}")]
+    Protocol {
+        code: Option<ErrorCode>,
+        text: String,
+    },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl Error {
+    pub fn deserialize(source: impl Into<Source>) -> Self {
+        Self::Deserialize(source.into())
+    }
+
+    pub fn protocol(text: impl Into<String>) -> Self {
+        Self::Protocol {
+            code: None,
+            text: text.into(),
+        }
+    }
+
+    // The Maelstrom code a reply carried, if this came from one.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::Timeout(_) => Some(ErrorCode::Timeout),
+            Self::CasConflict(_) => Some(ErrorCode::PreconditionFailed),
+            Self::KeyNotFound(_) => Some(ErrorCode::KeyDoesNotExist),
+            Self::Protocol { code, .. } => *code,
+            Self::Deserialize(_) | Self::Transport(_) | Self::Other(_) => None,
+        }
+    }
+
+    // Like `anyhow::Context::context`, but keeps the variant: its message,
+    // if it has one, is prefixed with `context`.
+    pub fn context(self, context: impl std::fmt::Display) -> Self {
+        self.with_context(vec![context.to_string()])
+    }
+
+    // `context` with several layers, outermost first.
+    fn with_context(self, context: Vec<String>) -> Self {
+        if context.is_empty() {
+            return self;
+        }
+        let prefix = |text: String| format!("{}: {}", context.join(": "), text);
+        match self {
+            Self::Timeout(text) => Self::Timeout(prefix(text)),
+            Self::CasConflict(text) => Self::CasConflict(prefix(text)),
+            Self::KeyNotFound(text) => Self::KeyNotFound(prefix(text)),
+            Self::Protocol { code, text } => Self::Protocol {
+                code,
+                text: prefix(text),
+            },
+            Self::Other(error) => Self::Other(error.context(context.join(": "))),
+            error => error,
+        }
+    }
+
+    // The first crate error anywhere in `error`'s chain.
+    pub fn find(error: &anyhow::Error) -> Option<&Error> {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Error>())
+    }
+}
+
+impl From<(ErrorCode, String)> for Error {
+    fn from((code, text): (ErrorCode, String)) -> Self {
+        match code {
+            ErrorCode::Timeout => Self::Timeout(text),
+            ErrorCode::PreconditionFailed => Self::CasConflict(text),
+            ErrorCode::KeyDoesNotExist => Self::KeyNotFound(text),
+            code => Self::Protocol {
+                code: Some(code),
+                text,
+            },
+        }
+    }
+}
+
+// Comes back out as the crate error in `error`'s chain, if there is one, so
+// `?` on an `anyhow::Result` keeps it matchable.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let context = error
+            .chain()
+            .take_while(|cause| cause.downcast_ref::<Error>().is_none())
+            .map(|cause| cause.to_string())
+            .collect();
+        match error.downcast::<Error>() {
+            Ok(found) => found.with_context(context),
+            Err(error) => Self::Other(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn conversion_from_anyhow_keeps_the_variant_and_its_context() {
+        let wrapped: anyhow::Result<()> = Err(Error::Timeout("read".to_string()))
+            .context("fetching value")
+            .context("handling poll");
+        let error = Error::from(wrapped.unwrap_err());
+        assert!(matches!(&error, Error::Timeout(_)));
+        assert_eq!(
+            error.to_string(),
+            "timed out: handling poll: fetching value: read"
+        );
+        assert_eq!(error.code(), Some(ErrorCode::Timeout));
+    }
+
+    #[test]
+    fn conversion_from_anyhow_without_a_crate_error_is_other() {
+        let error = Error::from(anyhow::anyhow!("disk full").context("saving"));
+        assert!(matches!(&error, Error::Other(_)));
+        assert_eq!(format!("{:#}", error), "saving: disk full");
+        assert_eq!(error.code(), None);
+    }
+}
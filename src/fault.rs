@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{network::Network, protocol::UntypedMessage};
+
+// What happens to an outbound message a fault rule matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    Pass,
+    Drop,
+    // Written out once this has passed on the network's clock.
+    Delay(Duration),
+}
+
+// Sees every outbound message with its sequence number: 1 for the first
+// message of its type to its destination, 2 for the second, and so on.
+pub type FaultRule = Arc<dyn Fn(&UntypedMessage, usize) -> FaultAction + Send + Sync>;
+
+#[derive(Clone)]
+enum Rule {
+    Predicate(FaultRule),
+    // Applies to everything sent to `dst` while the time since `install`
+    // falls in `window`.
+    Window {
+        dst: String,
+        window: Range<Duration>,
+        action: FaultAction,
+    },
+}
+
+// Drops or delays chosen outbound messages, for replaying a scripted failure
+// rather than a random one: "drop the 3rd gossip to n2". Rules run in the
+// order they were added and the first that doesn't pass decides. Messages
+// are counted whatever happens to them, so a dropped message still takes up
+// its sequence number.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    rules: Vec<Rule>,
+    sent: Arc<Mutex<HashMap<(String, String), usize>>>,
+}
+
+impl Debug for FaultInjector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FaultInjector")
+            .field("rules", &self.rules.len())
+            .finish()
+    }
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule<F>(self, rule: F) -> Self
+    where
+        F: Fn(&UntypedMessage, usize) -> FaultAction + Send + Sync + 'static,
+    {
+        self.push(Rule::Predicate(Arc::new(rule)))
+    }
+
+    pub fn drop_nth(self, kind: &str, dst: &str, n: usize) -> Self {
+        self.act_on_nth(kind, dst, n, FaultAction::Drop)
+    }
+
+    pub fn delay_nth(self, kind: &str, dst: &str, n: usize, delay: Duration) -> Self {
+        self.act_on_nth(kind, dst, n, FaultAction::Delay(delay))
+    }
+
+    // `window` is measured from when the injector is installed.
+    pub fn drop_to_during(self, dst: &str, window: Range<Duration>) -> Self {
+        self.push(Rule::Window {
+            dst: dst.to_string(),
+            window,
+            action: FaultAction::Drop,
+        })
+    }
+
+    fn act_on_nth(self, kind: &str, dst: &str, n: usize, action: FaultAction) -> Self {
+        let (kind, dst) = (kind.to_string(), dst.to_string());
+        self.rule(move |message, seq| {
+            if seq == n && message.dst == dst && message.kind() == Some(kind.as_str()) {
+                action
+            } else {
+                FaultAction::Pass
+            }
+        })
+    }
+
+    fn push(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    fn action(&self, message: &UntypedMessage, since_install: Duration) -> FaultAction {
+        let seq = {
+            let mut sent = self.sent.lock().unwrap();
+            let kind = message.kind().unwrap_or("unknown").to_string();
+            let seq = sent.entry((message.dst.clone(), kind)).or_default();
+            *seq += 1;
+            *seq
+        };
+
+        self.rules
+            .iter()
+            .map(|rule| match rule {
+                Rule::Predicate(rule) => rule(message, seq),
+                Rule::Window {
+                    dst,
+                    window,
+                    action,
+                } if message.dst == *dst && window.contains(&since_install) => *action,
+                Rule::Window { .. } => FaultAction::Pass,
+            })
+            .find(|action| *action != FaultAction::Pass)
+            .unwrap_or(FaultAction::Pass)
+    }
+
+    // Adds the rules as an outbound filter. Once its delay is up, a delayed
+    // message goes on through the filters added after this one, a mock store
+    // included, as it would have undelayed.
+    pub fn install<IP>(&self, network: Network<IP>) -> Network<IP>
+    where
+        IP: Debug + Clone + Send + Sync + 'static,
+    {
+        let injector = self.clone();
+        let delayed = network.clone();
+        let installed = network.clock().now();
+        let after = network.outbound_filter_count() + 1;
+        network.with_outbound_filter(Arc::new(move |message| {
+            let since_install = delayed.clock().elapsed(installed);
+            match injector.action(&message, since_install) {
+                FaultAction::Pass => Some(message),
+                FaultAction::Drop => None,
+                FaultAction::Delay(delay) => {
+                    let network = delayed.clone();
+                    delayed.run_after(delay, move || {
+                        if let Err(e) = network.write_past_filters(message, after) {
+                            eprintln!("writing delayed message failed: {:#}", e);
+                        }
+                    });
+                    None
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::MockClock, mock_store::MockStore, protocol::UntypedMessage, service::StoragePayload,
+        transport::Transport, Message,
+    };
+
+    fn gossip(dst: &str, n: u64) -> Message<serde_json::Value> {
+        Message::new("n1", dst, serde_json::json!({"type": "gossip", "n": n}))
+    }
+
+    #[test]
+    fn drops_the_scripted_messages_and_no_others() {
+        let (transport, output) = Transport::in_memory("");
+        let network = FaultInjector::new()
+            .drop_nth("gossip", "n2", 3)
+            .rule(|message, seq| {
+                if message.dst == "n3" && seq % 2 == 0 {
+                    FaultAction::Drop
+                } else {
+                    FaultAction::Pass
+                }
+            })
+            .install(Network::<()>::with_transport(transport));
+        for n in 1..=5 {
+            network.send(gossip("n2", n)).unwrap();
+            network.send(gossip("n3", n)).unwrap();
+        }
+
+        let written: Vec<(String, u64)> = output
+            .lines()
+            .iter()
+            .map(|line| {
+                let message: UntypedMessage = serde_json::from_str(line).unwrap();
+                (message.dst, message.body.payload["n"].as_u64().unwrap())
+            })
+            .collect();
+        let to = |dst: &str| -> Vec<u64> {
+            written
+                .iter()
+                .filter(|(to, _)| to == dst)
+                .map(|(_, n)| *n)
+                .collect()
+        };
+        assert_eq!(to("n2"), [1, 2, 4, 5]);
+        assert_eq!(to("n3"), [1, 3, 5]);
+    }
+
+    // The delayed request goes on to the mock store installed after the
+    // injector, rather than out to a real one that isn't there.
+    #[tokio::test]
+    async fn delayed_storage_requests_still_reach_the_mock_store() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new();
+        let (transport, output) = Transport::in_memory("");
+        let network = store.install(
+            FaultInjector::new()
+                .delay_nth("write", "lin-kv", 1, Duration::from_millis(100))
+                .install(Network::<()>::with_transport(transport).with_clock(clock.clone())),
+        );
+        let dispatcher = network.clone();
+        tokio::spawn(
+            async move { while dispatcher.recv::<serde_json::Value>().await.is_some() {} },
+        );
+
+        let write = Message::new(
+            "n1",
+            "lin-kv",
+            StoragePayload::Write {
+                key: "k".to_string(),
+                value: 5.into(),
+            },
+        );
+        let write = tokio::spawn({
+            let network = network.clone();
+            async move { network.request(write).await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(store.value("lin-kv", "k"), None);
+
+        clock.advance(Duration::from_millis(100));
+        write.await.unwrap().unwrap();
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(5)));
+        assert!(output.lines().is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+
+use rand::seq::{index::sample, SliceRandom};
+use roaring::RoaringTreemap;
+
+// What backs an `IdSet`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdSetKind {
+    // Broadcast ids are mostly dense from zero, which a roaring bitmap stores
+    // in a fraction of a hash set's memory and diffs a container at a time
+    // rather than id by id.
+    #[default]
+    Roaring,
+    Hash,
+}
+
+// A set of broadcast ids, as gossip diffs them. The wire still carries
+// `usize` ids either way.
+#[derive(Debug, Clone)]
+pub enum IdSet {
+    Roaring(RoaringTreemap),
+    Hash(HashSet<usize>),
+}
+
+impl IdSet {
+    pub fn new(kind: IdSetKind) -> Self {
+        match kind {
+            IdSetKind::Roaring => Self::Roaring(RoaringTreemap::new()),
+            IdSetKind::Hash => Self::Hash(HashSet::new()),
+        }
+    }
+
+    pub fn insert(&mut self, id: usize) -> bool {
+        match self {
+            Self::Roaring(ids) => ids.insert(id as u64),
+            Self::Hash(ids) => ids.insert(id),
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        match self {
+            Self::Roaring(ids) => ids.contains(id as u64),
+            Self::Hash(ids) => ids.contains(&id),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Roaring(ids) => ids.len() as usize,
+            Self::Hash(ids) => ids.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        match self {
+            Self::Roaring(ids) => Box::new(ids.iter().map(|id| id as usize)),
+            Self::Hash(ids) => Box::new(ids.iter().copied()),
+        }
+    }
+
+    pub fn is_subset(&self, other: &IdSet) -> bool {
+        match (self, other) {
+            (Self::Roaring(ids), Self::Roaring(other)) => ids.is_subset(other),
+            _ => self.iter().all(|id| other.contains(id)),
+        }
+    }
+
+    // Splits the ids not in `exhausted` into those `known` lacks and those it
+    // has. Bitmaps do it with set operations; a hash set partitions id by id
+    // into two new sets.
+    pub fn split_by(&self, known: &IdSet, exhausted: &IdSet) -> (IdSet, IdSet) {
+        match (self, known, exhausted) {
+            (Self::Roaring(ids), Self::Roaring(known), Self::Roaring(exhausted)) => (
+                Self::Roaring(&(ids - known) - exhausted),
+                Self::Roaring(&(ids & known) - exhausted),
+            ),
+            _ => {
+                let (known, unknown) = self
+                    .iter()
+                    .filter(|id| !exhausted.contains(*id))
+                    .partition(|id| known.contains(*id));
+                (Self::Hash(unknown), Self::Hash(known))
+            }
+        }
+    }
+
+    // Up to `amount` ids picked at random.
+    pub fn sample(&self, amount: usize) -> Vec<usize> {
+        let mut rng = rand::thread_rng();
+        match self {
+            Self::Roaring(ids) => sample(&mut rng, self.len(), amount.min(self.len()))
+                .into_iter()
+                .filter_map(|rank| ids.select(rank as u64))
+                .map(|id| id as usize)
+                .collect(),
+            Self::Hash(ids) => {
+                let ids: Vec<usize> = ids.iter().copied().collect();
+                ids.choose_multiple(&mut rng, amount).copied().collect()
+            }
+        }
+    }
+}
+
+impl Extend<usize> for IdSet {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, ids: I) {
+        match self {
+            Self::Roaring(set) => set.extend(ids.into_iter().map(|id| id as u64)),
+            Self::Hash(set) => set.extend(ids),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [IdSetKind; 2] = [IdSetKind::Roaring, IdSetKind::Hash];
+
+    fn set_of(kind: IdSetKind, ids: impl IntoIterator<Item = usize>) -> IdSet {
+        let mut set = IdSet::new(kind);
+        set.extend(ids);
+        set
+    }
+
+    fn sorted(set: &IdSet) -> Vec<usize> {
+        let mut ids: Vec<usize> = set.iter().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn both_kinds_split_the_same_way() {
+        for kind in KINDS {
+            let messages = set_of(kind, 0..10);
+            let known = set_of(kind, [1, 2, 3, 20]);
+            let exhausted = set_of(kind, [0, 3]);
+
+            let (unknown, already_known) = messages.split_by(&known, &exhausted);
+            assert_eq!(sorted(&unknown), [4, 5, 6, 7, 8, 9], "{:?}", kind);
+            assert_eq!(sorted(&already_known), [1, 2], "{:?}", kind);
+            assert!(already_known.is_subset(&known), "{:?}", kind);
+            assert!(!messages.is_subset(&known), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn samples_distinct_ids_from_the_set() {
+        for kind in KINDS {
+            let set = set_of(kind, (0..100).map(|id| id * 3));
+            let mut picked = set.sample(10);
+            assert!(picked.iter().all(|id| set.contains(*id)), "{:?}", kind);
+            picked.sort_unstable();
+            picked.dedup();
+            assert_eq!(picked.len(), 10, "{:?}", kind);
+            assert_eq!(set.sample(1_000).len(), 100, "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::Rng;
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+// How a unique id is put together. `Snowflake` packs milliseconds since the
+// epoch, the node's index and a per-millisecond sequence into a u64; `Counter`
+// prefixes a local counter with the node id; `Uuid` is a random v4 UUID.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdStrategy {
+    #[default]
+    Snowflake,
+    Counter,
+    Uuid,
+}
+
+#[derive(Debug)]
+pub struct IdGenerator {
+    strategy: IdStrategy,
+    node_id: String,
+    node_index: u64,
+    counter: AtomicU64,
+    // `(millisecond, sequence)` of the last snowflake handed out.
+    last: Mutex<(u64, u64)>,
+}
+
+impl IdGenerator {
+    // `node_index` must be distinct per node and fit in `NODE_BITS`, e.g. the
+    // node's position in `init.node_ids`.
+    pub fn new(strategy: IdStrategy, node_id: String, node_index: usize) -> Self {
+        assert!(
+            (node_index as u64) < (1 << NODE_BITS),
+            "node index {} does not fit in a snowflake",
+            node_index
+        );
+        Self {
+            strategy,
+            node_id,
+            node_index: node_index as u64,
+            counter: AtomicU64::new(0),
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn next_id(&self) -> serde_json::Value {
+        match self.strategy {
+            IdStrategy::Snowflake => self.next_snowflake(now_ms()).into(),
+            IdStrategy::Counter => {
+                let n = self.counter.fetch_add(1, Ordering::Relaxed);
+                format!("{}-{}", self.node_id, n).into()
+            }
+            IdStrategy::Uuid => uuid_v4().into(),
+        }
+    }
+
+    // If the clock went backwards, or the sequence for this millisecond ran
+    // out, the id is taken from the last millisecond used (or the one after
+    // it) instead, so ids keep increasing regardless of the wall clock.
+    fn next_snowflake(&self, now_ms: u64) -> u64 {
+        let mut last = self.last.lock().unwrap();
+        let (last_ms, last_sequence) = *last;
+        let next = if now_ms > last_ms {
+            (now_ms, 0)
+        } else if last_sequence < MAX_SEQUENCE {
+            (last_ms, last_sequence + 1)
+        } else {
+            (last_ms + 1, 0)
+        };
+        *last = next;
+
+        let (ms, sequence) = next;
+        (ms << (NODE_BITS + SEQUENCE_BITS)) | (self.node_index << SEQUENCE_BITS) | sequence
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn uuid_v4() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use super::*;
+
+    #[test]
+    fn snowflakes_keep_increasing_when_the_clock_goes_back() {
+        let ids = IdGenerator::new(IdStrategy::Snowflake, "n1".to_string(), 3);
+        let first = ids.next_snowflake(1_000);
+        let second = ids.next_snowflake(900);
+        let third = ids.next_snowflake(1_000);
+        let fourth = ids.next_snowflake(1_001);
+        assert!(first < second && second < third && third < fourth);
+        assert_eq!(fourth >> (NODE_BITS + SEQUENCE_BITS), 1_001);
+    }
+
+    #[test]
+    fn snowflakes_roll_into_the_next_millisecond_when_the_sequence_runs_out() {
+        let ids = IdGenerator::new(IdStrategy::Snowflake, "n1".to_string(), 0);
+        let mut last = None;
+        for _ in 0..=MAX_SEQUENCE {
+            last = Some(ids.next_snowflake(5));
+        }
+        assert_eq!(last.unwrap() >> (NODE_BITS + SEQUENCE_BITS), 5);
+        let overflowed = ids.next_snowflake(5);
+        assert!(overflowed > last.unwrap());
+        assert_eq!(overflowed >> (NODE_BITS + SEQUENCE_BITS), 6);
+    }
+
+    #[test]
+    fn ids_are_unique_across_threads_and_nodes() {
+        for strategy in [IdStrategy::Snowflake, IdStrategy::Counter, IdStrategy::Uuid] {
+            let generators: Vec<Arc<IdGenerator>> = (0..2)
+                .map(|i| Arc::new(IdGenerator::new(strategy, format!("n{}", i), i)))
+                .collect();
+            let handles: Vec<_> = generators
+                .iter()
+                .flat_map(|ids| std::iter::repeat_n(ids.clone(), 4))
+                .map(|ids| {
+                    std::thread::spawn(move || {
+                        (0..2_000).map(|_| ids.next_id()).collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            let mut seen = HashSet::new();
+            for handle in handles {
+                for id in handle.join().unwrap() {
+                    assert!(
+                        seen.insert(id.to_string()),
+                        "{:?} handed out twice",
+                        strategy
+                    );
+                }
+            }
+            assert_eq!(seen.len(), 2 * 4 * 2_000);
+        }
+    }
+}
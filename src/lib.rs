@@ -1,11 +1,31 @@
-use protocol::{UntypedBody, UntypedMessage};
+use anyhow::Context;
+use protocol::{MaelstromError, UntypedBody, UntypedMessage};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use service::{StoragePayload, STORAGE_ADDRESSES};
 
+pub mod bitset;
+pub mod clock;
+pub mod codec;
+pub mod erased;
+pub mod error;
+pub mod fault;
+pub mod id_set;
+pub mod ids;
+pub mod limiter;
+pub mod lru;
+pub mod mock_store;
 pub mod network;
 pub mod protocol;
+pub mod report;
+pub mod retry;
 pub mod server;
 pub mod service;
+pub mod shard;
+pub mod testing;
+pub mod transport;
+pub mod vector_clock;
+
+pub use error::Error;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Body<P> {
@@ -25,6 +45,31 @@ pub struct Message<P> {
     pub body: Body<P>,
 }
 
+impl<PAYLOAD> Message<PAYLOAD> {
+    pub fn new(src: impl Into<String>, dst: impl Into<String>, payload: PAYLOAD) -> Self {
+        Self {
+            src: src.into(),
+            dst: dst.into(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload,
+            },
+        }
+    }
+
+    pub fn with_in_reply_to(mut self, id: Option<usize>) -> Self {
+        self.body.in_reply_to = id;
+        self
+    }
+
+    // A reply to this message carrying `payload`, leaving the message itself
+    // usable for further replies.
+    pub fn reply_with<Q>(&self, payload: Q) -> Message<Q> {
+        Message::new(self.dst.clone(), self.src.clone(), payload).with_in_reply_to(self.body.id)
+    }
+}
+
 impl<PAYLOAD> Message<PAYLOAD>
 where
     PAYLOAD: Serialize,
@@ -42,14 +87,15 @@ where
     }
 }
 
-impl<PAYLOAD> From<UntypedMessage> for Message<PAYLOAD>
+impl<PAYLOAD> Message<PAYLOAD>
 where
     PAYLOAD: DeserializeOwned,
 {
-    fn from(untyped: UntypedMessage) -> Self {
+    pub fn try_from_untyped(untyped: UntypedMessage) -> anyhow::Result<Self> {
         let payload = serde_json::from_value(untyped.body.payload)
-            .expect("could not deserialize payload into provided type");
-        Self {
+            .map_err(error::Error::deserialize)
+            .context("could not deserialize payload into provided type")?;
+        Ok(Self {
             src: untyped.src,
             dst: untyped.dst,
             body: Body {
@@ -57,7 +103,16 @@ where
                 in_reply_to: untyped.body.in_reply_to,
                 payload,
             },
-        }
+        })
+    }
+}
+
+impl<PAYLOAD> From<UntypedMessage> for Message<PAYLOAD>
+where
+    PAYLOAD: DeserializeOwned,
+{
+    fn from(untyped: UntypedMessage) -> Self {
+        Self::try_from_untyped(untyped).expect("could not deserialize payload into provided type")
     }
 }
 
@@ -79,39 +134,92 @@ where
     }
 }
 
+#[derive(Debug, Clone)]
 pub enum NetworkEvent<InjectedPayload = ()> {
     Message(UntypedMessage),
     Injected(InjectedPayload),
+    // Reserved tick from the keepalive timer, consumed by the network.
+    Keepalive,
+    // Queued by the read thread once input is exhausted.
+    InputClosed,
 }
 
 #[derive(Debug, Clone)]
 pub enum Event<Payload, InjectedPayload = ()> {
     Message(Message<Payload>),
     Injected(InjectedPayload),
+    // Storage traffic no request was waiting for. The server hands it to
+    // `Node::on_storage` rather than `step`.
     Storage(Message<StoragePayload>),
+    Error(Message<MaelstromError>),
 }
 
-impl<P, IP> From<NetworkEvent<IP>> for Event<P, IP>
+impl<P, IP> Event<P, IP> {
+    // Who sent the event, if it came in over the network.
+    pub fn source(&self) -> Option<&str> {
+        match self {
+            Event::Message(message) => Some(&message.src),
+            Event::Storage(message) => Some(&message.src),
+            Event::Error(message) => Some(&message.src),
+            Event::Injected(_) => None,
+        }
+    }
+}
+
+// Replies to storage requests are settled by the network itself, so only
+// ones nobody is waiting for any more reach this conversion. They're routed by
+// address rather than by the node's payload type, so a body the
+// `StoragePayload` enum doesn't model is reported as an error instead of
+// being parsed as the node's own.
+impl<P, IP> TryFrom<NetworkEvent<IP>> for Event<P, IP>
 where
     P: DeserializeOwned,
 {
-    fn from(value: NetworkEvent<IP>) -> Self {
+    type Error = anyhow::Error;
+
+    fn try_from(value: NetworkEvent<IP>) -> anyhow::Result<Self> {
         match value {
             NetworkEvent::Message(untyped) => {
-                if STORAGE_ADDRESSES.contains(&untyped.dst.as_str())
-                    || STORAGE_ADDRESSES.contains(&untyped.src.as_str())
-                {
-                    let typed: Message<StoragePayload> = Message::from(untyped);
-                    return Event::Storage(typed);
+                if STORAGE_ADDRESSES.contains(&untyped.src.as_str()) {
+                    let typed: Message<StoragePayload> = Message::try_from_untyped(untyped.clone())
+                        .with_context(|| format!("unrecognized storage message {:?}", untyped))?;
+                    return Ok(Event::Storage(typed));
+                }
+                // Checked before the node's payload type, which usually has no
+                // error variant and would fail to parse it.
+                if untyped.kind() == Some("error") {
+                    let typed: Message<MaelstromError> = Message::try_from_untyped(untyped.clone())
+                        .with_context(|| format!("malformed error message {:?}", untyped))?;
+                    return Ok(Event::Error(typed));
                 }
-                let typed: Message<P> = Message::from(untyped);
-                Event::Message(typed)
+                let typed: Message<P> = Message::try_from_untyped(untyped.clone())
+                    .with_context(|| format!("unrecognized message {:?}", untyped))?;
+                Ok(Event::Message(typed))
             }
-            NetworkEvent::Injected(payload) => Event::Injected(payload),
+            NetworkEvent::Injected(payload) => Ok(Event::Injected(payload)),
+            NetworkEvent::Keepalive => Err(anyhow::anyhow!("keepalive is not a node event")),
+            NetworkEvent::InputClosed => Err(anyhow::anyhow!("end of input is not a node event")),
         }
     }
 }
 
+// Hands `event` to `node`: storage messages to `on_storage`, everything else
+// to `step`.
+pub(crate) async fn deliver<N, P, IP>(
+    node: &mut N,
+    event: Event<P, IP>,
+    network: &crate::network::Network<IP>,
+) -> anyhow::Result<()>
+where
+    N: Node<P, IP> + Send,
+    IP: Clone,
+{
+    match event {
+        Event::Storage(message) => node.on_storage(message, network).await,
+        event => node.step(event, network).await,
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Node<Payload, InjectedPayload = ()>
 where
@@ -120,10 +228,82 @@ where
     fn from_init(
         init: crate::protocol::Init,
         network: &crate::network::Network<InjectedPayload>,
-    ) -> Self;
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    // Message types the node accepts. Anything else is rejected with a
+    // `NotSupported` error before deserialization; an empty slice accepts all.
+    fn handled_types() -> &'static [&'static str]
+    where
+        Self: Sized,
+    {
+        &[]
+    }
+
+    // The state reported in reply to `debug_dump` when the server's debug
+    // endpoint is on.
+    fn debug_dump(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    // Adds the node's own counters to the report written at shutdown.
+    fn report(&self, _report: &mut crate::report::NodeReport) {}
+
+    // The reply to a `metrics` request. Nodes with counters of their own
+    // start from the network's and fill in the rest.
+    fn metrics(
+        &self,
+        network: &crate::network::Network<InjectedPayload>,
+    ) -> crate::report::Metrics {
+        network.metrics()
+    }
+
+    // Sent back as `capabilities` in `init_ok`; `None` leaves it out.
+    fn capabilities(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    // Called when a `reconfigure` message changes the cluster to `node_ids`,
+    // which `Network::node_ids` already returns. It runs on the node every
+    // step is cloned from, so anything steps already running or queued per
+    // source should see has to be shared between clones.
+    fn on_reconfigure(
+        &mut self,
+        _node_ids: &[String],
+        _network: &crate::network::Network<InjectedPayload>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     async fn step(
         &mut self,
         event: Event<Payload, InjectedPayload>,
         network: &crate::network::Network<InjectedPayload>,
     ) -> anyhow::Result<()>;
+
+    // Storage messages no request was waiting for, e.g. the reply to a
+    // fire-and-forget write or one that came after its request timed out.
+    // Ignored unless a node has a use for them.
+    async fn on_storage(
+        &mut self,
+        _message: Message<StoragePayload>,
+        _network: &crate::network::Network<InjectedPayload>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // Runs once the server stops taking events and the steps still in flight
+    // have finished, before the network shuts down and the shutdown report is
+    // written, for final async work such as flushing or reading back state.
+    // Replies to its requests are still delivered but new messages are
+    // dropped, and it's cut off after `server::FINALIZE_TIMEOUT`. Replies
+    // that come over the transport can't arrive once input has closed;
+    // services answered in-process, e.g. a mock store, still reply.
+    async fn finalize(
+        &mut self,
+        _network: &crate::network::Network<InjectedPayload>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
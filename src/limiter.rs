@@ -0,0 +1,83 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    available: usize,
+    high: VecDeque<tokio::sync::oneshot::Sender<()>>,
+    normal: VecDeque<tokio::sync::oneshot::Sender<()>>,
+}
+
+// Caps the number of requests in flight. Waiters queue by priority, and a
+// freed permit always goes to the oldest high-priority waiter before any
+// normal one.
+#[derive(Debug)]
+pub struct RequestLimiter {
+    state: Mutex<LimiterState>,
+}
+
+impl RequestLimiter {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            state: Mutex::new(LimiterState {
+                available: max_in_flight,
+                high: VecDeque::new(),
+                normal: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> RequestPermit {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                return RequestPermit {
+                    limiter: self.clone(),
+                };
+            }
+
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            match priority {
+                Priority::High => state.high.push_back(tx),
+                Priority::Normal => state.normal.push_back(tx),
+            }
+            rx
+        };
+
+        rx.await.expect("request limiter dropped with waiters");
+        RequestPermit {
+            limiter: self.clone(),
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.high.pop_front().or_else(|| state.normal.pop_front()) {
+            // A waiter whose request was cancelled has dropped its receiver.
+            if waiter.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+pub struct RequestPermit {
+    limiter: Arc<RequestLimiter>,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
@@ -0,0 +1,908 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    network::Network,
+    protocol::{ErrorCode, UntypedBody, UntypedMessage},
+    service::{StoragePayload, STORAGE_ADDRESSES},
+    NetworkEvent,
+};
+
+// An in-memory stand-in for Maelstrom's lin-kv, seq-kv, lww-kv and lin-tso,
+// for running nodes locally. `install` catches storage requests on their way
+// out of a network and answers them back into it. Every operation can be
+// delayed and made to fail with `TemporarilyUnavailable`, so retry and
+// timeout paths can be exercised; delays run on the network's clock, so a
+// `MockClock` makes them virtual, and a fixed seed makes failures repeat.
+// Without a delay the reply is queued before the request's `send` returns.
+#[derive(Debug, Clone)]
+pub struct MockStore {
+    state: Arc<Mutex<MockState>>,
+    // Keyed by request type, e.g. "read" or "cas".
+    latency: HashMap<String, Duration>,
+    failure_rate: f64,
+}
+
+#[derive(Debug)]
+struct MockState {
+    values: HashMap<(String, String), serde_json::Value>,
+    next_ts: u64,
+    rng: StdRng,
+}
+
+impl Default for MockStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockStore {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                values: HashMap::new(),
+                next_ts: 0,
+                rng: StdRng::seed_from_u64(0),
+            })),
+            latency: HashMap::new(),
+            failure_rate: 0.0,
+        }
+    }
+
+    pub fn with_latency(mut self, kind: &str, latency: Duration) -> Self {
+        self.latency.insert(kind.to_string(), latency);
+        self
+    }
+
+    // The share of requests, from 0.0 to 1.0, answered with
+    // `TemporarilyUnavailable` instead of being applied.
+    pub fn with_failure_rate(mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate;
+        self
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.state.lock().unwrap().rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    // What `key` currently holds in `service`, e.g. "lin-kv".
+    pub fn value(&self, service: &str, key: &str) -> Option<serde_json::Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .values
+            .get(&(service.to_string(), key.to_string()))
+            .cloned()
+    }
+
+    // Every key `service` holds, in order.
+    pub fn keys(&self, service: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut keys: Vec<String> = state
+            .values
+            .keys()
+            .filter(|(held_by, _)| held_by == service)
+            .map(|(_, key)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // Must be the last change made to `network`: replies are delayed on the
+    // clock it has now, which a later `with_clock` doesn't change.
+    pub fn install<IP>(&self, network: Network<IP>) -> Network<IP>
+    where
+        IP: Debug + Clone + Send + Sync + 'static,
+    {
+        let store = self.clone();
+        let replies = network.clone();
+        network.with_outbound_filter(Arc::new(move |message| {
+            if !STORAGE_ADDRESSES.contains(&message.dst.as_str()) {
+                return Some(message);
+            }
+
+            let reply = store.answer(&message);
+            let latency = message
+                .kind()
+                .and_then(|kind| store.latency.get(kind))
+                .copied()
+                .unwrap_or_default();
+            if latency.is_zero() {
+                deliver(&replies, reply);
+            } else {
+                let network = replies.clone();
+                replies.run_after(latency, move || deliver(&network, reply));
+            }
+            None
+        }))
+    }
+
+    fn answer(&self, request: &UntypedMessage) -> UntypedMessage {
+        let mut state = self.state.lock().unwrap();
+        let failed = self.failure_rate > 0.0 && state.rng.gen_bool(self.failure_rate.min(1.0));
+        let payload = if failed {
+            error(ErrorCode::TemporarilyUnavailable, "injected by mock store")
+        } else {
+            match serde_json::from_value(request.body.payload.clone()) {
+                Ok(payload) => state.apply(&request.dst, payload),
+                Err(e) => error(ErrorCode::MalformedRequest, &e.to_string()),
+            }
+        };
+
+        UntypedMessage {
+            src: request.dst.clone(),
+            dst: request.src.clone(),
+            body: UntypedBody {
+                id: None,
+                in_reply_to: request.body.id,
+                payload: serde_json::to_value(payload).expect("serializing mock reply"),
+            },
+        }
+    }
+}
+
+impl MockState {
+    fn apply(&mut self, service: &str, payload: StoragePayload) -> StoragePayload {
+        match payload {
+            StoragePayload::Read { key } => match self.values.get(&(service.to_string(), key)) {
+                Some(value) => StoragePayload::ReadOk {
+                    value: value.clone(),
+                },
+                None => error(ErrorCode::KeyDoesNotExist, "key does not exist"),
+            },
+            StoragePayload::Write { key, value, .. } => {
+                self.values.insert((service.to_string(), key), value);
+                StoragePayload::WriteOk
+            }
+            StoragePayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+                ..
+            } => match self.values.get_mut(&(service.to_string(), key.clone())) {
+                Some(current) if *current == from => {
+                    *current = to;
+                    StoragePayload::CasOk
+                }
+                Some(current) => error(
+                    ErrorCode::PreconditionFailed,
+                    &format!("expected {}, found {}", from, current),
+                ),
+                None if create_if_not_exists == Some(true) => {
+                    self.values.insert((service.to_string(), key), to);
+                    StoragePayload::CasOk
+                }
+                None => error(ErrorCode::KeyDoesNotExist, "key does not exist"),
+            },
+            StoragePayload::Ts => {
+                self.next_ts += 1;
+                StoragePayload::TsOk { ts: self.next_ts }
+            }
+            reply => error(
+                ErrorCode::NotSupported,
+                &format!("not a storage request: {:?}", reply),
+            ),
+        }
+    }
+}
+
+fn error(code: ErrorCode, text: &str) -> StoragePayload {
+    StoragePayload::Error {
+        code: code.into(),
+        text: text.to_string(),
+    }
+}
+
+fn deliver<IP>(network: &Network<IP>, reply: UntypedMessage)
+where
+    IP: Debug + Clone + Send + Sync + 'static,
+{
+    if network.tx.send(NetworkEvent::Message(reply)).is_err() {
+        eprintln!("mock store reply dropped: network closed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        retry::{Retry, RetryPolicy, RetryStrategy},
+        service::{
+            CachedStore, IdempotencyToken, IdempotentValue, KeyConflict, LinearStore, Storage,
+            VersionedStore,
+        },
+        transport::Transport,
+        Error, Message,
+    };
+
+    // A network answered by `store`, with replies settled as they land.
+    fn network(store: &MockStore, clock: Arc<MockClock>) -> Network {
+        network_with_policy(store, clock, RetryPolicy::default())
+    }
+
+    fn network_with_policy(
+        store: &MockStore,
+        clock: Arc<MockClock>,
+        policy: RetryPolicy,
+    ) -> Network {
+        let (transport, _) = Transport::in_memory("");
+        let network = store.install(
+            Network::with_transport(transport)
+                .with_clock(clock)
+                .with_retry_policy(policy),
+        );
+        let dispatcher = network.clone();
+        tokio::spawn(
+            async move { while dispatcher.recv::<serde_json::Value>().await.is_some() {} },
+        );
+        network
+    }
+
+    #[tokio::test]
+    async fn answers_without_latency_at_once() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let storage = LinearStore::new("n1".to_string());
+
+        let missing = storage.read::<u64>("k".to_string(), &network).await;
+        assert!(matches!(missing, Err(Error::KeyNotFound(_))));
+        storage
+            .compare_and_store("k".to_string(), 0, 5, &network)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .read::<u64>("k".to_string(), &network)
+                .await
+                .unwrap(),
+            5
+        );
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(5)));
+    }
+
+    #[tokio::test]
+    async fn latency_runs_on_the_network_clock() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("read", Duration::from_secs(1));
+        let network = network(&store, clock.clone());
+        let storage = LinearStore::new("n1".to_string());
+
+        let reader = network.clone();
+        let read = tokio::spawn(async move {
+            Storage::<()>::try_read::<u64>(&storage, "k".to_string(), &reader).await
+        });
+        while network.pending_requests().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!read.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(read.await.unwrap().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn strict_cas_on_a_missing_key_fails_where_create_succeeds() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let storage = LinearStore::new("n1".to_string());
+
+        let error = storage
+            .compare_and_store_opts("k".to_string(), 0, 5, false, &network)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::KeyNotFound(_)), "{:?}", error);
+        assert_eq!(error.code(), Some(ErrorCode::KeyDoesNotExist));
+        assert_eq!(store.value("lin-kv", "k"), None);
+
+        storage
+            .compare_and_store_opts("k".to_string(), 0, 5, true, &network)
+            .await
+            .unwrap();
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(5)));
+        // Once it exists, strict CAS applies like any other.
+        storage
+            .compare_and_store_opts("k".to_string(), 5, 6, false, &network)
+            .await
+            .unwrap();
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(6)));
+    }
+
+    #[tokio::test]
+    async fn failures_answer_temporarily_unavailable() {
+        let store = MockStore::new().with_failure_rate(1.0);
+        let network = network(&store, Arc::new(MockClock::new()));
+        let storage = LinearStore::new("n1".to_string());
+
+        let error = storage
+            .compare_and_store("k".to_string(), 0, 5, &network)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Some(ErrorCode::TemporarilyUnavailable));
+        assert_eq!(store.value("lin-kv", "k"), None);
+    }
+
+    // The first write was applied, but its acknowledgement never arrived, and
+    // another write landed before the retry.
+    fn write(key: &str, value: u64) -> Message<StoragePayload> {
+        Message::new(
+            "n1",
+            "lin-kv",
+            StoragePayload::Write {
+                key: key.to_string(),
+                value: value.into(),
+            },
+        )
+    }
+
+    fn immediate() -> RetryPolicy {
+        RetryPolicy::default().with_strategy(RetryStrategy::Immediate)
+    }
+
+    #[tokio::test]
+    async fn request_retry_rides_out_injected_failures() {
+        let store = MockStore::new().with_failure_rate(0.5).with_seed(7);
+        let network = network_with_policy(&store, Arc::new(MockClock::new()), immediate());
+        for value in 1..=10 {
+            network.request_retry(write("k", value)).await.unwrap();
+            assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(value)));
+        }
+    }
+
+    #[tokio::test]
+    async fn request_retry_stops_after_max_attempts() {
+        let store = MockStore::new().with_failure_rate(1.0);
+        let policy = immediate().with_max_attempts(3);
+        let network = network_with_policy(&store, Arc::new(MockClock::new()), policy);
+
+        let error = network.request_retry(write("k", 1)).await.unwrap_err();
+        assert_eq!(error.code(), Some(ErrorCode::TemporarilyUnavailable));
+        assert!(error.to_string().contains("after 3 attempts"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn request_retry_gives_up_on_codes_the_policy_doesnt_retry() {
+        let store = MockStore::new().with_failure_rate(1.0);
+        let policy = immediate().with_retryable(vec![ErrorCode::Timeout]);
+        let network = network_with_policy(&store, Arc::new(MockClock::new()), policy);
+
+        let error = network.request_retry(write("k", 1)).await.unwrap_err();
+        assert_eq!(error.code(), Some(ErrorCode::TemporarilyUnavailable));
+        assert!(error.to_string().contains("after 1 attempts"), "{}", error);
+    }
+
+    // Both the reads and the CASes fail half the time, and every increment
+    // still lands exactly once.
+    #[tokio::test]
+    async fn read_cas_retries_injected_failures_by_the_policy() {
+        let store = MockStore::new().with_failure_rate(0.5).with_seed(11);
+        let network = network_with_policy(&store, Arc::new(MockClock::new()), immediate());
+        let storage = LinearStore::new("n1".to_string());
+        let increment = |current: Option<u64>| current.unwrap_or_default() + 1;
+
+        for _ in 0..10 {
+            storage
+                .read_cas("k".to_string(), increment, &network)
+                .await
+                .unwrap();
+        }
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(10)));
+    }
+
+    // Zero latency answers each request while it's still being sent, the
+    // tightest a reply can chase its request's registration. None may hang.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_all_get_their_replies() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let mut requests = tokio::task::JoinSet::new();
+        for i in 0..500 {
+            let network = network.clone();
+            requests.spawn(async move {
+                let key = format!("k{}", i % 10);
+                network.request(write(&key, i)).await.map(|_| ())
+            });
+        }
+
+        let all = async {
+            while let Some(request) = requests.join_next().await {
+                request.unwrap().unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), all)
+            .await
+            .expect("a request never got its reply");
+        assert!(network.pending_requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retried_write_is_not_applied_twice() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let storage = LinearStore::new("n1".to_string());
+        let (first, second) = (IdempotencyToken::new(), IdempotencyToken::new());
+
+        storage
+            .write_idempotent("k".to_string(), 1, first, &network)
+            .await
+            .unwrap();
+        storage
+            .write_idempotent("k".to_string(), 2, second, &network)
+            .await
+            .unwrap();
+        storage
+            .write_idempotent("k".to_string(), 1, first, &network)
+            .await
+            .unwrap();
+
+        let stored: IdempotentValue<u64> = storage.read("k".to_string(), &network).await.unwrap();
+        assert_eq!(stored.value, 2);
+        assert_eq!(stored.tokens, [first, second]);
+    }
+
+    #[tokio::test]
+    async fn retried_cas_succeeds_once_applied() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let storage = LinearStore::new("n1".to_string());
+        let token = IdempotencyToken::new();
+
+        storage
+            .compare_and_store_idempotent("k".to_string(), 0, 5, token, &network)
+            .await
+            .unwrap();
+        // The key no longer holds 0, but this token already moved it off 0.
+        storage
+            .compare_and_store_idempotent("k".to_string(), 0, 5, token, &network)
+            .await
+            .unwrap();
+        let other = storage
+            .compare_and_store_idempotent("k".to_string(), 0, 6, IdempotencyToken::new(), &network)
+            .await;
+        assert!(matches!(other, Err(Error::CasConflict(_))), "{:?}", other);
+
+        let stored: IdempotentValue<u64> = storage.read("k".to_string(), &network).await.unwrap();
+        assert_eq!(stored, IdempotentValue::new(5, token));
+    }
+
+    // The longest any of `writers` increments of one key took to land, each
+    // starting at a random point in the same 400ms and backing off with
+    // `strategy` whenever it loses the race. Runs on tokio's paused clock.
+    async fn longest_increment(strategy: RetryStrategy, writers: usize) -> Duration {
+        let store = MockStore::new()
+            .with_latency("read", Duration::from_millis(5))
+            .with_latency("cas", Duration::from_millis(5));
+        let (transport, _) = Transport::in_memory("");
+        let network: Network = store.install(Network::with_transport(transport));
+        let dispatcher = network.clone();
+        tokio::spawn(
+            async move { while dispatcher.recv::<serde_json::Value>().await.is_some() {} },
+        );
+        let storage = LinearStore::new("n1".to_string());
+        storage
+            .compare_and_store("k".to_string(), 0, 0, &network)
+            .await
+            .unwrap();
+
+        let mut increments = tokio::task::JoinSet::new();
+        for _ in 0..writers {
+            let (network, storage) = (network.clone(), storage.clone());
+            increments.spawn(async move {
+                let start = Duration::from_millis(rand::random::<u64>() % 400);
+                tokio::time::sleep(start).await;
+                let started = tokio::time::Instant::now();
+                let mut retry = Retry::new(strategy);
+                loop {
+                    let current: u64 = storage.read("k".to_string(), &network).await.unwrap();
+                    match storage
+                        .compare_and_store_opts(
+                            "k".to_string(),
+                            current,
+                            current + 1,
+                            false,
+                            &network,
+                        )
+                        .await
+                    {
+                        Ok(()) => return started.elapsed(),
+                        Err(Error::CasConflict(_)) => retry.backoff().await,
+                        Err(e) => panic!("increment failed: {:?}", e),
+                    }
+                }
+            });
+        }
+        let mut longest = Duration::ZERO;
+        while let Some(took) = increments.join_next().await {
+            longest = longest.max(took.unwrap());
+        }
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(writers)));
+        longest
+    }
+
+    // Writers that have lost more often retry sooner, so the unluckiest
+    // increment waits less than with a flat backoff. Summed over several
+    // runs, since the arrivals and the jitter are random.
+    #[tokio::test(start_paused = true)]
+    async fn fair_backoff_shortens_the_longest_wait_under_contention() {
+        let flat = RetryStrategy::Jittered {
+            base: Duration::from_millis(20),
+        };
+        let (mut fair, mut jittered) = (Duration::ZERO, Duration::ZERO);
+        for _ in 0..10 {
+            fair += longest_increment(RetryStrategy::default(), 60).await;
+            jittered += longest_increment(flat, 60).await;
+        }
+        assert!(fair < jittered, "fair {:?}, jittered {:?}", fair, jittered);
+    }
+
+    fn swap(key: &str, from: u64, to: u64) -> (String, u64, u64) {
+        (key.to_string(), from, to)
+    }
+
+    // Runs `multi_cas` with `pairs` on its own task.
+    fn spawn_multi_cas(
+        network: &Network,
+        pairs: Vec<(String, u64, u64)>,
+    ) -> tokio::task::JoinHandle<Result<Result<(), KeyConflict>, Error>> {
+        let network = network.clone();
+        tokio::spawn(async move {
+            LinearStore::new("n1".to_string())
+                .multi_cas(pairs, &network)
+                .await
+        })
+    }
+
+    // Each side swaps one key and then loses the other to its rival, so both
+    // abort and put back what they had swapped.
+    #[tokio::test]
+    async fn contending_multi_cas_both_abort_and_roll_back() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("cas", Duration::from_millis(10));
+        let network = network(&store, clock.clone());
+        for key in ["a", "b"] {
+            store.state.lock().unwrap().values.insert(
+                ("lin-kv".to_string(), key.to_string()),
+                serde_json::json!(0),
+            );
+        }
+
+        let first = spawn_multi_cas(&network, vec![swap("a", 0, 1), swap("b", 0, 1)]);
+        let second = spawn_multi_cas(&network, vec![swap("b", 0, 2), swap("a", 0, 2)]);
+        while !(first.is_finished() && second.is_finished()) {
+            clock.advance(Duration::from_millis(10));
+            tokio::task::yield_now().await;
+        }
+
+        let first = first.await.unwrap().unwrap().unwrap_err();
+        assert_eq!((first.key.as_str(), first.unrolled.len()), ("b", 0));
+        let second = second.await.unwrap().unwrap().unwrap_err();
+        assert_eq!((second.key.as_str(), second.unrolled.len()), ("a", 0));
+        for key in ["a", "b"] {
+            assert_eq!(store.value("lin-kv", key), Some(serde_json::json!(0)));
+        }
+    }
+
+    #[tokio::test]
+    async fn multi_cas_reports_an_unavailable_store_as_an_error() {
+        let store = MockStore::new().with_failure_rate(1.0);
+        let network = network(&store, Arc::new(MockClock::new()));
+
+        let result = spawn_multi_cas(&network, vec![swap("a", 0, 1)])
+            .await
+            .unwrap();
+        let error = result.unwrap_err();
+        assert_eq!(error.code(), Some(ErrorCode::TemporarilyUnavailable));
+    }
+
+    // "a" is overwritten after multi_cas swaps it, so swapping it back fails.
+    #[tokio::test]
+    async fn multi_cas_reports_keys_it_could_not_roll_back() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("cas", Duration::from_millis(10));
+        let network = network(&store, clock.clone());
+        store.state.lock().unwrap().values.insert(
+            ("lin-kv".to_string(), "b".to_string()),
+            serde_json::json!(5),
+        );
+
+        let attempt = spawn_multi_cas(&network, vec![swap("a", 0, 1), swap("b", 0, 1)]);
+        while network.pending_requests().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        LinearStore::new("n2".to_string())
+            .write("a".to_string(), 7, &network)
+            .unwrap();
+        while !attempt.is_finished() {
+            clock.advance(Duration::from_millis(10));
+            tokio::task::yield_now().await;
+        }
+
+        let conflict = attempt.await.unwrap().unwrap().unwrap_err();
+        assert_eq!(conflict.key, "b");
+        assert_eq!(conflict.unrolled, ["a"]);
+        assert_eq!(store.value("lin-kv", "a"), Some(serde_json::json!(7)));
+    }
+
+    // "a" is swapped, then the CAS on "b" loses. A reader in between sees the
+    // aborted write to "a" until the rollback puts it back.
+    #[tokio::test]
+    async fn readers_see_an_aborted_multi_cas_until_it_rolls_back() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("cas", Duration::from_millis(10));
+        let network = network(&store, clock.clone());
+        overwrite(&store, "b", 5);
+        let reader = LinearStore::new("n2".to_string());
+
+        let attempt = spawn_multi_cas(&network, vec![swap("a", 0, 1), swap("b", 0, 1)]);
+        let casing_b = || {
+            network
+                .pending_requests()
+                .iter()
+                .any(|(_, pending)| pending.key.as_deref() == Some("b"))
+        };
+        while !casing_b() {
+            clock.advance(Duration::from_millis(10));
+            tokio::task::yield_now().await;
+        }
+        let dirty = reader.read::<u64>("a".to_string(), &network).await.unwrap();
+        assert_eq!(dirty, 1);
+
+        while !attempt.is_finished() {
+            clock.advance(Duration::from_millis(10));
+            tokio::task::yield_now().await;
+        }
+        let conflict = attempt.await.unwrap().unwrap().unwrap_err();
+        assert_eq!((conflict.key.as_str(), conflict.unrolled.len()), ("b", 0));
+        let rolled_back = reader.read::<u64>("a".to_string(), &network).await.unwrap();
+        assert_eq!(rolled_back, 0);
+    }
+
+    // Stands in for another node writing `key` behind the cache's back.
+    fn overwrite(store: &MockStore, key: &str, value: u64) {
+        store
+            .state
+            .lock()
+            .unwrap()
+            .values
+            .insert(("lin-kv".into(), key.into()), serde_json::json!(value));
+    }
+
+    // Pending replies are only settled by the dispatcher, so give it, and
+    // the task awaiting a write's acknowledgement, a chance to run.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_reads_see_this_nodes_writes() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let storage = CachedStore::new(LinearStore::new("n1".to_string()));
+        let read = || Storage::<()>::read::<u64>(&storage, "k".to_string(), &network);
+        overwrite(&store, "k", 1);
+
+        assert_eq!(read().await.unwrap(), 1);
+        // Cached, so a write by another node goes unseen.
+        overwrite(&store, "k", 9);
+        assert_eq!(read().await.unwrap(), 1);
+
+        storage.write("k".to_string(), 2, &network).unwrap();
+        settle().await;
+        assert_eq!(read().await.unwrap(), 2);
+        // What the acknowledged write stored is cached in turn.
+        overwrite(&store, "k", 9);
+        assert_eq!(read().await.unwrap(), 2);
+
+        storage
+            .compare_and_store("k".to_string(), 9, 3, &network)
+            .await
+            .unwrap();
+        assert_eq!(read().await.unwrap(), 3);
+    }
+
+    // The read is answered with the value from before the write, but only
+    // after the write went out, so it mustn't be cached.
+    #[tokio::test]
+    async fn read_racing_a_write_isnt_cached() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("read", Duration::from_secs(1));
+        let network = network(&store, clock.clone());
+        let storage = CachedStore::new(LinearStore::new("n1".to_string()));
+        overwrite(&store, "k", 1);
+
+        let read = tokio::spawn({
+            let (storage, network) = (storage.clone(), network.clone());
+            async move { Storage::<()>::read::<u64>(&storage, "k".to_string(), &network).await }
+        });
+        while network.pending_requests().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        storage.write("k".to_string(), 2, &network).unwrap();
+        settle().await;
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(read.await.unwrap().unwrap(), 1);
+
+        let reread = tokio::spawn({
+            let (storage, network) = (storage.clone(), network.clone());
+            async move { Storage::<()>::read::<u64>(&storage, "k".to_string(), &network).await }
+        });
+        settle().await;
+        assert!(reread.is_finished());
+        assert_eq!(reread.await.unwrap().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn read_cas_creates_then_updates() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let storage = LinearStore::new("n1".to_string());
+        let increment = |current: Option<u64>| current.unwrap_or_default() + 1;
+
+        for expected in [1, 2] {
+            let stored = storage
+                .read_cas("k".to_string(), increment, &network)
+                .await
+                .unwrap();
+            assert_eq!(stored, expected);
+        }
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(2)));
+    }
+
+    // Both increments read the key as missing and compute 1. The second to
+    // land must not match the first's 1, or an increment is lost.
+    #[tokio::test]
+    async fn racing_read_cas_on_a_missing_key_loses_no_update() {
+        let clock = Arc::new(MockClock::new());
+        let store = MockStore::new().with_latency("read", Duration::from_millis(10));
+        let network = network(&store, clock.clone())
+            .with_retry_policy(RetryPolicy::default().with_max_backoff(Duration::ZERO));
+
+        let increments: Vec<_> = (0..2)
+            .map(|_| {
+                let network = network.clone();
+                tokio::spawn(async move {
+                    let storage = LinearStore::new("n1".to_string());
+                    storage
+                        .read_cas(
+                            "k".to_string(),
+                            |current: Option<u64>| current.unwrap_or_default() + 1,
+                            &network,
+                        )
+                        .await
+                })
+            })
+            .collect();
+        while network.pending_requests().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        while !increments.iter().all(|increment| increment.is_finished()) {
+            clock.advance(Duration::from_millis(10));
+            tokio::task::yield_now().await;
+        }
+
+        let mut stored = Vec::new();
+        for increment in increments {
+            stored.push(increment.await.unwrap().unwrap());
+        }
+        stored.sort();
+        assert_eq!(stored, [1, 2]);
+        assert_eq!(store.value("lin-kv", "k"), Some(serde_json::json!(2)));
+    }
+
+    // Three nodes write version 0 at once: one wins, the others conflict and
+    // then read the winner. The key holds only the version record, so the CAS
+    // never compares the value.
+    #[tokio::test]
+    async fn concurrent_write_ifs_at_one_version_have_a_single_winner() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let stores: Vec<VersionedStore<LinearStore, Vec<u64>>> = ["n1", "n2", "n3"]
+            .map(|node| VersionedStore::new(LinearStore::new(node.to_string())))
+            .to_vec();
+
+        let writes = stores.iter().enumerate().map(|(i, versioned)| {
+            versioned.write_if("k".to_string(), 0, vec![i as u64; 1_000], &network)
+        });
+        let results = futures::future::join_all(writes).await;
+        let winners: Vec<usize> = results
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| result.is_ok())
+            .map(|(i, _)| i)
+            .collect();
+        let [winner] = winners[..] else {
+            panic!("expected one winner, got {:?}", results);
+        };
+        assert_eq!(results[winner].as_ref().unwrap(), &1);
+        for result in &results {
+            if let Err(e) = result {
+                assert!(matches!(e, Error::CasConflict(_)), "{:?}", e);
+            }
+        }
+
+        let record = store.value("lin-kv", "k").unwrap();
+        assert_eq!(record["version"], 1);
+        assert!(record.to_string().len() < 100, "{}", record);
+
+        for versioned in &stores {
+            let (value, version) = versioned.read("k".to_string(), &network).await.unwrap();
+            assert_eq!((value, version), (vec![winner as u64; 1_000], 1));
+        }
+        let loser = &stores[(winner + 1) % stores.len()];
+        assert!(matches!(
+            loser.write_if("k".to_string(), 0, vec![], &network).await,
+            Err(Error::CasConflict(_))
+        ));
+        assert_eq!(
+            loser
+                .write_if("k".to_string(), 1, vec![7], &network)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            stores[winner]
+                .read("k".to_string(), &network)
+                .await
+                .unwrap(),
+            (vec![7], 2)
+        );
+    }
+
+    // Two writes race for version 1, then the winner writes twice more. Every
+    // value key but the one the record points at ends up reclaimed, the
+    // loser's as soon as its CAS fails.
+    #[tokio::test]
+    async fn values_no_record_points_at_are_reclaimed() {
+        let store = MockStore::new();
+        let network = network(&store, Arc::new(MockClock::new()));
+        let stores: Vec<VersionedStore<LinearStore, Vec<u64>>> = ["n1", "n2"]
+            .map(|node| VersionedStore::new(LinearStore::new(node.to_string())))
+            .to_vec();
+
+        let writes = stores.iter().enumerate().map(|(i, versioned)| {
+            versioned.write_if("k".to_string(), 0, vec![i as u64; 100], &network)
+        });
+        let results = futures::future::join_all(writes).await;
+        let winner = &stores[results.iter().position(Result::is_ok).unwrap()];
+        for version in 1..3 {
+            winner
+                .write_if("k".to_string(), version, vec![version; 100], &network)
+                .await
+                .unwrap();
+        }
+
+        let value_keys: Vec<String> = store
+            .keys("lin-kv")
+            .into_iter()
+            .filter(|key| key.starts_with("k@"))
+            .collect();
+        assert_eq!(value_keys.len(), 4);
+        let live: Vec<&String> = value_keys
+            .iter()
+            .filter(|key| store.value("lin-kv", key) != Some(serde_json::json!("reclaimed")))
+            .collect();
+        let record = store.value("lin-kv", "k").unwrap();
+        assert_eq!(live, [record["value_key"].as_str().unwrap()]);
+        for versioned in &stores {
+            assert_eq!(
+                versioned.read("k".to_string(), &network).await.unwrap(),
+                (vec![2; 100], 3)
+            );
+        }
+    }
+}
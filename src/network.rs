@@ -1,173 +1,2343 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::Debug,
-    io::BufRead,
-    sync::{Arc, Mutex, RwLock},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use serde::{de::DeserializeOwned, Serialize};
 use std::thread::JoinHandle;
+use tokio::sync::mpsc::error::SendError;
 
-use crate::{protocol::UntypedMessage, Event, Message, NetworkEvent};
+use crate::{
+    clock::{self, Clock, RealClock},
+    codec::{Codec, WireFormat},
+    error::Error,
+    limiter::{Priority, RequestLimiter},
+    lru::LruCache,
+    protocol::{ErrorCode, Init, MaelstromError, UntypedMessage},
+    report::{Metrics, NodeReport},
+    retry::{Retry, RetryPolicy},
+    service::{SequentialStore, Storage, STORAGE_ADDRESSES},
+    transport::Transport,
+    Event, Message, NetworkEvent,
+};
+
+const MAX_READ_RETRIES: usize = 5;
+const READ_RETRY_DELAY: Duration = Duration::from_millis(50);
+// Scoped ids carry their scope's tag above this bit and the shared counter
+// below it.
+const SCOPE_SHIFT: u32 = 40;
+// The shared counter's bits of an id, scoped or not.
+const ID_COUNTER_MASK: usize = (1 << SCOPE_SHIFT) - 1;
+// Tags stay below this so a scoped id still fits in an i64, which is all some
+// Maelstrom clients accept.
+const MAX_SCOPE_TAG: usize = (i64::MAX as usize) >> SCOPE_SHIFT;
+// Ids are usize, and the tag needs the bits above `SCOPE_SHIFT`.
+const _: () = assert!(usize::BITS == 64, "scoped message ids need a 64-bit usize");
+// How far past the current message id each checkpoint reserves.
+const ID_CHECKPOINT_HEADROOM: usize = 10_000;
+
+fn is_transient(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendErrorAction {
+    Propagate,
+    Swallow,
+}
+
+type SendErrorFn = dyn Fn(&str, &anyhow::Error) -> SendErrorAction + Send + Sync;
+
+#[derive(Clone, Default)]
+struct SendErrorHook {
+    hook: Arc<RwLock<Option<Arc<SendErrorFn>>>>,
+}
+
+impl Debug for SendErrorHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendErrorHook").finish_non_exhaustive()
+    }
+}
+
+impl SendErrorHook {
+    fn set<F>(&self, hook: F)
+    where
+        F: Fn(&str, &anyhow::Error) -> SendErrorAction + Send + Sync + 'static,
+    {
+        *self.hook.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    fn handle(&self, dst: &str, error: &anyhow::Error) -> SendErrorAction {
+        let hook = self.hook.read().unwrap().clone();
+        match hook {
+            Some(hook) => hook(dst, error),
+            None => SendErrorAction::Propagate,
+        }
+    }
+}
+
+// Sees every message on its way in or out and returns what should continue in
+// its place, or `None` to drop it.
+pub type MessageFilter = Arc<dyn Fn(UntypedMessage) -> Option<UntypedMessage> + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct MessageFilters {
+    inbound: Vec<MessageFilter>,
+    outbound: Vec<MessageFilter>,
+}
+
+impl Debug for MessageFilters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageFilters")
+            .field("inbound", &self.inbound.len())
+            .field("outbound", &self.outbound.len())
+            .finish()
+    }
+}
+
+// Runs `message` through each filter in the order they were added, stopping
+// at the first that drops it.
+fn apply_filters(filters: &[MessageFilter], message: UntypedMessage) -> Option<UntypedMessage> {
+    filters
+        .iter()
+        .try_fold(message, |message, filter| filter(message))
+}
+
+fn read_init_line(transport: &Transport) -> Result<String, Error> {
+    Ok(transport
+        .read_line()
+        .map_err(Error::Transport)
+        .context("failed to read init message")?
+        .context("input closed before init message")?)
+}
+
+fn read_input<IP>(
+    transport: &Transport,
+    format: WireFormat,
+    tx: &EventSender<IP>,
+) -> anyhow::Result<()> {
+    let mut failures = 0;
+    loop {
+        let input = match transport.read_line() {
+            Ok(Some(input)) => input,
+            Ok(None) => return Ok(()),
+            Err(e) if is_transient(&e) && failures < MAX_READ_RETRIES => {
+                failures += 1;
+                eprintln!("retrying read after transient error: {}", e);
+                std::thread::sleep(READ_RETRY_DELAY);
+                continue;
+            }
+            Err(e) => {
+                return Err(Error::Transport(e))
+                    .context("Maelstrom event could not be read from input")
+            }
+        };
+        failures = 0;
+
+        dbg!("RECEIVED {}", input.clone());
+        let messages = match format
+            .decode(input.as_str())
+            .map_err(Error::deserialize)
+            .context("failed to deserialize maelstrom input")?
+        {
+            Frame::One(message) => vec![message],
+            Frame::Many(messages) => messages,
+        };
+        for message in messages {
+            if tx.send_input(NetworkEvent::Message(message)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// One line of input: a single message, or several sent by a peer batching
+// its output.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Frame {
+    One(UntypedMessage),
+    Many(Vec<UntypedMessage>),
+}
+
+type OrphanReplyFn = dyn Fn(RequestDescriptor, UntypedMessage) + Send + Sync;
+
+// Requests re-registered by `reregister_pending`, whose original awaiters
+// belonged to an earlier process, and the hook their replies go to.
+#[derive(Clone, Default)]
+struct Orphans {
+    descriptors: Arc<RwLock<HashMap<usize, RequestDescriptor>>>,
+    hook: Arc<RwLock<Option<Arc<OrphanReplyFn>>>>,
+}
+
+impl Debug for Orphans {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Orphans")
+            .field("descriptors", &self.descriptors.read().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+type DebugDumpFn = dyn Fn() -> serde_json::Value + Send + Sync;
+
+#[derive(Clone, Default)]
+struct DebugDumpHook {
+    hook: Arc<RwLock<Option<Arc<DebugDumpFn>>>>,
+}
+
+impl Debug for DebugDumpHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DebugDumpHook").finish_non_exhaustive()
+    }
+}
+
+impl DebugDumpHook {
+    fn dump(&self) -> serde_json::Value {
+        let hook = self.hook.read().unwrap().clone();
+        hook.map(|hook| hook()).unwrap_or_default()
+    }
+}
+
+// Handed the network answering, so a hook never has to hold a clone of it,
+// which would keep the network alive through its own hook.
+type MetricsFn<IP> = dyn Fn(&Network<IP>) -> Metrics + Send + Sync;
+
+#[derive(Clone)]
+struct MetricsHook<IP> {
+    hook: Arc<RwLock<Option<Arc<MetricsFn<IP>>>>>,
+}
+
+impl<IP> Default for MetricsHook<IP> {
+    fn default() -> Self {
+        Self {
+            hook: Arc::new(RwLock::new(None)),
+        }
+    }
+}
+
+impl<IP> Debug for MetricsHook<IP> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricsHook").finish_non_exhaustive()
+    }
+}
+
+type DeliveredSet = LruCache<(String, usize), ()>;
+
+pub type EventId = u64;
+
+// What the server takes off the queue: an event for the node, or a change of
+// cluster membership the network has already applied.
+pub(crate) enum Received<P, IP> {
+    Event(Option<EventId>, Event<P, IP>),
+    Reconfigured(Vec<String>),
+}
+
+// Events handed out by `recv_logged` whose processing hasn't been acked yet.
+#[derive(Debug)]
+struct EventLog<IP> {
+    next_id: EventId,
+    pending: BTreeMap<EventId, NetworkEvent<IP>>,
+}
+
+impl<IP> Default for EventLog<IP> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestDescriptor {
+    pub kind: String,
+    pub key: Option<String>,
+    pub sent_at: Instant,
+}
+
+impl RequestDescriptor {
+    fn from_payload<PAYLOAD: Serialize>(payload: &PAYLOAD, sent_at: Instant) -> Self {
+        let value = serde_json::to_value(payload).unwrap_or_default();
+        let field = |name: &str| match value.get(name) {
+            Some(serde_json::Value::String(s)) => Some(s.clone()),
+            Some(other) => Some(other.to_string()),
+            None => None,
+        };
+
+        Self {
+            kind: field("type").unwrap_or_else(|| "unknown".to_string()),
+            key: field("key"),
+            sent_at,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingRequest {
+    responder: tokio::sync::oneshot::Sender<(UntypedMessage, Duration)>,
+    descriptor: RequestDescriptor,
+}
+
+// Deregisters a request once its requester stops waiting, so one abandoned
+// on a timeout doesn't stay pending forever.
+struct Registration<'a> {
+    awaiting_responses: &'a RwLock<HashMap<usize, PendingRequest>>,
+    id: usize,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut awaiting_responses) = self.awaiting_responses.write() {
+            awaiting_responses.remove(&self.id);
+        }
+    }
+}
+
+// A reply together with how long the request waited for it, measured from
+// when it was sent to when the reply was delivered.
+#[derive(Debug, Clone)]
+pub struct Response<P> {
+    pub message: Message<P>,
+    pub elapsed: Duration,
+}
+
+// The serve loop only dispatches events and never waits on a node's `step`,
+// so a full bounded queue stalls the reader thread (and with it stdin) but
+// can't deadlock responses queued behind requests: the loop keeps draining.
+//
+// Only the read thread ever waits on a full queue. Everything else (timers,
+// injections, the mock store) goes on an unbounded queue of its own, so
+// `send` never blocks or awaits and is safe from any thread or runtime.
+#[derive(Debug)]
+pub struct EventSender<IP> {
+    events: tokio::sync::mpsc::UnboundedSender<NetworkEvent<IP>>,
+    // Set by `with_event_buffer`; input shares `events` without it.
+    input: Option<tokio::sync::mpsc::Sender<NetworkEvent<IP>>>,
+    ready: Arc<tokio::sync::Notify>,
+}
+
+impl<IP> Clone for EventSender<IP> {
+    fn clone(&self) -> Self {
+        Self {
+            events: self.events.clone(),
+            input: self.input.clone(),
+            ready: self.ready.clone(),
+        }
+    }
+}
+
+impl<IP> EventSender<IP> {
+    pub fn send(&self, event: NetworkEvent<IP>) -> Result<(), SendError<NetworkEvent<IP>>> {
+        self.events.send(event)?;
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    // Blocks while a bounded input queue is full, so it must only be called
+    // off the runtime, from the read thread.
+    fn send_input(&self, event: NetworkEvent<IP>) -> Result<(), SendError<NetworkEvent<IP>>> {
+        match &self.input {
+            Some(input) => input.blocking_send(event)?,
+            None => self.events.send(event)?,
+        }
+        self.ready.notify_one();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct EventReceiver<IP> {
+    events: tokio::sync::mpsc::UnboundedReceiver<NetworkEvent<IP>>,
+    input: Option<tokio::sync::mpsc::Receiver<NetworkEvent<IP>>>,
+}
+
+impl<IP> EventReceiver<IP> {
+    fn try_recv(&mut self) -> Option<NetworkEvent<IP>> {
+        if let Ok(event) = self.events.try_recv() {
+            return Some(event);
+        }
+        self.input.as_mut()?.try_recv().ok()
+    }
+}
+
+// Messages held by `with_reply_batching` until the next write, and why the
+// last write the flusher tried failed, for the next send to report.
+#[derive(Debug, Default)]
+struct Batch {
+    messages: Vec<UntypedMessage>,
+    failed: Option<anyhow::Error>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Network<IP = ()> {
-    pub tx: std::sync::mpsc::Sender<NetworkEvent<IP>>,
-    rx: Arc<Mutex<std::sync::mpsc::Receiver<NetworkEvent<IP>>>>,
-    awaiting_responses: Arc<RwLock<HashMap<usize, tokio::sync::oneshot::Sender<UntypedMessage>>>>,
+    pub tx: EventSender<IP>,
+    // Only locked to take an event that's already queued, never across an
+    // await, so any number of tasks can wait in `recv` at once.
+    rx: Arc<Mutex<EventReceiver<IP>>>,
+    awaiting_responses: Arc<RwLock<HashMap<usize, PendingRequest>>>,
+    orphans: Orphans,
     message_id: Arc<RwLock<usize>>,
-    stdout_lock: Arc<Mutex<()>>,
-    stdin_lock: Arc<Mutex<()>>,
+    next_scope: Arc<RwLock<usize>>,
+    scope: Option<usize>,
+    id_checkpoint: Option<Duration>,
+    requests_sent: Arc<RwLock<usize>>,
+    sent_by_type: Arc<RwLock<HashMap<String, usize>>>,
+    request_timeouts: Arc<RwLock<usize>>,
+    closed: Arc<RwLock<bool>>,
+    delivered: Option<Arc<Mutex<DeliveredSet>>>,
+    tracing: bool,
+    auto_health: bool,
+    debug_endpoint: bool,
+    debug_dump: DebugDumpHook,
+    metrics_endpoint: bool,
+    metrics: MetricsHook<IP>,
+    limiter: Option<Arc<RequestLimiter>>,
+    retry_policy: Arc<RetryPolicy>,
+    send_error_hook: SendErrorHook,
+    // Shared by every clone, including ones taken before a filter was added,
+    // so a filter that holds a message back can send it on through the
+    // filters added after it.
+    filters: Arc<RwLock<MessageFilters>>,
+    batch: Option<(Duration, Arc<Mutex<Batch>>)>,
+    handled_types: &'static [&'static str],
+    keepalive: Option<Duration>,
+    last_send: Arc<RwLock<Instant>>,
+    last_heard: Arc<RwLock<HashMap<String, Instant>>>,
+    format: WireFormat,
+    event_log: Option<Arc<Mutex<EventLog<IP>>>>,
+    clock: Arc<dyn Clock>,
+    init: Arc<OnceLock<Init>>,
+    members: Arc<RwLock<Vec<String>>>,
+    transport: Transport,
 }
 
 impl<IP> Default for Network<IP> {
     fn default() -> Self {
-        let (tx, rx) = std::sync::mpsc::channel();
+        Self::with_transport(Transport::stdio())
+    }
+}
+
+impl<IP> Network<IP> {
+    pub fn with_transport(transport: Transport) -> Self {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
         Self {
-            tx,
-            rx: Arc::new(Mutex::new(rx)),
+            tx: EventSender {
+                events: tx,
+                input: None,
+                ready: Arc::new(tokio::sync::Notify::new()),
+            },
+            rx: Arc::new(Mutex::new(EventReceiver {
+                events: rx,
+                input: None,
+            })),
             awaiting_responses: Arc::new(RwLock::new(HashMap::new())),
+            orphans: Orphans::default(),
             message_id: Arc::new(RwLock::new(0)),
-            stdout_lock: Arc::new(Mutex::new(())),
-            stdin_lock: Arc::new(Mutex::new(())),
+            next_scope: Arc::new(RwLock::new(1)),
+            scope: None,
+            id_checkpoint: None,
+            requests_sent: Arc::new(RwLock::new(0)),
+            sent_by_type: Arc::new(RwLock::new(HashMap::new())),
+            request_timeouts: Arc::new(RwLock::new(0)),
+            closed: Arc::new(RwLock::new(false)),
+            delivered: None,
+            tracing: false,
+            auto_health: true,
+            debug_endpoint: false,
+            debug_dump: DebugDumpHook::default(),
+            metrics_endpoint: false,
+            metrics: MetricsHook::default(),
+            limiter: None,
+            retry_policy: Arc::new(RetryPolicy::default()),
+            send_error_hook: SendErrorHook::default(),
+            filters: Arc::default(),
+            batch: None,
+            handled_types: &[],
+            keepalive: None,
+            last_send: Arc::new(RwLock::new(Instant::now())),
+            last_heard: Arc::new(RwLock::new(HashMap::new())),
+            format: WireFormat::default(),
+            event_log: None,
+            clock: Arc::new(RealClock),
+            init: Arc::new(OnceLock::new()),
+            members: Arc::new(RwLock::new(Vec::new())),
+            transport,
         }
     }
-}
 
-impl<IP> Network<IP>
-where
-    IP: Send + Clone + Debug + 'static,
-{
-    pub fn new() -> Self {
-        Self::default()
+    // Remembers the last `window` delivered `(src, msg_id)` pairs and drops
+    // exact redeliveries of them before they reach the node.
+    pub fn with_dedup_window(mut self, window: usize) -> Self {
+        self.delivered = Some(Arc::new(Mutex::new(LruCache::new(window))));
+        self
+    }
+
+    // Logs every completed RPC to stderr alongside the request that opened it.
+    pub fn with_tracing(mut self, tracing: bool) -> Self {
+        self.tracing = tracing;
+        self
+    }
+
+    // Answers `health` probes with `health_ok` without involving the node.
+    pub fn with_auto_health(mut self, auto_health: bool) -> Self {
+        self.auto_health = auto_health;
+        self
     }
 
-    pub fn read<PAYLOAD>(&mut self) -> anyhow::Result<Message<PAYLOAD>>
+    // Answers `debug_dump` with whatever the hook set by `on_debug_dump`
+    // returns, or null without one.
+    pub fn with_debug_endpoint(mut self, debug_endpoint: bool) -> Self {
+        self.debug_endpoint = debug_endpoint;
+        self
+    }
+
+    pub fn on_debug_dump<F>(&self, hook: F)
     where
-        PAYLOAD: DeserializeOwned,
+        F: Fn() -> serde_json::Value + Send + Sync + 'static,
+    {
+        *self.debug_dump.hook.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    // Answers `metrics` requests with `metrics_ok` before the node sees them.
+    // Off by default, since a node's own `metrics` messages would never reach
+    // it.
+    pub fn with_metrics_endpoint(mut self, metrics_endpoint: bool) -> Self {
+        self.metrics_endpoint = metrics_endpoint;
+        self
+    }
+
+    // What the metrics endpoint replies with; the network's own `metrics`
+    // without a hook.
+    pub fn on_metrics<F>(&self, hook: F)
+    where
+        F: Fn(&Network<IP>) -> Metrics + Send + Sync + 'static,
     {
-        let _lock = self.stdin_lock.lock().unwrap();
+        *self.metrics.hook.write().unwrap() = Some(Arc::new(hook));
+    }
 
-        let stdin = std::io::stdin().lock();
-        let mut stdin = stdin.lines();
+    pub fn set_handled_types(&mut self, handled_types: &'static [&'static str]) {
+        self.handled_types = handled_types;
+    }
 
-        let line = stdin
-            .next()
-            .expect("could not read from stdin")
-            .context("failed to read init message from stdin")?;
+    // A request holds its slot until the reply arrives, including requests
+    // forwarded to peers that may themselves be waiting on this node, so the
+    // cap has to leave room for that fan-in.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.limiter = Some(Arc::new(RequestLimiter::new(max_in_flight)));
+        self
+    }
 
-        let message: UntypedMessage =
-            serde_json::from_str(&line).context("failed to deserialize message")?;
+    // Injects a reserved keepalive tick whenever nothing has been sent for
+    // `interval`. Nodes never see the tick; it only resets the activity timer.
+    pub fn with_keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
 
-        Ok(message.into())
+    // How long it has been since the last outbound message or keepalive tick.
+    pub fn idle_for(&self) -> Duration {
+        self.clock.elapsed(*self.last_send.read().unwrap())
     }
 
-    pub fn start_read_thread(&self) -> JoinHandle<anyhow::Result<()>> {
-        let tx = self.tx.clone();
-        std::thread::spawn(move || {
-            let stdin = std::io::stdin().lock();
-            for input in stdin.lines() {
-                let input = input.context("Maelstrom event could not be read from stdin")?;
-                dbg!("RECEIVED {}", input.clone());
-                let message: UntypedMessage = serde_json::from_str(input.as_str())
-                    .context("failed to deserialize maelstrom input")?;
-                if tx.send(NetworkEvent::Message(message)).is_err() {
-                    return Ok::<_, anyhow::Error>(());
-                }
-            }
-            Ok(())
-        })
+    fn touch(&self) {
+        *self.last_send.write().unwrap() = self.clock.now();
     }
 
-    pub async fn recv<PAYLOAD>(&mut self) -> Option<Event<PAYLOAD, IP>>
+    // Starts the silence clock for peers we haven't heard from yet, so a peer
+    // that is down from the start is still reported.
+    pub fn track_peers<I>(&self, peers: I)
     where
-        PAYLOAD: DeserializeOwned,
+        I: IntoIterator<Item = String>,
     {
-        let receiver = self.rx.lock().unwrap();
+        let now = self.clock.now();
+        let mut last_heard = self.last_heard.write().unwrap();
+        for peer in peers {
+            last_heard.entry(peer).or_insert(now);
+        }
+    }
 
-        loop {
-            let result = receiver.recv();
-            let Ok(event) = result else { return None };
+    // Peers nothing has been received from for longer than `threshold`. Only
+    // inbound traffic counts: writing to a dead peer still succeeds, so sends
+    // say nothing about whether it is up.
+    pub fn suspected_down(&self, threshold: Duration) -> Vec<String> {
+        let mut suspected: Vec<_> = self
+            .last_heard
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, heard)| self.clock.elapsed(**heard) > threshold)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        suspected.sort();
+        suspected
+    }
 
-            if let Some(tx) = self.is_response(&event) {
-                let NetworkEvent::Message(message) = event else {
-                    panic!("response message is not a message!")
-                };
+    // Clients and services aren't tracked, only the peers registered above.
+    fn heard_from(&self, peer: &str) {
+        if let Some(heard) = self.last_heard.write().unwrap().get_mut(peer) {
+            *heard = self.clock.now();
+        }
+    }
+
+    // Keeps every event handed to the node until `ack`, so it can be
+    // re-dispatched with `redispatch_pending`.
+    pub fn with_event_log(mut self) -> Self {
+        self.event_log = Some(Arc::new(Mutex::new(EventLog::default())));
+        self
+    }
+
+    // Applied to every message read, responses included, before anything
+    // else looks at it.
+    pub fn with_inbound_filter(self, filter: MessageFilter) -> Self {
+        self.filters.write().unwrap().inbound.push(filter);
+        self
+    }
+
+    // Applied to every message just before it's written. A dropped message
+    // still counts as sent to the caller, like one lost on the wire.
+    pub fn with_outbound_filter(self, filter: MessageFilter) -> Self {
+        self.filters.write().unwrap().outbound.push(filter);
+        self
+    }
+
+    // Holds outbound messages for up to `window` and writes whatever piled up
+    // for each destination as one line: a JSON array when there's more than
+    // one. Needs `start_batch_flusher`; `flush` writes the batch out early.
+    // A write the flusher fails is reported by the next `send`.
+    pub fn with_reply_batching(mut self, window: Duration) -> Self {
+        self.batch = Some((window, Arc::new(Mutex::new(Batch::default()))));
+        self
+    }
+
+    // Seeds the message id counter from `seq-kv` in `persist_message_ids` and
+    // checkpoints it there every `interval`, so a restarted node doesn't reuse
+    // ids its previous life still has requests out under. Each checkpoint
+    // reserves `ID_CHECKPOINT_HEADROOM` ids ahead, so ids stay unique as long
+    // as fewer than that are sent between checkpoints. The cost is one storage
+    // read before the counter is seeded plus a write every `interval`, and
+    // seq-kv may hand a restarted node a stale checkpoint, so this narrows the
+    // window for reuse rather than closing it.
+    pub fn with_id_checkpoint(mut self, interval: Duration) -> Self {
+        self.id_checkpoint = Some(interval);
+        self
+    }
 
-                tx.send(message)
-                    .unwrap_or_else(|_| panic!("failed to send event"));
-            } else {
-                return Some(event.into());
+    // Timers, request latencies and peer liveness all read this clock, so a
+    // `MockClock` makes them step only when advanced.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        *self.last_send.write().unwrap() = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    // Runs `f` once `delay` has passed on the network's clock: as a task
+    // when called on a runtime, so waiting ties up no thread, and on a thread
+    // of its own otherwise.
+    pub(crate) fn run_after<F>(&self, delay: Duration, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let clock = self.clock.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                // Counted from now, not from whenever the task first runs.
+                let deadline = clock.now() + delay;
+                runtime.spawn(async move {
+                    clock
+                        .delay(deadline.saturating_duration_since(clock.now()))
+                        .await;
+                    f();
+                });
+            }
+            Err(_) => {
+                std::thread::spawn(move || {
+                    clock.sleep(delay);
+                    f();
+                });
             }
         }
     }
 
-    fn is_response(
-        &self,
-        event: &NetworkEvent<IP>,
-    ) -> Option<tokio::sync::oneshot::Sender<UntypedMessage>> {
-        if let NetworkEvent::Message(message) = event {
-            if let Some(replying_to) = message.body.in_reply_to {
-                let request = self
-                    .awaiting_responses
-                    .write()
-                    .unwrap()
-                    .remove_entry(&replying_to);
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Arc::new(policy);
+        self
+    }
 
-                if let Some(r) = request {
-                    dbg!("RESPONDING TO REQUEST", r.0);
-                    return Some(r.1);
-                }
+    // The policy `request_retry` and the storage retry loops follow.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    // The cluster as of the init message. Panics if called before init.
+    pub fn init(&self) -> &Init {
+        self.init.get().expect("init message not received yet")
+    }
+
+    // The cluster as of the last `reconfigure`, or the init message before
+    // any arrives.
+    pub fn node_ids(&self) -> Vec<String> {
+        self.members.read().unwrap().clone()
+    }
+
+    // Whether `dst` is this node. Messages to ourselves still go through
+    // Maelstrom, so callers can use this to handle them in place instead.
+    pub fn is_self(&self, dst: &str) -> bool {
+        self.init.get().is_some_and(|init| init.node_id == dst)
+    }
+
+    // How many messages have been written so far, keyed by their `type`.
+    pub fn sent_by_type(&self) -> HashMap<String, usize> {
+        self.sent_by_type.read().unwrap().clone()
+    }
+
+    // The network's share of a `metrics` reply.
+    pub fn metrics(&self) -> Metrics {
+        let report = self.report();
+        Metrics {
+            node_id: report.node_id,
+            requests: report.requests,
+            timeouts: report.timeouts,
+            sent_by_type: self.sent_by_type().into_iter().collect(),
+            ..Metrics::default()
+        }
+    }
+
+    // The network's share of the shutdown report.
+    pub fn report(&self) -> NodeReport {
+        let node_id = self
+            .init
+            .get()
+            .map(|init| init.node_id.clone())
+            .unwrap_or_default();
+        let mut report = NodeReport::new(node_id);
+        report.requests = *self.requests_sent.read().unwrap();
+        report.timeouts = *self.request_timeouts.read().unwrap();
+        report.record("sent_by_type", self.sent_by_type());
+        report
+    }
+
+    pub fn set_init(&self, init: Init) -> Result<(), Error> {
+        *self.members.write().unwrap() = init.node_ids.clone();
+        self.init
+            .set(init)
+            .map_err(|_| Error::protocol("init message already received"))
+    }
+
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        self.write_batch()?;
+        self.transport.flush().map_err(Error::Transport)
+    }
+
+    fn write_batch(&self) -> anyhow::Result<()> {
+        let Some((_, batch)) = &self.batch else {
+            return Ok(());
+        };
+        // Held across the write so batches can't be written out of order.
+        let mut batch = batch.lock().unwrap();
+        let mut lines: Vec<(String, Vec<UntypedMessage>)> = Vec::new();
+        for message in batch.messages.drain(..) {
+            match lines.iter_mut().find(|(dst, _)| *dst == message.dst) {
+                Some((_, to_dst)) => to_dst.push(message),
+                None => lines.push((message.dst.clone(), vec![message])),
             }
         }
 
-        None
+        // Every destination gets its line even if an earlier one failed.
+        let mut failed = None;
+        for (dst, messages) in lines {
+            let Some(output) = self.encode_batch_line(&dst, messages) else {
+                continue;
+            };
+            dbg!("SENDING BATCH {:?}", &output);
+            if let Err(e) = self.transport.write_line(&output) {
+                failed.get_or_insert(
+                    anyhow::Error::new(Error::Transport(e))
+                        .context(format!("writing batch to {}", dst)),
+                );
+            }
+        }
+        failed.map_or(Ok(()), Err)
     }
 
-    pub fn inject(&self, payload: IP) -> anyhow::Result<()> {
-        self.tx
-            .send(NetworkEvent::Injected(payload))
-            .expect("injecting message into network");
-        Ok(())
+    // One destination's line of a batch. A message that won't serialize is
+    // logged and left out rather than taking the rest of the line with it, so
+    // this is `None` only when nothing on the line could be sent.
+    fn encode_batch_line(&self, dst: &str, mut messages: Vec<UntypedMessage>) -> Option<String> {
+        let encode = |messages: &[UntypedMessage]| match messages {
+            [] => None,
+            [message] => Some(self.format.encode(message)),
+            _ => Some(self.format.encode(&messages)),
+        };
+        if let Ok(output) = encode(&messages)? {
+            return Some(output);
+        }
+        messages.retain(|message| match self.format.encode(message) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("dropping batched message to {}: {:#}", dst, e);
+                false
+            }
+        });
+        match encode(&messages)? {
+            Ok(output) => Some(output),
+            Err(e) => {
+                eprintln!("dropping batch to {}: {:#}", dst, e);
+                None
+            }
+        }
     }
 
-    pub fn send<PAYLOAD>(&self, mut message: Message<PAYLOAD>) -> anyhow::Result<usize>
+    // `write_batch` for the flusher, which has no caller to report a failure
+    // to, so the next send reports it instead.
+    fn flush_batch(&self) {
+        if let Err(e) = self.write_batch() {
+            eprintln!("failed to write batch: {:#}", e);
+            if let Some((_, batch)) = &self.batch {
+                batch.lock().unwrap().failed = Some(e);
+            }
+        }
+    }
+
+    // Bounds the queue of messages read but not yet received to `capacity`,
+    // which must be at least 1.
+    pub fn with_event_buffer(mut self, capacity: usize) -> Self {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+        self.tx.input = Some(tx);
+        self.rx.lock().unwrap().input = Some(rx);
+        self
+    }
+}
+
+impl<IP> Network<IP>
+where
+    IP: Send + Clone + Debug + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read<PAYLOAD>(&mut self) -> Result<Message<PAYLOAD>, Error>
     where
-        PAYLOAD: Serialize + Clone + Debug,
+        PAYLOAD: DeserializeOwned,
     {
-        let id = self.next_message_id();
-        message.body.id = Some(id);
-        dbg!(
-            "SENDING {:?}",
-            serde_json::to_string(&message).expect("serializing message failed")
-        );
-        let _lock = self.stdout_lock.lock().unwrap();
-        let output = serde_json::to_string(&message).context("serializing message")?;
-        println!("{}", output);
-        Ok(id)
+        let line = read_init_line(&self.transport)?;
+        self.decode_init(&line)
     }
 
-    pub async fn request<PAYLOAD>(
+    // Reads the init message's line on a thread of its own, so waiting for it
+    // can be given up. The thread holds only the transport, and ends as soon
+    // as input yields a line or closes.
+    pub(crate) fn spawn_init_reader(
         &self,
-        message: Message<PAYLOAD>,
-    ) -> anyhow::Result<Message<PAYLOAD>>
+    ) -> tokio::sync::oneshot::Receiver<Result<String, Error>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let transport = self.transport.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(read_init_line(&transport));
+        });
+        rx
+    }
+
+    pub(crate) fn decode_init<PAYLOAD>(&self, line: &str) -> Result<Message<PAYLOAD>, Error>
     where
-        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+        PAYLOAD: DeserializeOwned,
     {
-        let id = self.send(message).context("sending message in request")?;
+        let message: UntypedMessage = self
+            .format
+            .decode(line)
+            .map_err(Error::deserialize)
+            .context("failed to deserialize message")?;
 
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.awaiting_responses.write().unwrap().insert(id, tx);
-
-        let response = rx.await.context("failed to receive response")?;
-        Ok(response.into())
+        Ok(message.into())
     }
 
-    fn next_message_id(&self) -> usize {
+    // Queues every message read until input runs out, then marks the end of
+    // input so `recv` returns `None`.
+    pub fn start_read_thread(&self) -> JoinHandle<Result<(), Error>> {
+        let tx = self.tx.clone();
+        let transport = self.transport.clone();
+        let format = self.format;
+        std::thread::spawn(move || {
+            let read = read_input(&transport, format, &tx);
+            // The receiving side outlives the thread, so this can't fail.
+            let _ = tx.send_input(NetworkEvent::InputClosed);
+            read.map_err(Error::from)
+        })
+    }
+
+    // `None` once input is exhausted. Events injected or still queued after
+    // that are returned by later calls.
+    pub async fn recv<PAYLOAD>(&self) -> Option<Event<PAYLOAD, IP>>
+    where
+        PAYLOAD: DeserializeOwned,
+    {
+        self.recv_logged().await.map(|(id, event)| {
+            if let Some(id) = id {
+                self.ack(id);
+            }
+            event
+        })
+    }
+
+    // Like `recv`, but with an event log configured the event stays logged
+    // under the returned id until it is acked.
+    pub async fn recv_logged<PAYLOAD>(&self) -> Option<(Option<EventId>, Event<PAYLOAD, IP>)>
+    where
+        PAYLOAD: DeserializeOwned,
+    {
+        loop {
+            if let Received::Event(id, event) = self.recv_received().await? {
+                return Some((id, event));
+            }
+        }
+    }
+
+    // Like `recv_logged`, but also says when a `reconfigure` changed the
+    // membership, so the server can tell the node.
+    pub(crate) async fn recv_received<PAYLOAD>(&self) -> Option<Received<PAYLOAD, IP>>
+    where
+        PAYLOAD: DeserializeOwned,
+    {
+        loop {
+            let event = match self.next_event().await {
+                NetworkEvent::InputClosed => return None,
+                NetworkEvent::Message(message) => {
+                    match apply_filters(&self.filters.read().unwrap().inbound, message) {
+                        Some(message) => NetworkEvent::Message(message),
+                        None => continue,
+                    }
+                }
+                event => event,
+            };
+            if let NetworkEvent::Message(message) = &event {
+                self.heard_from(&message.src);
+            }
+
+            if let Some(pending) = self.is_response(&event) {
+                let NetworkEvent::Message(message) = event else {
+                    panic!("response message is not a message!")
+                };
+
+                // The requester may have given up waiting, e.g. after a timeout.
+                let elapsed = self.clock.elapsed(pending.descriptor.sent_at);
+                if pending.responder.send((message, elapsed)).is_err() {
+                    dbg!("DROPPING ABANDONED RESPONSE");
+                }
+            } else if matches!(&event, NetworkEvent::Message(message) if self.settle_orphan(message))
+            {
+                dbg!("SETTLED ORPHANED REPLY");
+            } else if let NetworkEvent::Keepalive = event {
+                dbg!("KEEPALIVE", self.idle_for());
+                self.touch();
+            } else if self.is_duplicate(&event) {
+                dbg!("DROPPING DUPLICATE", &event);
+            } else if let Some(node_ids) = self.reconfigure(&event) {
+                return Some(Received::Reconfigured(node_ids));
+            } else if !self.intercept(&event) {
+                let logged = self.event_log.as_ref().map(|_| event.clone());
+                match Event::try_from(event) {
+                    Ok(event) => {
+                        return Some(Received::Event(
+                            logged.map(|event| self.log_event(event)),
+                            event,
+                        ))
+                    }
+                    Err(e) => eprintln!("dropping undeliverable message: {:#}", e),
+                }
+            }
+        }
+    }
+
+    // Cancellation safe: an event is only taken off the queue once it's
+    // returned, so a dropped call never loses one.
+    async fn next_event(&self) -> NetworkEvent<IP> {
+        loop {
+            // Registered before looking, so a send landing in between still
+            // wakes this waiter.
+            let ready = self.tx.ready.notified();
+            tokio::pin!(ready);
+            ready.as_mut().enable();
+            if let Some(event) = self.rx.lock().unwrap().try_recv() {
+                return event;
+            }
+            ready.await;
+        }
+    }
+
+    fn log_event(&self, event: NetworkEvent<IP>) -> EventId {
+        let mut log = self
+            .event_log
+            .as_ref()
+            .expect("logging without an event log")
+            .lock()
+            .unwrap();
+        let id = log.next_id;
+        log.next_id += 1;
+        log.pending.insert(id, event);
+        id
+    }
+
+    // Marks a logged event as fully processed.
+    pub fn ack(&self, id: EventId) {
+        if let Some(log) = &self.event_log {
+            log.lock().unwrap().pending.remove(&id);
+        }
+    }
+
+    // Puts every logged but unacked event back on the queue, oldest first, so
+    // it is dispatched again, e.g. after the transport reconnects. They are
+    // forgotten by the dedup window first so they aren't dropped as
+    // redeliveries.
+    pub fn redispatch_pending(&self) -> Result<usize, Error> {
+        let Some(log) = &self.event_log else {
+            return Ok(0);
+        };
+
+        let pending = std::mem::take(&mut log.lock().unwrap().pending);
+        let count = pending.len();
+        for event in pending.into_values() {
+            if let (Some(delivered), NetworkEvent::Message(message)) = (&self.delivered, &event) {
+                if let Some(id) = message.body.id {
+                    delivered.lock().unwrap().remove(&(message.src.clone(), id));
+                }
+            }
+            self.tx
+                .send(event)
+                .map_err(|_| Error::Other(anyhow::anyhow!("re-dispatching logged event")))?;
+        }
+        Ok(count)
+    }
+
+    // Handles reserved message types at the untyped layer so they never have to
+    // appear in a node's payload enum.
+    fn intercept(&self, event: &NetworkEvent<IP>) -> bool {
+        let NetworkEvent::Message(message) = event else {
+            return false;
+        };
+
+        // Replies nobody is waiting for any more, e.g. to a fire-and-forget
+        // write or a request that timed out. They go on to the node as
+        // `Event::Storage` whatever types it handles.
+        if STORAGE_ADDRESSES.contains(&message.src.as_str()) {
+            return false;
+        }
+
+        match message.kind() {
+            Some("health") if self.auto_health => {
+                let reply = serde_json::json!({ "type": "health_ok" });
+                if let Err(e) = self.reply_untyped(message, reply) {
+                    eprintln!("failed to answer health check: {:#}", e);
+                }
+                true
+            }
+            Some("debug_dump") if self.debug_endpoint => {
+                let reply = serde_json::json!({
+                    "type": "debug_dump_ok",
+                    "state": self.debug_dump.dump(),
+                });
+                if let Err(e) = self.reply_untyped(message, reply) {
+                    eprintln!("failed to answer debug dump: {:#}", e);
+                }
+                true
+            }
+            Some("metrics") if self.metrics_endpoint => {
+                let hook = self.metrics.hook.read().unwrap().clone();
+                let metrics = hook
+                    .map(|hook| hook(self))
+                    .unwrap_or_else(|| self.metrics());
+                let reply = serde_json::json!({
+                    "type": "metrics_ok",
+                    "metrics": metrics,
+                });
+                if let Err(e) = self.reply_untyped(message, reply) {
+                    eprintln!("failed to answer metrics: {:#}", e);
+                }
+                true
+            }
+            Some("reconfigure") => {
+                let reply = serde_json::json!({
+                    "type": "error",
+                    "code": usize::from(ErrorCode::MalformedRequest),
+                    "text": "reconfigure needs a non-empty node_ids list",
+                });
+                if let Err(e) = self.reply_untyped(message, reply) {
+                    eprintln!("failed to reject reconfigure: {:#}", e);
+                }
+                true
+            }
+            Some(kind) if !self.handles(kind) => {
+                if message.body.id.is_some() && kind != "error" {
+                    let reply = serde_json::json!({
+                        "type": "error",
+                        "code": usize::from(ErrorCode::NotSupported),
+                        "text": format!("message type {} is not supported", kind),
+                    });
+                    if let Err(e) = self.reply_untyped(message, reply) {
+                        eprintln!("failed to reject unsupported message: {:#}", e);
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Applies a `{"type": "reconfigure", "node_ids": [...]}` message to the
+    // membership and the tracked peers, and acknowledges it. Maelstrom never
+    // sends one; it's for experiments with a changing cluster. Malformed ones
+    // are left for `intercept` to reject.
+    fn reconfigure(&self, event: &NetworkEvent<IP>) -> Option<Vec<String>> {
+        let NetworkEvent::Message(message) = event else {
+            return None;
+        };
+        if message.kind() != Some("reconfigure") {
+            return None;
+        }
+        let node_ids: Vec<String> =
+            serde_json::from_value(message.body.payload.get("node_ids")?.clone()).ok()?;
+        if node_ids.is_empty() {
+            return None;
+        }
+
+        *self.members.write().unwrap() = node_ids.clone();
+        let me = self.init.get().map(|init| init.node_id.as_str());
+        self.last_heard
+            .write()
+            .unwrap()
+            .retain(|peer, _| node_ids.contains(peer));
+        self.track_peers(
+            node_ids
+                .iter()
+                .filter(|id| Some(id.as_str()) != me)
+                .cloned(),
+        );
+
+        let reply = serde_json::json!({ "type": "reconfigure_ok" });
+        if let Err(e) = self.reply_untyped(message, reply) {
+            eprintln!("failed to answer reconfigure: {:#}", e);
+        }
+        Some(node_ids)
+    }
+
+    fn handles(&self, kind: &str) -> bool {
+        self.handled_types.is_empty() || kind == "error" || self.handled_types.contains(&kind)
+    }
+
+    fn reply_untyped(
+        &self,
+        message: &UntypedMessage,
+        payload: serde_json::Value,
+    ) -> Result<usize, Error> {
+        self.send(
+            Message::new(message.dst.clone(), message.src.clone(), payload)
+                .with_in_reply_to(message.body.id),
+        )
+    }
+
+    fn is_duplicate(&self, event: &NetworkEvent<IP>) -> bool {
+        let Some(delivered) = &self.delivered else {
+            return false;
+        };
+        let NetworkEvent::Message(message) = event else {
+            return false;
+        };
+        let Some(id) = message.body.id else {
+            return false;
+        };
+
+        let key = (message.src.clone(), id);
+        let mut delivered = delivered.lock().unwrap();
+        if delivered.get(&key).is_some() {
+            return true;
+        }
+        delivered.put(key, ());
+        false
+    }
+
+    fn trace_response(&self, id: usize, descriptor: &RequestDescriptor, response: &UntypedMessage) {
+        let response_kind = response.kind().unwrap_or("unknown");
+
+        eprintln!(
+            "RPC {} {}{} -> {} {} in {:?}",
+            id,
+            descriptor.kind,
+            descriptor
+                .key
+                .as_ref()
+                .map(|key| format!(" key={}", key))
+                .unwrap_or_default(),
+            response.src,
+            response_kind,
+            self.clock.elapsed(descriptor.sent_at)
+        );
+    }
+
+    fn is_response(&self, event: &NetworkEvent<IP>) -> Option<PendingRequest> {
+        if let NetworkEvent::Message(message) = event {
+            if let Some(replying_to) = message.body.in_reply_to {
+                let request = self
+                    .awaiting_responses
+                    .write()
+                    .unwrap()
+                    .remove_entry(&replying_to);
+
+                if let Some((id, pending)) = request {
+                    dbg!("RESPONDING TO REQUEST", id);
+                    if self.tracing {
+                        self.trace_response(id, &pending.descriptor, message);
+                    }
+                    return Some(pending);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn inject(&self, payload: IP) -> Result<(), Error> {
+        self.tx
+            .send(NetworkEvent::Injected(payload))
+            .map_err(|_| Error::Other(anyhow::anyhow!("injecting message into network")))
+    }
+
+    // Injects `payload` every `interval` until the network shuts down.
+    pub fn every(&self, interval: Duration, payload: IP) -> JoinHandle<()> {
+        let network = self.clone();
+        std::thread::spawn(move || loop {
+            network.clock.sleep(interval);
+            if network.is_closed() || network.inject(payload.clone()).is_err() {
+                break;
+            }
+        })
+    }
+
+    // Writes the pending batch every window until the network shuts down.
+    pub fn start_batch_flusher(&self) -> Option<JoinHandle<()>> {
+        let (window, _) = self.batch.as_ref()?;
+        let window = *window;
+        let network = self.clone();
+        Some(std::thread::spawn(move || loop {
+            network.clock.sleep(window);
+            network.flush_batch();
+            if network.is_closed() {
+                break;
+            }
+        }))
+    }
+
+    // Starts the keepalive timer if one was configured. It stops once the
+    // network shuts down.
+    pub fn start_keepalive(&self) -> Option<JoinHandle<()>> {
+        let interval = self.keepalive?;
+        let network = self.clone();
+        Some(std::thread::spawn(move || loop {
+            network.clock.sleep(interval);
+            if network.is_closed() {
+                break;
+            }
+            if network.idle_for() >= interval && network.tx.send(NetworkEvent::Keepalive).is_err() {
+                break;
+            }
+        }))
+    }
+
+    // Injects `payload` once after `delay`, unless the network shuts down
+    // first. A node can call this while handling the payload to reschedule
+    // itself with a delay of its choosing instead of running on a fixed
+    // `every` timer; the chain ends on shutdown since nothing is injected.
+    pub fn inject_after(&self, payload: IP, delay: Duration) {
+        let network = self.clone();
+        self.run_after(delay, move || {
+            if !network.is_closed() && network.inject(payload).is_err() {
+                dbg!("DROPPING DELAYED INJECTION");
+            }
+        });
+    }
+
+    // Registers one timer per `(interval, payload)`. Giving every periodic task
+    // its own payload variant lets the node's injected arm dispatch on it.
+    pub fn every_tagged<I>(&self, timers: I) -> Vec<JoinHandle<()>>
+    where
+        I: IntoIterator<Item = (Duration, IP)>,
+    {
+        timers
+            .into_iter()
+            .map(|(interval, payload)| self.every(interval, payload))
+            .collect()
+    }
+
+    pub fn send<PAYLOAD>(&self, message: Message<PAYLOAD>) -> Result<usize, Error>
+    where
+        PAYLOAD: Serialize + Clone + Debug,
+    {
+        let id = self.next_message_id();
+        Ok(self.send_as(message, id)?)
+    }
+
+    // `send` with the msg_id already picked, for callers that need to know it
+    // before the message goes out.
+    fn send_as<PAYLOAD>(&self, mut message: Message<PAYLOAD>, id: usize) -> anyhow::Result<usize>
+    where
+        PAYLOAD: Serialize + Clone + Debug,
+    {
+        if self.is_closed() {
+            anyhow::bail!("network is shut down");
+        }
+
+        message.body.id = Some(id);
+        match self.write_message(&message) {
+            Ok(()) => {
+                self.touch();
+                Ok(id)
+            }
+            Err(e) => match self.send_error_hook.handle(&message.dst, &e) {
+                SendErrorAction::Propagate => Err(e),
+                SendErrorAction::Swallow => {
+                    eprintln!("dropping message {} to {}: {:#}", id, message.dst, e);
+                    Ok(id)
+                }
+            },
+        }
+    }
+
+    fn write_message<PAYLOAD>(&self, message: &Message<PAYLOAD>) -> anyhow::Result<()>
+    where
+        PAYLOAD: Serialize,
+    {
+        // Going through `UntypedMessage` exposes the `type` tag for counting
+        // and hands filters something they can inspect.
+        let message: UntypedMessage = serde_json::to_value(message)
+            .and_then(serde_json::from_value)
+            .context("serializing message")?;
+        self.write_past_filters(message, 0)
+    }
+
+    // How many outbound filters there are, so a filter being added knows its
+    // own place in the chain.
+    pub(crate) fn outbound_filter_count(&self) -> usize {
+        self.filters.read().unwrap().outbound.len()
+    }
+
+    // Runs `message` through the outbound filters from the `skip`th on and
+    // writes what's left, for a filter that held it back and sends it on
+    // later.
+    pub(crate) fn write_past_filters(
+        &self,
+        message: UntypedMessage,
+        skip: usize,
+    ) -> anyhow::Result<()> {
+        let filtered = {
+            let filters = self.filters.read().unwrap();
+            apply_filters(filters.outbound.get(skip..).unwrap_or_default(), message)
+        };
+        let Some(message) = filtered else {
+            dbg!("DROPPED BY OUTBOUND FILTER");
+            return Ok(());
+        };
+        self.write_unfiltered(message)
+    }
+
+    // Writes `message` as is, without running the outbound filters.
+    fn write_unfiltered(&self, message: UntypedMessage) -> anyhow::Result<()> {
+        let kind = message.kind().unwrap_or("unknown").to_string();
+        if let Some((_, batch)) = &self.batch {
+            let mut batch = batch.lock().unwrap();
+            if let Some(e) = batch.failed.take() {
+                return Err(e.context("an earlier batched write failed"));
+            }
+            batch.messages.push(message);
+        } else {
+            let output = self
+                .format
+                .encode(&message)
+                .context("serializing message")?;
+            dbg!("SENDING {:?}", &output);
+            self.transport
+                .write_line(&output)
+                .map_err(Error::Transport)
+                .context("writing message to output")?;
+        }
+
+        *self.sent_by_type.write().unwrap().entry(kind).or_default() += 1;
+        Ok(())
+    }
+
+    // Called whenever serializing or writing an outbound message fails, with
+    // the destination and the error. The hook decides whether `send` reports
+    // the failure or swallows it.
+    pub fn on_send_error<F>(&self, hook: F)
+    where
+        F: Fn(&str, &anyhow::Error) -> SendErrorAction + Send + Sync + 'static,
+    {
+        self.send_error_hook.set(hook);
+    }
+
+    pub async fn request<PAYLOAD>(
+        &self,
+        message: Message<PAYLOAD>,
+    ) -> Result<Message<PAYLOAD>, Error>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+    {
+        self.request_with_priority(message, Priority::Normal).await
+    }
+
+    pub async fn request_timed<PAYLOAD>(
+        &self,
+        message: Message<PAYLOAD>,
+    ) -> Result<Response<PAYLOAD>, Error>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+    {
+        self.request_with_priority_timed(message, Priority::Normal)
+            .await
+    }
+
+    // With an in-flight cap configured, `priority` decides who gets the next
+    // free slot; otherwise it has no effect.
+    pub async fn request_with_priority<PAYLOAD>(
+        &self,
+        message: Message<PAYLOAD>,
+        priority: Priority,
+    ) -> Result<Message<PAYLOAD>, Error>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+    {
+        let response = self.request_with_priority_timed(message, priority).await?;
+        Ok(response.message)
+    }
+
+    // The elapsed time excludes any wait for an in-flight slot.
+    pub async fn request_with_priority_timed<PAYLOAD>(
+        &self,
+        message: Message<PAYLOAD>,
+        priority: Priority,
+    ) -> Result<Response<PAYLOAD>, Error>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+    {
+        let (response, elapsed) = self.request_untyped(message, priority).await?;
+        Ok(Response {
+            message: response.into(),
+            elapsed,
+        })
+    }
+
+    // Re-sends `message` under the network's retry policy for as long as it
+    // fails with a retryable code, whether the request itself failed or the
+    // peer answered with an `error` reply. Every attempt gets a fresh msg_id.
+    // A non-retryable `error` reply comes back as an `Error`, so `PAYLOAD`
+    // needn't have an error variant.
+    pub async fn request_retry<PAYLOAD>(
+        &self,
+        message: Message<PAYLOAD>,
+    ) -> Result<Message<PAYLOAD>, Error>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+    {
+        let mut retry = Retry::with_policy(self.retry_policy().clone());
+        loop {
+            let error = match self
+                .request_untyped(message.clone(), Priority::Normal)
+                .await
+            {
+                Ok((response, _)) if response.kind() == Some("error") => {
+                    let reply: MaelstromError = serde_json::from_value(response.body.payload)
+                        .map_err(Error::deserialize)
+                        .context("reading error reply")?;
+                    Error::from((ErrorCode::from(reply.code), reply.text))
+                }
+                Ok((response, _)) => return Ok(response.into()),
+                Err(e) => Error::from(e),
+            };
+            if !retry.should_retry(&error) {
+                return Err(error.context(format!(
+                    "request to {} failed after {} attempts",
+                    message.dst,
+                    retry.failures() + 1
+                )));
+            }
+            retry.backoff().await;
+        }
+    }
+
+    async fn request_untyped<PAYLOAD>(
+        &self,
+        message: Message<PAYLOAD>,
+        priority: Priority,
+    ) -> anyhow::Result<(UntypedMessage, Duration)>
+    where
+        PAYLOAD: Serialize + Clone + Debug,
+    {
+        let _permit = match &self.limiter {
+            Some(limiter) => Some(limiter.acquire(priority).await),
+            None => None,
+        };
+
+        // Registered before the send, so a reply can't arrive ahead of its
+        // entry and be dropped as unawaited.
+        let descriptor = RequestDescriptor::from_payload(&message.body.payload, self.clock.now());
+        let id = self.next_message_id();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        {
+            let mut awaiting_responses = self.awaiting_responses.write().unwrap();
+            if self.is_closed() {
+                anyhow::bail!("network shut down before request {} was registered", id);
+            }
+            awaiting_responses.insert(
+                id,
+                PendingRequest {
+                    responder: tx,
+                    descriptor,
+                },
+            );
+        }
+        let _registration = Registration {
+            awaiting_responses: &self.awaiting_responses,
+            id,
+        };
+
+        self.send_as(message, id)
+            .context("sending message in request")?;
+        *self.requests_sent.write().unwrap() += 1;
+
+        rx.await.context("failed to receive response")
+    }
+
+    // Sends every chunk as its own reply to `original`: each gets a fresh
+    // msg_id but they all share its in_reply_to. `request` on the other end
+    // resolves with whichever chunk lands first and hands the rest to the
+    // node as ordinary messages, so a streaming client sends its request
+    // with `send` and collects the chunks itself by in_reply_to, with a
+    // payload variant of the protocol's choosing marking the last one.
+    pub fn reply_stream<P, Q>(
+        &self,
+        original: &Message<P>,
+        chunks: Vec<Q>,
+    ) -> Result<Vec<usize>, Error>
+    where
+        Q: Serialize + Clone + Debug,
+    {
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                self.send(original.reply_with(chunk))
+                    .map_err(|e| e.context(format!("sending reply chunk {}", i)))
+            })
+            .collect()
+    }
+
+    // Sends `message` to its destination and, if no reply arrives within
+    // `timeout` on the network's clock, re-issues it to `fallback`, which gets
+    // as long again before this fails with `Error::Timeout`. Returns whichever
+    // destination answered along with the reply. A late reply from the first
+    // destination reaches the node like any other unawaited reply.
+    pub async fn request_with_fallback<PAYLOAD>(
+        &self,
+        message: Message<PAYLOAD>,
+        fallback: &str,
+        timeout: Duration,
+    ) -> Result<(String, Message<PAYLOAD>), Error>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+    {
+        let primary = message.dst.clone();
+        let retry = Message::new(message.src.clone(), fallback, message.body.payload.clone());
+        let primary_request = self.request(message);
+        if let Some(response) = clock::timeout(self.clock.as_ref(), timeout, primary_request).await
+        {
+            let response =
+                response.map_err(|e| e.context(format!("requesting from {}", primary)))?;
+            return Ok((primary, response));
+        }
+        *self.request_timeouts.write().unwrap() += 1;
+
+        let fallback_request = self.request(retry);
+        let Some(response) = clock::timeout(self.clock.as_ref(), timeout, fallback_request).await
+        else {
+            *self.request_timeouts.write().unwrap() += 1;
+            return Err(Error::Timeout(format!(
+                "neither {} nor fallback {} answered within {:?}",
+                primary, fallback, timeout
+            )));
+        };
+        let response =
+            response.map_err(|e| e.context(format!("requesting from fallback {}", fallback)))?;
+        Ok((fallback.to_string(), response))
+    }
+
+    // Sends every message as a request at once. Replies come out of the set
+    // as they land, paired with the peer they came from; dropping the set
+    // abandons whichever are still outstanding.
+    pub fn request_all<PAYLOAD>(
+        &self,
+        messages: Vec<Message<PAYLOAD>>,
+    ) -> tokio::task::JoinSet<(String, Result<Message<PAYLOAD>, Error>)>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug + Send + 'static,
+        IP: Sync,
+    {
+        let mut requests = tokio::task::JoinSet::new();
+        for message in messages {
+            let network = self.clone();
+            requests.spawn(async move {
+                let dst = message.dst.clone();
+                let response = network.request(message).await;
+                (dst, response)
+            });
+        }
+        requests
+    }
+
+    // Re-issues `message` to `dst` on behalf of its sender and relays whatever
+    // `dst` answers back to that sender as the reply to the original message.
+    pub async fn forward<PAYLOAD>(&self, message: Message<PAYLOAD>, dst: &str) -> Result<(), Error>
+    where
+        PAYLOAD: DeserializeOwned + Serialize + Clone + Debug,
+    {
+        let forwarded = Message::new(message.dst.clone(), dst, message.body.payload.clone());
+        let response = self
+            .request(forwarded)
+            .await
+            .context(format!("forwarding to {}", dst))?;
+
+        let mut reply = message.into_reply();
+        reply.body.payload = response.body.payload;
+        self.send(reply).context("relaying forwarded reply")?;
+        Ok(())
+    }
+
+    // Drops every pending responder so tasks awaiting a reply in `request` unwind
+    // with an error instead of hanging, and rejects any further sends.
+    pub fn shutdown(&self) {
+        let mut awaiting_responses = self.awaiting_responses.write().unwrap();
+        *self.closed.write().unwrap() = true;
+        awaiting_responses.clear();
+    }
+
+    // What every outstanding request is waiting on, for a process about to
+    // hand over to a successor that will `reregister_pending` them.
+    pub fn pending_requests(&self) -> Vec<(usize, RequestDescriptor)> {
+        let awaiting_responses = self.awaiting_responses.read().unwrap();
+        awaiting_responses
+            .iter()
+            .map(|(id, pending)| (*id, pending.descriptor.clone()))
+            .collect()
+    }
+
+    // Expects replies to requests an earlier process sent. Their awaiters are
+    // gone, so matching replies go to the `on_orphaned_reply` hook instead of
+    // the node, and are dropped without one. The message id counter is moved
+    // past every re-registered id so new requests can't collide with them.
+    pub fn reregister_pending(&self, pending: Vec<(usize, RequestDescriptor)>) {
+        let mut message_id = self.message_id.write().unwrap();
+        let mut descriptors = self.orphans.descriptors.write().unwrap();
+        for (id, descriptor) in pending {
+            // Only the counter bits of a scoped id; its tag would push every
+            // later unscoped id into that scope.
+            *message_id = (*message_id).max((id & ID_COUNTER_MASK) + 1);
+            descriptors.insert(id, descriptor);
+        }
+    }
+
+    pub fn on_orphaned_reply<F>(&self, hook: F)
+    where
+        F: Fn(RequestDescriptor, UntypedMessage) + Send + Sync + 'static,
+    {
+        *self.orphans.hook.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    // Hands `message` to the orphaned reply hook if it answers a re-registered
+    // request, returning whether it did.
+    fn settle_orphan(&self, message: &UntypedMessage) -> bool {
+        let Some(replying_to) = message.body.in_reply_to else {
+            return false;
+        };
+        let Some(descriptor) = self
+            .orphans
+            .descriptors
+            .write()
+            .unwrap()
+            .remove(&replying_to)
+        else {
+            return false;
+        };
+
+        let hook = self.orphans.hook.read().unwrap().clone();
+        match hook {
+            Some(hook) => hook(descriptor, message.clone()),
+            None => eprintln!(
+                "dropping reply to re-registered request {} ({})",
+                replying_to, descriptor.kind
+            ),
+        }
+        true
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.read().unwrap()
+    }
+
+    // Runs until shutdown when `with_id_checkpoint` is set, checkpointing every
+    // `interval` on the network's clock; a server aborts it once finished
+    // rather than wait out the last interval. Replies are only settled by
+    // `recv`, so this must run alongside the receive loop, and any message
+    // sent before the seed lands still uses the unseeded counter.
+    pub async fn persist_message_ids(&self) -> Result<(), Error> {
+        let Some(interval) = self.id_checkpoint else {
+            return Ok(());
+        };
+
+        let node_id = self.init().node_id.clone();
+        let key = format!("msg-id/{}", node_id);
+        let store = SequentialStore::new(node_id);
+        let stored: Option<usize> = store
+            .try_read(key.clone(), self)
+            .await
+            .context("reading message id checkpoint")?;
+        if let Some(stored) = stored {
+            let mut message_id = self.message_id.write().unwrap();
+            *message_id = (*message_id).max(stored);
+        }
+
+        loop {
+            self.clock.delay(interval).await;
+            if self.is_closed() {
+                return Ok(());
+            }
+            let reserved = *self.message_id.read().unwrap() + ID_CHECKPOINT_HEADROOM;
+            if let Err(e) = store.write(key.clone(), reserved, self) {
+                eprintln!("failed to checkpoint message ids: {:#}", e);
+            }
+        }
+    }
+
+    // A handle on this network whose messages all get ids tagged with the
+    // same fresh scope, so a trace can group the RPCs one operation issues:
+    // `id >> SCOPE_SHIFT` is the tag, 0 for unscoped ids. The low bits still
+    // come from the one shared counter, which is what keeps ids unique, so
+    // scoped and unscoped sends interleave safely and a persisted checkpoint
+    // covers both. Tags wrap after `MAX_SCOPE_TAG` scopes.
+    pub fn id_scope(&self) -> IdScope<IP>
+    where
+        IP: Clone,
+    {
+        let mut next_scope = self.next_scope.write().unwrap();
+        let tag = *next_scope;
+        *next_scope = tag % MAX_SCOPE_TAG + 1;
+
+        let mut network = self.clone();
+        network.scope = Some(tag);
+        IdScope { network }
+    }
+
+    fn next_message_id(&self) -> usize {
         let mut message_id = self.message_id.write().unwrap();
         let id = *message_id;
         *message_id += 1;
-        id
+        match self.scope {
+            Some(tag) => (tag << SCOPE_SHIFT) | id,
+            None => id,
+        }
+    }
+}
+
+// Returned by `Network::id_scope`; send through it to tag ids with the scope.
+#[derive(Debug, Clone)]
+pub struct IdScope<IP = ()> {
+    network: Network<IP>,
+}
+
+impl<IP> IdScope<IP> {
+    pub fn tag(&self) -> usize {
+        self.network.scope.expect("id scope without a tag")
+    }
+}
+
+impl<IP> std::ops::Deref for IdScope<IP> {
+    type Target = Network<IP>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.network
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, service::StoragePayload};
+
+    fn network() -> (Network, crate::transport::Output) {
+        let (transport, output) = Transport::in_memory("");
+        (Network::with_transport(transport), output)
+    }
+
+    fn ping(dst: &str) -> Message<serde_json::Value> {
+        Message::new("n1", dst, serde_json::json!({ "type": "ping" }))
+    }
+
+    // Waits until `count` requests are registered and awaiting replies.
+    async fn until_pending(network: &Network, count: usize) {
+        while network.pending_requests().len() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // Answers the request written on `line` as its destination would.
+    fn reply_to(network: &Network, line: &str) {
+        let request: Message<serde_json::Value> = serde_json::from_str(line).unwrap();
+        let reply = request.reply_with(serde_json::json!({ "type": "pong" }));
+        network
+            .tx
+            .send(NetworkEvent::Message(reply.into()))
+            .unwrap();
+    }
+
+    // Settles replies until aborted, as the serve loop would.
+    fn dispatch(network: &Network) -> tokio::task::JoinHandle<()> {
+        let network = network.clone();
+        tokio::spawn(async move { while network.recv::<serde_json::Value>().await.is_some() {} })
+    }
+
+    #[tokio::test]
+    async fn fallback_answers_when_primary_never_does() {
+        let clock = Arc::new(MockClock::new());
+        let (network, output) = network();
+        let network = network.with_clock(clock.clone());
+        let dispatcher = dispatch(&network);
+
+        let requester = network.clone();
+        let request = tokio::spawn(async move {
+            requester
+                .request_with_fallback(ping("n2"), "n3", Duration::from_secs(1))
+                .await
+        });
+        until_pending(&network, 1).await;
+        clock.advance(Duration::from_secs(1));
+
+        // The abandoned primary request doesn't linger once the fallback goes out.
+        while output.lines().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        let pending = network.pending_requests();
+        assert_eq!(pending.len(), 1);
+        let lines = output.lines();
+        assert!(lines[1].contains(r#""dest":"n3""#));
+
+        reply_to(&network, &lines[1]);
+        let (answered_by, reply) = request.await.unwrap().unwrap();
+        assert_eq!(answered_by, "n3");
+        assert_eq!(reply.body.payload["type"], "pong");
+        assert!(network.pending_requests().is_empty());
+        dispatcher.abort();
+    }
+
+    #[tokio::test]
+    async fn fallback_that_never_answers_times_out() {
+        let clock = Arc::new(MockClock::new());
+        let (network, _) = network();
+        let network = network.with_clock(clock.clone());
+
+        let requester = network.clone();
+        let request = tokio::spawn(async move {
+            requester
+                .request_with_fallback(ping("n2"), "n3", Duration::from_secs(1))
+                .await
+        });
+        until_pending(&network, 1).await;
+        clock.advance(Duration::from_secs(1));
+        // The primary is deregistered before the fallback goes out.
+        while network.report().timeouts < 1 {
+            tokio::task::yield_now().await;
+        }
+        until_pending(&network, 1).await;
+        clock.advance(Duration::from_secs(1));
+
+        let error = request.await.unwrap().unwrap_err();
+        assert!(matches!(error, Error::Timeout(_)), "{:?}", error);
+        assert!(network.pending_requests().is_empty());
+    }
+
+    // Hands out `chunks` one read at a time, failing once with a timeout
+    // after the first.
+    struct FlakyReader {
+        chunks: std::collections::VecDeque<&'static str>,
+        failed: bool,
+    }
+
+    impl std::io::Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.chunks.len() == 1 && !self.failed {
+                self.failed = true;
+                return Err(std::io::Error::from(std::io::ErrorKind::TimedOut));
+            }
+            let Some(chunk) = self.chunks.pop_front() else {
+                return Ok(0);
+            };
+            buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+            Ok(chunk.len())
+        }
+    }
+
+    // The error lands mid-line; the half already read isn't lost.
+    #[tokio::test]
+    async fn read_thread_resumes_after_a_transient_error() {
+        let reader = FlakyReader {
+            chunks: [
+                r#"{"src":"c1","dest":"n1","body":{"#,
+                concat!(
+                    r#""type":"ping"}}"#,
+                    "\n",
+                    r#"{"src":"c2","dest":"n1","body":{"type":"ping"}}"#,
+                    "\n"
+                ),
+            ]
+            .into(),
+            failed: false,
+        };
+        let reader = std::io::BufReader::new(reader);
+        let network: Network = Network::with_transport(Transport::from_io(reader, std::io::sink()));
+        let reading = network.start_read_thread();
+
+        let mut sources = Vec::new();
+        while let Some(Event::Message(message)) = network.recv::<serde_json::Value>().await {
+            sources.push(message.src);
+        }
+        assert_eq!(sources, ["c1", "c2"]);
+        reading.join().unwrap().unwrap();
+    }
+
+    // With the one slot taken, a high-priority request queued behind two
+    // normal ones still goes out first, and the normal ones keep their order.
+    #[tokio::test]
+    async fn high_priority_requests_take_the_next_free_slot() {
+        let (network, output) = network();
+        let network = network.with_max_in_flight(1);
+        let dispatcher = dispatch(&network);
+
+        let mut requests = Vec::new();
+        for (dst, priority) in [
+            ("n2", Priority::Normal),
+            ("n3", Priority::Normal),
+            ("n4", Priority::Normal),
+            ("n5", Priority::High),
+        ] {
+            let requester = network.clone();
+            requests.push(tokio::spawn(async move {
+                requester.request_with_priority(ping(dst), priority).await
+            }));
+            // Queued in the order they were issued.
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        for sent in 1..=4 {
+            while output.lines().len() < sent {
+                tokio::task::yield_now().await;
+            }
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            // The slot is still taken, so nothing else has gone out.
+            assert_eq!(output.lines().len(), sent);
+            reply_to(&network, &output.lines()[sent - 1]);
+        }
+        for request in requests {
+            request.await.unwrap().unwrap();
+        }
+
+        let destinations: Vec<String> = output
+            .lines()
+            .iter()
+            .map(|line| serde_json::from_str::<UntypedMessage>(line).unwrap().dst)
+            .collect();
+        assert_eq!(destinations, ["n2", "n5", "n3", "n4"]);
+        dispatcher.abort();
+    }
+
+    #[tokio::test]
+    async fn storage_message_with_an_unexpected_body_is_dropped() {
+        let (network, _) = network();
+        for (src, payload) in [
+            (
+                "lin-kv",
+                serde_json::json!({ "type": "surprise", "in_reply_to": 99 }),
+            ),
+            ("n2", serde_json::json!({ "type": "ping" })),
+        ] {
+            let message: Message<serde_json::Value> = Message::new(src, "n1", payload);
+            network
+                .tx
+                .send(NetworkEvent::Message(message.into()))
+                .unwrap();
+        }
+
+        // The node's payload type can't parse the storage body, so reaching the
+        // ping means it was dropped rather than deserialized.
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum Payload {
+            Ping,
+        }
+        let Some(Event::Message(message)) = network.recv::<Payload>().await else {
+            panic!("expected the ping");
+        };
+        assert_eq!(message.src, "n2");
+        assert_eq!(message.body.payload, Payload::Ping);
+    }
+
+    #[tokio::test]
+    async fn unawaited_storage_reply_is_received_as_a_storage_event() {
+        let (mut network, _) = network();
+        network.set_handled_types(&["ping"]);
+        let mut reply: Message<serde_json::Value> =
+            Message::new("lin-kv", "n1", serde_json::json!({ "type": "write_ok" }));
+        reply.body.in_reply_to = Some(99);
+        network
+            .tx
+            .send(NetworkEvent::Message(reply.into()))
+            .unwrap();
+
+        let Some(Event::Storage(message)) = network.recv::<serde_json::Value>().await else {
+            panic!("expected a storage event");
+        };
+        assert_eq!(message.src, "lin-kv");
+        assert!(matches!(message.body.payload, StoragePayload::WriteOk));
+    }
+
+    #[tokio::test]
+    async fn inject_after_waits_on_the_network_clock() {
+        let clock = Arc::new(MockClock::new());
+        let (transport, _) = Transport::in_memory("");
+        let network: Network<usize> = Network::with_transport(transport).with_clock(clock.clone());
+        network.inject_after(1, Duration::from_secs(1));
+
+        let next = network.recv::<serde_json::Value>();
+        tokio::pin!(next);
+        assert!(futures::poll!(next.as_mut()).is_pending());
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(next.await, Some(Event::Injected(1))));
+    }
+
+    #[tokio::test]
+    async fn abandoned_request_is_deregistered() {
+        let (network, _) = network();
+        let request = network.request(ping("n2"));
+        let timed = tokio::time::timeout(Duration::from_millis(10), request).await;
+        assert!(timed.is_err());
+        assert!(network.pending_requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_unblocks_pending_request() {
+        let (network, output) = network();
+        let requester = network.clone();
+        let pending = tokio::spawn(async move { requester.request(ping("n2")).await });
+        until_pending(&network, 1).await;
+        assert_eq!(output.lines().len(), 1);
+
+        network.shutdown();
+        let result = pending.await.unwrap();
+        assert!(result.is_err());
+        assert!(network.send(ping("n2")).is_err());
+    }
+
+    // A current-thread runtime has nowhere to move a blocking send to, so
+    // this only passes if injecting never blocks, even with the input queue
+    // bounded.
+    #[tokio::test]
+    async fn inject_never_blocks_the_runtime() {
+        let (transport, _) = Transport::in_memory("");
+        let network: Network<usize> = Network::with_transport(transport).with_event_buffer(1);
+        for i in 0..3 {
+            network.inject(i).unwrap();
+        }
+        for i in 0..3 {
+            let event = network.recv::<serde_json::Value>().await;
+            assert!(matches!(event, Some(Event::Injected(n)) if n == i));
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_receivers_each_get_an_event() {
+        let (transport, _) = Transport::in_memory("");
+        let network: Network<usize> = Network::with_transport(transport);
+        let receivers: Vec<_> = (0..2)
+            .map(|_| {
+                let network = network.clone();
+                tokio::spawn(async move { network.recv::<serde_json::Value>().await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+
+        network.inject(1).unwrap();
+        network.inject(2).unwrap();
+        let mut received = Vec::new();
+        for receiver in receivers {
+            match receiver.await.unwrap() {
+                Some(Event::Injected(n)) => received.push(n),
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        received.sort();
+        assert_eq!(received, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn recv_ends_with_input() {
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1}}"#;
+        let (transport, _) = Transport::in_memory(format!("{}\n", line));
+        let network: Network = Network::with_transport(transport);
+        let reader = network.start_read_thread();
+
+        let event = network.recv::<serde_json::Value>().await;
+        assert!(matches!(event, Some(Event::Message(message)) if message.src == "c1"));
+        assert!(network.recv::<serde_json::Value>().await.is_none());
+        reader.join().unwrap().unwrap();
+    }
+
+    // n2 goes quiet while n3 keeps talking; only n2 is suspected once the
+    // clock passes the threshold.
+    #[tokio::test]
+    async fn peers_silent_past_the_threshold_are_suspected() {
+        let clock = Arc::new(MockClock::new());
+        let (network, _) = network();
+        let network = network.with_clock(clock.clone());
+        network.track_peers(["n2".to_string(), "n3".to_string()]);
+        let threshold = Duration::from_secs(5);
+
+        clock.advance(threshold);
+        assert!(network.suspected_down(threshold).is_empty());
+
+        let message = Message::new("n3", "n1", serde_json::json!({ "type": "ping" }));
+        network
+            .tx
+            .send(NetworkEvent::Message(message.into()))
+            .unwrap();
+        network.recv::<serde_json::Value>().await.unwrap();
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(network.suspected_down(threshold), ["n2"]);
+    }
+
+    #[test]
+    fn scoped_ids_share_a_tag_and_the_counter() {
+        let (network, _) = network();
+        let first = network.next_message_id();
+        let scope = network.id_scope();
+        let scoped = [scope.next_message_id(), scope.next_message_id()];
+        let after = network.next_message_id();
+
+        assert!(scoped.iter().all(|id| id >> SCOPE_SHIFT == scope.tag()));
+        let counters: Vec<usize> = [first, scoped[0], scoped[1], after]
+            .iter()
+            .map(|id| id & ID_COUNTER_MASK)
+            .collect();
+        assert_eq!(counters, [first, first + 1, first + 2, first + 3]);
+    }
+
+    #[test]
+    fn scope_tags_wrap_before_ids_leave_the_i64_range() {
+        let (network, _) = network();
+        *network.next_scope.write().unwrap() = MAX_SCOPE_TAG;
+        let last = network.id_scope();
+        assert!(last.next_message_id() <= i64::MAX as usize);
+        assert_eq!(network.id_scope().tag(), 1);
+    }
+
+    #[tokio::test]
+    async fn reply_to_a_reregistered_request_goes_to_the_hook() {
+        let (network, _) = network();
+        let orphaned = Arc::new(Mutex::new(Vec::new()));
+        let hook_orphaned = orphaned.clone();
+        network.on_orphaned_reply(move |descriptor, reply| {
+            hook_orphaned
+                .lock()
+                .unwrap()
+                .push((descriptor.key, reply.kind().map(String::from)));
+        });
+        let descriptor = RequestDescriptor {
+            kind: "read".to_string(),
+            key: Some("k".to_string()),
+            sent_at: Instant::now(),
+        };
+        network.reregister_pending(vec![(7, descriptor)]);
+        assert!(network.next_message_id() > 7);
+
+        for (src, payload, in_reply_to) in [
+            (
+                "lin-kv",
+                serde_json::json!({ "type": "read_ok", "value": 1 }),
+                Some(7),
+            ),
+            ("n2", serde_json::json!({ "type": "ping" }), None),
+        ] {
+            let mut message: Message<serde_json::Value> = Message::new(src, "n1", payload);
+            message.body.in_reply_to = in_reply_to;
+            network
+                .tx
+                .send(NetworkEvent::Message(message.into()))
+                .unwrap();
+        }
+
+        let Some(Event::Message(message)) = network.recv::<serde_json::Value>().await else {
+            panic!("expected the ping");
+        };
+        assert_eq!(message.src, "n2");
+        assert_eq!(
+            *orphaned.lock().unwrap(),
+            [(Some("k".to_string()), Some("read_ok".to_string()))]
+        );
+    }
+
+    #[test]
+    fn batches_are_written_one_line_per_destination() {
+        let (transport, output) = Transport::in_memory("");
+        let network: Network =
+            Network::with_transport(transport).with_reply_batching(Duration::from_secs(60));
+        for dst in ["c1", "c2", "c1"] {
+            network.send(ping(dst)).unwrap();
+        }
+        assert!(output.lines().is_empty());
+
+        network.flush().unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        let to_c1 = lines[0].as_array().expect("c1's two messages as an array");
+        assert_eq!(to_c1.len(), 2);
+        assert!(to_c1.iter().all(|message| message["dest"] == "c1"));
+        assert_eq!(lines[1]["dest"], "c2");
+        assert_eq!(lines[1]["body"]["type"], "ping");
+    }
+
+    struct BrokenPipe;
+
+    impl std::io::Write for BrokenPipe {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_batch_write_is_reported_by_the_next_send() {
+        let transport = Transport::from_io(std::io::empty(), BrokenPipe);
+        let network: Network =
+            Network::with_transport(transport).with_reply_batching(Duration::from_secs(60));
+        network.send(ping("c1")).unwrap();
+
+        network.flush_batch();
+        assert!(network.send(ping("c1")).is_err());
+        assert!(network.send(ping("c1")).is_ok());
+    }
+
+    #[test]
+    fn reregistering_a_scoped_id_leaves_later_ids_unscoped() {
+        let (network, _) = network();
+        let scoped = network.id_scope().next_message_id();
+        assert_ne!(scoped >> SCOPE_SHIFT, 0);
+        let descriptor = RequestDescriptor {
+            kind: "read".to_string(),
+            key: None,
+            sent_at: Instant::now(),
+        };
+        network.reregister_pending(vec![(scoped, descriptor)]);
+
+        let next = network.next_message_id();
+        assert_eq!(next >> SCOPE_SHIFT, 0);
+        assert!(next > scoped & ID_COUNTER_MASK);
+    }
+
+    #[tokio::test]
+    async fn message_ids_are_checkpointed_on_the_network_clock() {
+        let clock = Arc::new(MockClock::new());
+        let store = crate::mock_store::MockStore::new();
+        let interval = Duration::from_secs(1);
+        let (transport, _) = Transport::in_memory("");
+        let network: Network = store.install(
+            Network::with_transport(transport)
+                .with_clock(clock.clone())
+                .with_id_checkpoint(interval),
+        );
+        network
+            .set_init(Init {
+                node_id: "n1".to_string(),
+                node_ids: vec!["n1".to_string()],
+            })
+            .unwrap();
+        let dispatcher = network.clone();
+        tokio::spawn(
+            async move { while dispatcher.recv::<serde_json::Value>().await.is_some() {} },
+        );
+        let persisting = network.clone();
+        let persistence = tokio::spawn(async move { persisting.persist_message_ids().await });
+
+        let checkpoint = || store.value("seq-kv", "msg-id/n1");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(checkpoint(), None);
+
+        let reserved = *network.message_id.read().unwrap() + ID_CHECKPOINT_HEADROOM;
+        clock.advance(interval);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(checkpoint(), Some(serde_json::json!(reserved)));
+
+        network.shutdown();
+        clock.advance(interval);
+        persistence.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_message_type_is_dropped_when_every_type_is_handled() {
+        // No handled types means every type reaches the node, so the payload
+        // type is the first thing to reject the unknown one.
+        let (network, _) = network();
+        for payload in [
+            serde_json::json!({ "type": "surprise" }),
+            serde_json::json!({ "type": "ping" }),
+        ] {
+            let message: Message<serde_json::Value> = Message::new("n2", "n1", payload);
+            network
+                .tx
+                .send(NetworkEvent::Message(message.into()))
+                .unwrap();
+        }
+
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum Payload {
+            Ping,
+        }
+        let Some(Event::Message(message)) = network.recv::<Payload>().await else {
+            panic!("expected the ping");
+        };
+        assert_eq!(message.body.payload, Payload::Ping);
     }
 }
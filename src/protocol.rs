@@ -1,3 +1,5 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -7,11 +9,101 @@ pub struct Init {
     pub node_ids: Vec<String>,
 }
 
+// Replicas forward every mutation to the primary and only serve reads
+// themselves. The first node in the cluster is the primary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeRole {
+    Primary,
+    Replica { primary: String },
+}
+
+impl NodeRole {
+    pub fn from_init(init: &Init) -> Self {
+        match init.node_ids.first() {
+            Some(primary) if *primary != init.node_id => Self::Replica {
+                primary: primary.clone(),
+            },
+            _ => Self::Primary,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum InitPayload {
     Init(Init),
-    InitOk,
+    InitOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<serde_json::Value>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Other(usize),
+}
+
+impl From<usize> for ErrorCode {
+    fn from(code: usize) -> Self {
+        match code {
+            0 => Self::Timeout,
+            1 => Self::NodeNotFound,
+            10 => Self::NotSupported,
+            11 => Self::TemporarilyUnavailable,
+            12 => Self::MalformedRequest,
+            13 => Self::Crash,
+            14 => Self::Abort,
+            20 => Self::KeyDoesNotExist,
+            21 => Self::KeyAlreadyExists,
+            22 => Self::PreconditionFailed,
+            30 => Self::TxnConflict,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for usize {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+// The body of an `error` message, whoever sent it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MaelstromError {
+    pub code: usize,
+    #[serde(default)]
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn error_code(&self) -> ErrorCode {
+        self.code.into()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -31,3 +123,161 @@ pub struct UntypedMessage {
     pub dst: String,
     pub body: UntypedBody,
 }
+
+impl UntypedMessage {
+    pub fn kind(&self) -> Option<&str> {
+        self.body.payload.get("type").and_then(|t| t.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(transparent)]
+pub struct Topology {
+    graph: HashMap<String, Vec<String>>,
+}
+
+impl From<HashMap<String, Vec<String>>> for Topology {
+    fn from(graph: HashMap<String, Vec<String>>) -> Self {
+        Self { graph }
+    }
+}
+
+// A topology the nodes can build for themselves from the node ids in `init`,
+// without waiting for Maelstrom's `topology` message. `Binary` links node `i`
+// to its parent `(i - 1) / 2` and children `2i + 1`, `2i + 2`; `Grid` lays
+// the nodes out row by row on a square of side `ceil(sqrt(n))` and links each
+// to the node above, below, left and right of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TreeShape {
+    #[default]
+    Binary,
+    Grid,
+}
+
+impl Topology {
+    // Every node must pass the same ids in the same order to agree on the
+    // result. `init.node_ids` is identical across the cluster.
+    pub fn spanning(shape: TreeShape, node_ids: &[String]) -> Self {
+        let n = node_ids.len();
+        let links = |i: usize| -> Vec<usize> {
+            match shape {
+                TreeShape::Binary => {
+                    let parent = (i > 0).then(|| (i - 1) / 2);
+                    parent
+                        .into_iter()
+                        .chain([2 * i + 1, 2 * i + 2])
+                        .filter(|j| *j < n)
+                        .collect()
+                }
+                TreeShape::Grid => {
+                    let side = (1..).find(|side| side * side >= n).unwrap_or(1);
+                    let (row, col) = (i / side, i % side);
+                    let mut links = Vec::new();
+                    if row > 0 {
+                        links.push(i - side);
+                    }
+                    if col > 0 {
+                        links.push(i - 1);
+                    }
+                    if col + 1 < side {
+                        links.push(i + 1);
+                    }
+                    links.push(i + side);
+                    links.retain(|j| *j < n);
+                    links
+                }
+            }
+        };
+
+        let graph = node_ids
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let neighbors = links(i).into_iter().map(|j| node_ids[j].clone());
+                (node.clone(), neighbors.collect())
+            })
+            .collect();
+        Self { graph }
+    }
+
+    // Where a message that arrived from `from` goes next: every neighbor but
+    // the one it came from. On a tree this reaches each node exactly once.
+    pub fn forward_targets<'a>(&'a self, node: &str, from: &str) -> Vec<&'a String> {
+        self.neighbors(node)
+            .iter()
+            .filter(|neighbor| neighbor.as_str() != from)
+            .collect()
+    }
+
+    pub fn neighbors(&self, node: &str) -> &[String] {
+        self.graph.get(node).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn degree(&self, node: &str) -> usize {
+        self.neighbors(node).len()
+    }
+
+    // Every node mentioned in the graph, either as a key or as a neighbor,
+    // must be reachable from every other. Links count both ways, since a
+    // node gossips with whoever lists it as much as with whoever it lists.
+    pub fn is_connected(&self) -> bool {
+        let mut links: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (node, neighbors) in &self.graph {
+            links.entry(node).or_default();
+            for neighbor in neighbors {
+                links.entry(node).or_default().push(neighbor);
+                links.entry(neighbor).or_default().push(node);
+            }
+        }
+
+        // The smallest id, so the walk doesn't depend on hash order.
+        let Some(start) = links.keys().next().copied() else {
+            return true;
+        };
+
+        let mut seen = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            for neighbor in &links[node] {
+                if seen.insert(neighbor) {
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+
+        seen.len() == links.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(links: &[(&str, &[&str])]) -> Topology {
+        let graph = links
+            .iter()
+            .map(|(node, neighbors)| {
+                let neighbors = neighbors.iter().map(|n| n.to_string()).collect();
+                (node.to_string(), neighbors)
+            })
+            .collect::<HashMap<_, _>>();
+        Topology::from(graph)
+    }
+
+    #[test]
+    fn one_way_links_connect_both_ends() {
+        // n3 lists nobody, so a walk that followed links one way and started
+        // there would find nothing else.
+        let chain = topology(&[("n1", &["n2"]), ("n2", &["n3"]), ("n3", &[])]);
+        assert!(chain.is_connected());
+        let only_listed = topology(&[("n2", &["n1"]), ("n3", &["n1"])]);
+        assert!(only_listed.is_connected());
+    }
+
+    #[test]
+    fn separate_groups_are_not_connected() {
+        let split = topology(&[("n1", &["n2"]), ("n3", &["n4"])]);
+        assert!(!split.is_connected());
+        assert!(topology(&[]).is_connected());
+    }
+}
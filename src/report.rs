@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// One JSON line written to stderr when the server shuts down. The network
+// fills in the common counters; a node adds its own through `Node::report`.
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeReport {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    pub node_id: String,
+    pub requests: usize,
+    pub timeouts: usize,
+    #[serde(flatten)]
+    pub metrics: BTreeMap<String, serde_json::Value>,
+}
+
+impl NodeReport {
+    pub fn new(node_id: String) -> Self {
+        Self {
+            kind: "node_report",
+            node_id,
+            requests: 0,
+            timeouts: 0,
+            metrics: BTreeMap::new(),
+        }
+    }
+
+    pub fn record<T: Serialize>(&mut self, name: &str, value: T) {
+        let value = serde_json::to_value(value).unwrap_or_default();
+        self.metrics.insert(name.to_string(), value);
+    }
+}
+
+// Sent back as `metrics` in reply to a `metrics` request while the node
+// runs. Unlike the report and `debug_dump` its shape is fixed, so tooling
+// can scrape it; a field the node has nothing for is left out.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub node_id: String,
+    pub requests: usize,
+    pub timeouts: usize,
+    pub sent_by_type: BTreeMap<String, usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cas_failures: Option<usize>,
+    // Entries held in each of the node's caches, by cache name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cache_sizes: BTreeMap<String, usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub converged: Option<bool>,
+}
@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::{error::Error, protocol::ErrorCode};
+
+#[derive(Debug, Clone, Copy)]
+pub enum RetryStrategy {
+    // Retry straight away.
+    Immediate,
+    // Sleep around `base` before every attempt.
+    Jittered { base: Duration },
+    // Sleep around `base`, shrinking with every failure down to `min`, so a
+    // writer that keeps losing the race retries sooner than fresh writers.
+    Fair { base: Duration, min: Duration },
+}
+
+impl Default for RetryStrategy {
+    fn default() -> Self {
+        Self::Fair {
+            base: Duration::from_millis(20),
+            min: Duration::from_millis(1),
+        }
+    }
+}
+
+// How a retry loop behaves: how long it waits between attempts, how many it
+// makes, and which failures are worth another one. Set once on the network
+// (`ServerBuilder::retry_policy`) and picked up by every loop that retries.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub strategy: RetryStrategy,
+    // Caps every delay the strategy comes up with, jitter included.
+    pub max_backoff: Duration,
+    // Attempts in total, the first included. `None` retries until it lands.
+    pub max_attempts: Option<u32>,
+    // Failures carrying one of these codes are retried; any other error
+    // ends the loop.
+    pub retryable: Vec<ErrorCode>,
+}
+
+impl RetryPolicy {
+    // Retries timeouts, temporary unavailability and lost CAS races, the
+    // failures Maelstrom's services expect a client to try again after.
+    pub fn maelstrom_default() -> Self {
+        Self {
+            strategy: RetryStrategy::default(),
+            max_backoff: Duration::from_secs(1),
+            max_attempts: None,
+            retryable: vec![
+                ErrorCode::Timeout,
+                ErrorCode::TemporarilyUnavailable,
+                ErrorCode::PreconditionFailed,
+            ],
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: RetryStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn with_retryable(mut self, retryable: Vec<ErrorCode>) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    pub fn is_retryable(&self, code: ErrorCode) -> bool {
+        self.retryable.contains(&code)
+    }
+
+    // Whether `error` carries a retryable code.
+    pub fn retries(&self, error: &Error) -> bool {
+        error.code().is_some_and(|code| self.is_retryable(code))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::maelstrom_default()
+    }
+}
+
+// Tracks the attempts of a single retry loop.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    policy: RetryPolicy,
+    failures: u32,
+}
+
+impl Retry {
+    pub fn new(strategy: RetryStrategy) -> Self {
+        Self::with_policy(RetryPolicy::default().with_strategy(strategy))
+    }
+
+    pub fn with_policy(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    // Whether the attempt that just failed was the last one the policy
+    // allows.
+    pub fn exhausted(&self) -> bool {
+        self.policy
+            .max_attempts
+            .is_some_and(|max| self.failures + 1 >= max)
+    }
+
+    // Call after a failed attempt, before `backoff`: true when `error` is
+    // retryable and attempts remain.
+    pub fn should_retry(&self, error: &Error) -> bool {
+        !self.exhausted() && self.policy.retries(error)
+    }
+
+    pub fn next_delay(&self) -> Duration {
+        let delay = match self.policy.strategy {
+            RetryStrategy::Immediate => Duration::ZERO,
+            RetryStrategy::Jittered { base } => jitter(base),
+            RetryStrategy::Fair { base, min } => jitter((base / self.failures.max(1)).max(min)),
+        };
+        delay.min(self.policy.max_backoff)
+    }
+
+    // Records a failed attempt and waits before the next one.
+    pub async fn backoff(&mut self) {
+        self.failures += 1;
+        let delay = self.next_delay();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
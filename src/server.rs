@@ -1,18 +1,39 @@
-use std::fmt::Debug;
+use std::{
+    any::Any, collections::HashMap, fmt::Debug, fs::File, io::Cursor, marker::PhantomData,
+    panic::AssertUnwindSafe, path::Path, sync::Arc, time::Duration,
+};
 
 use anyhow::Context;
 use serde::de::DeserializeOwned;
 
-use crate::protocol::InitPayload;
-use crate::Message;
+use crate::clock::Clock;
+use crate::codec::WireFormat;
+use crate::erased::{DynNode, ErasedNode};
+use crate::error::Error;
+use crate::fault::FaultInjector;
+use crate::mock_store::MockStore;
+use crate::network::{EventId, Received};
+use crate::network::{MessageFilter, Network};
+use crate::protocol::{ErrorCode, Init, InitPayload, UntypedMessage};
+use crate::retry::RetryPolicy;
+use crate::transport::{Transport, DEFAULT_READ_CAPACITY};
+use crate::{Event, Message};
 
 pub struct Server<IP = ()>
 where
     IP: Clone,
 {
     network: crate::network::Network<IP>,
+    ordered_sources: bool,
+    idle_shutdown: Option<Duration>,
+    init_timeout: Option<Duration>,
 }
 
+// How long `Node::finalize` gets before serving ends without it.
+pub const FINALIZE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type SourceQueue<P, IP> = tokio::sync::mpsc::UnboundedSender<(Option<EventId>, Event<P, IP>)>;
+
 impl<IP> Default for Server<IP>
 where
     IP: Debug + Clone + Send + Sync + 'static,
@@ -20,6 +41,374 @@ where
     fn default() -> Self {
         Self {
             network: crate::network::Network::new(),
+            ordered_sources: false,
+            idle_shutdown: None,
+            init_timeout: None,
+        }
+    }
+}
+
+// Flushes whatever output is still buffered however `serve` exits, including
+// while unwinding from a panic.
+struct FlushGuard<IP> {
+    network: Network<IP>,
+}
+
+impl<IP> Drop for FlushGuard<IP> {
+    fn drop(&mut self) {
+        if let Err(e) = self.network.flush() {
+            eprintln!("failed to flush output on exit: {:#}", e);
+        }
+    }
+}
+
+// Held while `serve` takes events. `finish` ends serving: the steps still in
+// flight complete, then the node's `finalize` runs and the shutdown report is
+// written from the node it leaves, with replies to their requests settled
+// throughout, which is work `Drop` can't await. However serving ends, dropping
+// the guard shuts the network down.
+struct ShutdownGuard<NODE, IP>
+where
+    IP: Debug + Clone + Send + Sync + 'static,
+{
+    node: Arc<std::sync::Mutex<NODE>>,
+    network: Network<IP>,
+}
+
+impl<NODE, IP> ShutdownGuard<NODE, IP>
+where
+    IP: Debug + Clone + Send + Sync + 'static,
+{
+    async fn finish<PAYLOAD>(self, steps: tokio::task::JoinSet<anyhow::Result<()>>)
+    where
+        PAYLOAD: DeserializeOwned + Send + 'static,
+        NODE: crate::Node<PAYLOAD, IP> + Send + Clone,
+    {
+        let finished = async {
+            steps.join_all().await;
+            let mut node = self.node.lock().unwrap().clone();
+            match tokio::time::timeout(FINALIZE_TIMEOUT, node.finalize(&self.network)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("finalize failed: {:#}", e),
+                Err(_) => eprintln!("finalize timed out after {:?}", FINALIZE_TIMEOUT),
+            }
+            node
+        };
+        tokio::pin!(finished);
+        // Only replies are wanted now; anything else that arrives is dropped.
+        let node = loop {
+            tokio::select! {
+                node = &mut finished => break node,
+                _ = self.network.recv_logged::<PAYLOAD>() => {}
+            }
+        };
+
+        let mut report = self.network.report();
+        node.report(&mut report);
+        match serde_json::to_string(&report) {
+            Ok(line) => eprintln!("{}", line),
+            Err(e) => eprintln!("failed to serialize node report: {:#}", e),
+        }
+    }
+}
+
+impl<NODE, IP> Drop for ShutdownGuard<NODE, IP>
+where
+    IP: Debug + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.network.shutdown();
+    }
+}
+
+pub struct ServerBuilder<IP = ()> {
+    transport: Option<Transport>,
+    read_buffer: usize,
+    dedup_window: Option<usize>,
+    event_buffer: Option<usize>,
+    tracing: bool,
+    auto_health: bool,
+    debug_endpoint: bool,
+    metrics_endpoint: bool,
+    max_in_flight: Option<usize>,
+    keepalive: Option<Duration>,
+    id_checkpoint: Option<Duration>,
+    format: WireFormat,
+    event_log: bool,
+    ordered_sources: bool,
+    idle_shutdown: Option<Duration>,
+    init_timeout: Option<Duration>,
+    clock: Option<Arc<dyn Clock>>,
+    retry_policy: Option<RetryPolicy>,
+    inbound_filters: Vec<MessageFilter>,
+    outbound_filters: Vec<MessageFilter>,
+    faults: Option<FaultInjector>,
+    mock_store: Option<MockStore>,
+    reply_batching: Option<Duration>,
+    record: Option<File>,
+    replay: Option<Cursor<Vec<u8>>>,
+    _injected: PhantomData<IP>,
+}
+
+impl<IP> Default for ServerBuilder<IP> {
+    fn default() -> Self {
+        Self {
+            transport: None,
+            read_buffer: DEFAULT_READ_CAPACITY,
+            dedup_window: None,
+            event_buffer: None,
+            tracing: false,
+            auto_health: true,
+            debug_endpoint: false,
+            metrics_endpoint: false,
+            max_in_flight: None,
+            keepalive: None,
+            id_checkpoint: None,
+            format: WireFormat::default(),
+            event_log: false,
+            ordered_sources: false,
+            idle_shutdown: None,
+            init_timeout: None,
+            clock: None,
+            retry_policy: None,
+            inbound_filters: Vec::new(),
+            outbound_filters: Vec::new(),
+            faults: None,
+            mock_store: None,
+            reply_batching: None,
+            record: None,
+            replay: None,
+            _injected: PhantomData,
+        }
+    }
+}
+
+impl<IP> ServerBuilder<IP>
+where
+    IP: Debug + Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    // Capacity of the buffer wrapping stdin; ignored for a custom transport.
+    pub fn read_buffer(mut self, capacity: usize) -> Self {
+        self.read_buffer = capacity;
+        self
+    }
+
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    pub fn event_buffer(mut self, capacity: usize) -> Self {
+        self.event_buffer = Some(capacity);
+        self
+    }
+
+    pub fn tracing(mut self, tracing: bool) -> Self {
+        self.tracing = tracing;
+        self
+    }
+
+    pub fn auto_health(mut self, auto_health: bool) -> Self {
+        self.auto_health = auto_health;
+        self
+    }
+
+    pub fn debug_endpoint(mut self, debug_endpoint: bool) -> Self {
+        self.debug_endpoint = debug_endpoint;
+        self
+    }
+
+    pub fn metrics_endpoint(mut self, metrics_endpoint: bool) -> Self {
+        self.metrics_endpoint = metrics_endpoint;
+        self
+    }
+
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    // Maelstrom only speaks JSON; other formats need a custom transport.
+    pub fn format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    // Defaults to `RetryPolicy::maelstrom_default`.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    // Steps events from the same source one at a time, in arrival order, while
+    // different sources still run concurrently. A step that waits on a peer
+    // which first needs this node to handle another of its messages
+    // deadlocks, since that message queues behind the waiting step.
+    pub fn ordered_per_source(mut self, ordered_sources: bool) -> Self {
+        self.ordered_sources = ordered_sources;
+        self
+    }
+
+    // Stops serving once no event has arrived for `window` on the network's
+    // clock, for transports that never close. Off by default, which waits
+    // forever.
+    pub fn idle_shutdown(mut self, window: Duration) -> Self {
+        self.idle_shutdown = Some(window);
+        self
+    }
+
+    // Fails `serve` if no init message has arrived within `window`, rather
+    // than waiting forever on a misconfigured harness. Off by default.
+    pub fn init_timeout(mut self, window: Duration) -> Self {
+        self.init_timeout = Some(window);
+        self
+    }
+
+    pub fn event_log(mut self, event_log: bool) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    // Off by default.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    // Off by default; see `Network::with_id_checkpoint` for the tradeoff.
+    pub fn persist_message_ids(mut self, interval: Duration) -> Self {
+        self.id_checkpoint = Some(interval);
+        self
+    }
+
+    // Filters run in the order they're added; any of them returning `None`
+    // drops the message. Useful for logging or injecting faults locally.
+    pub fn inbound_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(UntypedMessage) -> Option<UntypedMessage> + Send + Sync + 'static,
+    {
+        self.inbound_filters.push(Arc::new(filter));
+        self
+    }
+
+    pub fn outbound_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(UntypedMessage) -> Option<UntypedMessage> + Send + Sync + 'static,
+    {
+        self.outbound_filters.push(Arc::new(filter));
+        self
+    }
+
+    // Writes outbound messages at most once per `window`, those to the same
+    // destination on one line as a JSON array. Off by default.
+    pub fn reply_batching(mut self, window: Duration) -> Self {
+        self.reply_batching = Some(window);
+        self
+    }
+
+    // Drops or delays the outbound messages `faults` picks out. Runs after
+    // the other outbound filters and before a mock store, so storage requests
+    // can be lost too, and delayed ones still reach the mock store.
+    pub fn faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    // Answers storage requests from `store` instead of Maelstrom's services.
+    pub fn mock_store(mut self, store: MockStore) -> Self {
+        self.mock_store = Some(store);
+        self
+    }
+
+    pub fn record_to(mut self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).context(format!("creating recording {}", path.display()))?;
+        self.record = Some(file);
+        Ok(self)
+    }
+
+    pub fn replay_from(mut self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        self.replay = Some(Transport::load_recording(path.as_ref())?);
+        Ok(self)
+    }
+
+    pub fn build(self) -> Server<IP> {
+        let mut transport = self
+            .transport
+            .unwrap_or_else(|| Transport::stdio_with_capacity(self.read_buffer));
+        if let Some(replay) = self.replay {
+            transport = transport.with_reader(replay);
+        }
+        if let Some(record) = self.record {
+            transport = transport.with_recorder(record);
+        }
+
+        let mut network = Network::with_transport(transport)
+            .with_tracing(self.tracing)
+            .with_auto_health(self.auto_health)
+            .with_debug_endpoint(self.debug_endpoint)
+            .with_metrics_endpoint(self.metrics_endpoint)
+            .with_format(self.format);
+        if let Some(window) = self.dedup_window {
+            network = network.with_dedup_window(window);
+        }
+        if let Some(max_in_flight) = self.max_in_flight {
+            network = network.with_max_in_flight(max_in_flight);
+        }
+        if let Some(capacity) = self.event_buffer {
+            network = network.with_event_buffer(capacity);
+        }
+        if let Some(clock) = self.clock {
+            network = network.with_clock(clock);
+        }
+        if let Some(policy) = self.retry_policy {
+            network = network.with_retry_policy(policy);
+        }
+        if self.event_log {
+            network = network.with_event_log();
+        }
+        if let Some(interval) = self.keepalive {
+            network = network.with_keepalive(interval);
+        }
+        for filter in self.inbound_filters {
+            network = network.with_inbound_filter(filter);
+        }
+        for filter in self.outbound_filters {
+            network = network.with_outbound_filter(filter);
+        }
+        if let Some(window) = self.reply_batching {
+            network = network.with_reply_batching(window);
+        }
+        if let Some(interval) = self.id_checkpoint {
+            network = network.with_id_checkpoint(interval);
+        }
+
+        if let Some(faults) = self.faults {
+            network = faults.install(network);
+        }
+        if let Some(store) = self.mock_store {
+            network = store.install(network);
+        }
+
+        Server {
+            network,
+            ordered_sources: self.ordered_sources,
+            idle_shutdown: self.idle_shutdown,
+            init_timeout: self.init_timeout,
         }
     }
 }
@@ -32,19 +421,68 @@ where
         Self::default()
     }
 
-    fn construct_node<NODE, PAYLOAD>(&self, init_msg: Message<InitPayload>) -> anyhow::Result<NODE>
+    pub fn builder() -> ServerBuilder<IP> {
+        ServerBuilder::new()
+    }
+
+    fn construct_node<NODE, PAYLOAD, F>(
+        &self,
+        init_msg: Message<InitPayload>,
+        construct: F,
+    ) -> anyhow::Result<NODE>
     where
         NODE: crate::Node<PAYLOAD, IP>,
+        F: FnOnce(Init, &Network<IP>) -> anyhow::Result<NODE>,
     {
         let InitPayload::Init(init) = init_msg.body.payload.clone() else {
             panic!("first message was not an init");
         };
 
-        let node = NODE::from_init(init, &self.network.clone());
+        self.network
+            .set_init(init.clone())
+            .context("storing init message")?;
+        self.network.track_peers(
+            init.node_ids
+                .iter()
+                .filter(|id| **id != init.node_id)
+                .cloned(),
+        );
+        // A panic here would otherwise leave Maelstrom waiting on an init_ok
+        // that never comes, so it's answered with an error like any failure.
+        let network = self.network.clone();
+        let constructed = std::panic::catch_unwind(AssertUnwindSafe(|| construct(init, &network)))
+            .unwrap_or_else(|panic| {
+                Err(anyhow::anyhow!(
+                    "node panicked during init: {}",
+                    panic_message(panic.as_ref())
+                ))
+            });
+
+        let node = match constructed {
+            Ok(node) => node,
+            Err(e) => {
+                let error = serde_json::json!({
+                    "type": "error",
+                    "code": usize::from(ErrorCode::Crash),
+                    "text": format!("{:#}", e),
+                });
+                let reply = Message::new(init_msg.dst.clone(), init_msg.src.clone(), error)
+                    .with_in_reply_to(init_msg.body.id);
+                if let Err(send_error) = self.network.send(reply) {
+                    eprintln!("failed to report init failure: {:#}", send_error);
+                }
+                return Err(e).context("initializing node from init message");
+            }
+        };
 
         let mut reply = init_msg.into_reply();
-        reply.body.payload = InitPayload::InitOk;
+        reply.body.payload = InitPayload::InitOk {
+            capabilities: node.capabilities(),
+        };
         self.network.send(reply).context("sending init_ok")?;
+        // Maelstrom wants init_ok out before anything else the node writes,
+        // whatever the transport's buffering.
+        self.network.flush().context("flushing init_ok")?;
 
         Ok(node)
     }
@@ -55,29 +493,654 @@ where
         PAYLOAD: DeserializeOwned + Send + 'static,
         NODE: crate::Node<PAYLOAD, IP> + Send + Clone + 'static,
     {
-        let init_msg = self
-            .network
-            .read::<InitPayload>()
-            .context("reading init message")?;
+        self.run::<NODE, PAYLOAD, _, _>(NODE::from_init, |_| NODE::handled_types())
+            .await
+    }
+
+    // Like `serve`, but the node is built by `construct` rather than
+    // `Node::from_init`, e.g. to set options on it.
+    #[tokio::main]
+    pub async fn serve_with<NODE, PAYLOAD, F>(&mut self, construct: F) -> anyhow::Result<()>
+    where
+        PAYLOAD: DeserializeOwned + Send + 'static,
+        NODE: crate::Node<PAYLOAD, IP> + Send + Clone + 'static,
+        F: FnOnce(Init, &Network<IP>) -> anyhow::Result<NODE>,
+    {
+        self.run::<NODE, PAYLOAD, _, _>(construct, |_| NODE::handled_types())
+            .await
+    }
+
+    // Like `serve`, but the node is picked at runtime: `factory` sees the init
+    // message and returns any node wrapped with `erased::erase`. See
+    // `ErasedNode` for what the type erasure costs.
+    #[tokio::main]
+    pub async fn serve_dyn<F>(&mut self, factory: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(Init, &Network<IP>) -> anyhow::Result<Box<dyn ErasedNode<IP>>>,
+    {
+        self.run::<DynNode<IP>, serde_json::Value, _, _>(
+            |init, network| factory(init, network).map(DynNode),
+            |node| node.0.handled_types(),
+        )
+        .await
+    }
+
+    // Reading can't be cancelled, so with a timeout it happens on a thread of
+    // its own, timed on the network's clock. If the timeout fires the thread
+    // stays blocked on input until a line or the end of input frees it, but it
+    // holds nothing of the server's by then.
+    async fn read_init(&mut self) -> anyhow::Result<Message<InitPayload>> {
+        let Some(window) = self.init_timeout else {
+            return Ok(self.network.read::<InitPayload>()?);
+        };
+
+        let line = tokio::select! {
+            read = self.network.spawn_init_reader() => {
+                read.context("init reader stopped without a result")??
+            }
+            _ = self.network.clock().delay(window) => {
+                return Err(Error::Timeout(format!("no init message within {:?}", window)).into());
+            }
+        };
+        Ok(self.network.decode_init(&line)?)
+    }
+
+    async fn run<NODE, PAYLOAD, F, H>(
+        &mut self,
+        construct: F,
+        handled_types: H,
+    ) -> anyhow::Result<()>
+    where
+        PAYLOAD: DeserializeOwned + Send + 'static,
+        NODE: crate::Node<PAYLOAD, IP> + Send + Clone + 'static,
+        F: FnOnce(Init, &Network<IP>) -> anyhow::Result<NODE>,
+        H: FnOnce(&NODE) -> &'static [&'static str],
+    {
+        let _flush = FlushGuard {
+            network: self.network.clone(),
+        };
+
+        let init_msg = self.read_init().await.context("reading init message")?;
         let node: NODE = self
-            .construct_node(init_msg)
+            .construct_node(init_msg, construct)
             .context("constructing node from init message")?;
 
+        self.network.set_handled_types(handled_types(&node));
+        // Shared with the hooks, so a dump or metrics reflect what a
+        // reconfigure changed in the node itself, not just in state its
+        // clones share.
+        let node = Arc::new(std::sync::Mutex::new(node));
+        let dumped = node.clone();
+        self.network
+            .on_debug_dump(move || dumped.lock().unwrap().debug_dump());
+        let metered = node.clone();
+        self.network
+            .on_metrics(move |network| metered.lock().unwrap().metrics(network));
         let jh = self.network.start_read_thread();
+        self.network.start_keepalive();
+        self.network.start_batch_flusher();
 
+        let guard = ShutdownGuard {
+            node: node.clone(),
+            network: self.network.clone(),
+        };
+        // Not one of the steps finishing waits for; it's aborted once
+        // everything else is done, rather than left to notice the shutdown at
+        // its next checkpoint.
+        let network = self.network.clone();
+        let persistence = tokio::spawn(async move {
+            if let Err(e) = network.persist_message_ids().await {
+                eprintln!("message id persistence stopped: {:#}", e);
+            }
+        });
         let mut js = tokio::task::JoinSet::new();
-        while let Some(event) = self.network.recv::<PAYLOAD>().await {
+        let mut queues: HashMap<String, SourceQueue<PAYLOAD, IP>> = HashMap::new();
+        let mut idled = false;
+        loop {
+            let received = match self.idle_shutdown {
+                Some(window) => {
+                    let clock = self.network.clock();
+                    match crate::clock::timeout(
+                        clock.as_ref(),
+                        window,
+                        self.network.recv_received::<PAYLOAD>(),
+                    )
+                    .await
+                    {
+                        Some(received) => received,
+                        None => {
+                            eprintln!("no events for {:?}, shutting down", window);
+                            idled = true;
+                            break;
+                        }
+                    }
+                }
+                None => self.network.recv_received::<PAYLOAD>().await,
+            };
+            let (id, event) = match received {
+                Some(Received::Event(id, event)) => (id, event),
+                Some(Received::Reconfigured(node_ids)) => {
+                    let reconfigured = node
+                        .lock()
+                        .unwrap()
+                        .on_reconfigure(&node_ids, &self.network);
+                    if let Err(e) = reconfigured {
+                        eprintln!("reconfiguring to {:?} failed: {:#}", node_ids, e);
+                    }
+                    continue;
+                }
+                None => break,
+            };
+
             let network = self.network.clone();
-            let mut n = node.clone();
-            js.spawn(async move { n.step(event, &network).await });
-        }
+            let source = event.source().filter(|_| self.ordered_sources);
+            if let Some(source) = source {
+                let queue = queues.entry(source.to_string()).or_insert_with(|| {
+                    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+                    let mut n = node.lock().unwrap().clone();
+                    js.spawn(async move {
+                        while let Some((id, event)) = rx.recv().await {
+                            if let Err(e) = crate::deliver(&mut n, event, &network).await {
+                                eprintln!("step failed: {:#}", e);
+                            }
+                            if let Some(id) = id {
+                                network.ack(id);
+                            }
+                        }
+                        Ok(())
+                    });
+                    tx
+                });
+                if queue.send((id, event)).is_err() {
+                    eprintln!("dropping event for a source whose queue has stopped");
+                }
+                continue;
+            }
 
-        jh.join()
-            .expect("stdin thread panicked")
-            .context("stdin thread panicked")?;
+            let mut n = node.lock().unwrap().clone();
+            js.spawn(async move {
+                let result = crate::deliver(&mut n, event, &network).await;
+                if let Some(id) = id {
+                    network.ack(id);
+                }
+                result
+            });
+        }
+        // Closes the per-source queues, so their steps end once drained.
+        drop(queues);
+        guard.finish::<PAYLOAD>(js).await;
 
-        js.join_all().await;
+        // After going idle the read thread is still blocked on a transport
+        // that never closes, so it's left behind rather than joined.
+        if !idled {
+            jh.join()
+                .expect("stdin thread panicked")
+                .context("stdin thread panicked")?;
+        }
+        persistence.abort();
 
         Ok(())
     }
 }
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufReader, BufWriter, Read, Write},
+        sync::Mutex,
+        time::Instant,
+    };
+
+    use super::*;
+
+    // A `Vec<u8>` the test can still look at once the transport owns a writer
+    // over it.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_guard_flushes_buffered_output_while_unwinding() {
+        let sink = Sink::default();
+        let (transport, _) = Transport::in_memory("");
+        let network: Network =
+            Network::with_transport(transport.with_recorder(BufWriter::new(sink.clone())));
+
+        let crashed = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _flush = FlushGuard {
+                network: network.clone(),
+            };
+            let reply = Message::new("n1", "c1", serde_json::json!({ "type": "echo_ok" }));
+            network.send(reply).unwrap();
+            // Still sitting in the recorder's buffer.
+            assert!(sink.0.lock().unwrap().is_empty());
+            panic!("node crashed");
+        }));
+
+        assert!(crashed.is_err());
+        let recorded = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert!(recorded.contains(r#""type":"echo_ok""#), "{}", recorded);
+    }
+
+    // Keeps the cluster it was last given in a field of its own, which no
+    // clone shares.
+    #[derive(Clone)]
+    struct MembersNode {
+        node_ids: Vec<String>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::Node<serde_json::Value> for MembersNode {
+        fn from_init(init: Init, _network: &Network) -> anyhow::Result<Self> {
+            Ok(Self {
+                node_ids: init.node_ids,
+            })
+        }
+
+        fn debug_dump(&self) -> serde_json::Value {
+            serde_json::json!({ "node_ids": self.node_ids })
+        }
+
+        fn on_reconfigure(
+            &mut self,
+            node_ids: &[String],
+            _network: &Network,
+        ) -> anyhow::Result<()> {
+            self.node_ids = node_ids.to_vec();
+            Ok(())
+        }
+
+        async fn step(
+            &mut self,
+            _event: crate::Event<serde_json::Value>,
+            _network: &Network,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn metrics_replies(metrics_endpoint: bool) -> Vec<UntypedMessage> {
+        let input = [
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c0","dest":"n1","body":{"type":"metrics","msg_id":2}}"#,
+        ]
+        .join("\n");
+        let (transport, output) = Transport::in_memory(input + "\n");
+        Server::<()>::builder()
+            .transport(transport)
+            .metrics_endpoint(metrics_endpoint)
+            .build()
+            .serve::<MembersNode, serde_json::Value>()
+            .unwrap();
+
+        output
+            .lines()
+            .into_iter()
+            .map(|line| serde_json::from_str::<UntypedMessage>(&line).unwrap())
+            .filter(|message| message.kind() == Some("metrics_ok"))
+            .collect()
+    }
+
+    #[test]
+    fn metrics_endpoint_replies_with_the_fixed_schema_only_when_on() {
+        assert!(metrics_replies(false).is_empty());
+
+        let replies = metrics_replies(true);
+        let [reply] = &replies[..] else {
+            panic!("expected one metrics_ok, got {:?}", replies);
+        };
+        assert_eq!(reply.body.in_reply_to, Some(2));
+        let metrics = &reply.body.payload["metrics"];
+        let mut fields: Vec<&str> = metrics
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort();
+        assert_eq!(fields, ["node_id", "requests", "sent_by_type", "timeouts"]);
+        let metrics: crate::report::Metrics = serde_json::from_value(metrics.clone()).unwrap();
+        assert_eq!(metrics.node_id, "n1");
+        assert_eq!(metrics.sent_by_type["init_ok"], 1);
+    }
+
+    #[test]
+    fn debug_dump_sees_the_node_as_reconfigured() {
+        let input = [
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c0","dest":"n1","body":{"type":"reconfigure","msg_id":2,"node_ids":["n1","n2"]}}"#,
+            r#"{"src":"c0","dest":"n1","body":{"type":"debug_dump","msg_id":3}}"#,
+        ]
+        .join("\n");
+        let (transport, output) = Transport::in_memory(input + "\n");
+        Server::<()>::builder()
+            .transport(transport)
+            .debug_endpoint(true)
+            .build()
+            .serve::<MembersNode, serde_json::Value>()
+            .unwrap();
+
+        let dump = output
+            .lines()
+            .into_iter()
+            .map(|line| serde_json::from_str::<UntypedMessage>(&line).unwrap())
+            .find(|message| message.kind() == Some("debug_dump_ok"))
+            .expect("no debug dump in the output");
+        assert_eq!(
+            dump.body.payload["state"]["node_ids"],
+            serde_json::json!(["n1", "n2"])
+        );
+    }
+
+    // Records the storage messages it's handed outside of any request.
+    #[derive(Clone)]
+    struct StorageNode {
+        stored: Arc<Mutex<Vec<crate::service::StoragePayload>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::Node<serde_json::Value> for StorageNode {
+        fn from_init(_init: Init, _network: &Network) -> anyhow::Result<Self> {
+            unreachable!("built with serve_with")
+        }
+
+        async fn on_storage(
+            &mut self,
+            message: Message<crate::service::StoragePayload>,
+            _network: &Network,
+        ) -> anyhow::Result<()> {
+            self.stored.lock().unwrap().push(message.body.payload);
+            Ok(())
+        }
+
+        async fn step(
+            &mut self,
+            _event: crate::Event<serde_json::Value>,
+            _network: &Network,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn unawaited_storage_reply_reaches_on_storage() {
+        let input = [
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"lin-kv","dest":"n1","body":{"type":"write_ok","in_reply_to":99}}"#,
+        ]
+        .join("\n");
+        let (transport, _) = Transport::in_memory(input + "\n");
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let node = StorageNode {
+            stored: stored.clone(),
+        };
+        Server::<()>::builder()
+            .transport(transport)
+            .build()
+            .serve_with::<StorageNode, serde_json::Value, _>(|_, _| Ok(node))
+            .unwrap();
+
+        let stored = stored.lock().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(matches!(stored[0], crate::service::StoragePayload::WriteOk));
+    }
+
+    // Records each message as its step finishes, after sleeping for the
+    // message's `delay` in milliseconds.
+    #[derive(Clone)]
+    struct SleepyNode {
+        finished: Arc<Mutex<Vec<(String, u64)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::Node<serde_json::Value> for SleepyNode {
+        fn from_init(_init: Init, _network: &Network) -> anyhow::Result<Self> {
+            unreachable!("built with serve_with")
+        }
+
+        async fn step(
+            &mut self,
+            event: crate::Event<serde_json::Value>,
+            _network: &Network,
+        ) -> anyhow::Result<()> {
+            let crate::Event::Message(message) = event else {
+                return Ok(());
+            };
+            let delay = message.body.payload["delay"].as_u64().unwrap_or(0);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            let n = message.body.payload["n"].as_u64().unwrap_or(0);
+            self.finished.lock().unwrap().push((message.src, n));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ordered_per_source_steps_a_source_in_order_and_sources_concurrently() {
+        let input = [
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"work","msg_id":1,"n":1,"delay":100}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"work","msg_id":2,"n":2,"delay":0}}"#,
+            r#"{"src":"c2","dest":"n1","body":{"type":"work","msg_id":1,"n":3,"delay":0}}"#,
+        ]
+        .join("\n");
+        let (transport, _) = Transport::in_memory(input + "\n");
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let node = SleepyNode {
+            finished: finished.clone(),
+        };
+        Server::<()>::builder()
+            .transport(transport)
+            .ordered_per_source(true)
+            .build()
+            .serve_with::<SleepyNode, serde_json::Value, _>(|_, _| Ok(node))
+            .unwrap();
+
+        // c1's second message waits behind its slow first one, but c2's
+        // doesn't.
+        let finished = finished.lock().unwrap();
+        assert_eq!(
+            *finished,
+            vec![
+                ("c2".to_string(), 3),
+                ("c1".to_string(), 1),
+                ("c1".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn idle_shutdown_returns_when_no_events_arrive() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+        // Held open until the end, so the input never reaches EOF.
+        let (pipe, _writer) = std::io::pipe().unwrap();
+        let input = std::io::Cursor::new(format!("{}\n", init)).chain(BufReader::new(pipe));
+        let (transport, output) = Transport::in_memory("");
+        let window = Duration::from_millis(50);
+        let started = Instant::now();
+        Server::<()>::builder()
+            .transport(transport.with_reader(input))
+            .idle_shutdown(window)
+            .build()
+            .serve::<MembersNode, serde_json::Value>()
+            .unwrap();
+
+        assert!(started.elapsed() >= window);
+        assert_eq!(output.lines().len(), 1);
+    }
+
+    #[test]
+    fn init_ok_is_written_through_by_the_time_the_node_exists() {
+        let sink = Sink::default();
+        let transport = Transport::from_io(std::io::empty(), BufWriter::new(sink.clone()));
+        // Batched replies would otherwise wait out the window.
+        let server = Server::<()>::builder()
+            .transport(transport)
+            .reply_batching(Duration::from_secs(60))
+            .build();
+        let init_msg: Message<InitPayload> = serde_json::from_str(
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+        )
+        .unwrap();
+
+        let _node: MembersNode = server
+            .construct_node(
+                init_msg,
+                <MembersNode as crate::Node<serde_json::Value>>::from_init,
+            )
+            .unwrap();
+        let written = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert!(written.contains(r#""type":"init_ok""#), "{}", written);
+    }
+
+    // Counts its steps after sleeping through each, and in `finalize` stores
+    // the count in lin-kv, noting it once the write is acknowledged.
+    #[derive(Clone)]
+    struct FinalizingNode {
+        steps: Arc<Mutex<u64>>,
+        finalized: Arc<Mutex<Option<u64>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::Node<serde_json::Value> for FinalizingNode {
+        fn from_init(_init: Init, _network: &Network) -> anyhow::Result<Self> {
+            unreachable!("built with serve_with")
+        }
+
+        async fn step(
+            &mut self,
+            _event: crate::Event<serde_json::Value>,
+            _network: &Network,
+        ) -> anyhow::Result<()> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            *self.steps.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        async fn finalize(&mut self, network: &Network) -> anyhow::Result<()> {
+            use crate::service::{LinearStore, Storage};
+
+            let steps = *self.steps.lock().unwrap();
+            LinearStore::new("n1".to_string())
+                .compare_and_store_opts("steps".to_string(), 0, steps, true, network)
+                .await?;
+            *self.finalized.lock().unwrap() = Some(steps);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn finalize_sees_finished_steps_and_gets_replies_after_input_closes() {
+        let input = [
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"work","msg_id":1}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"work","msg_id":2}}"#,
+        ]
+        .join("\n");
+        let (transport, _) = Transport::in_memory(input + "\n");
+        let store = MockStore::new();
+        let finalized = Arc::new(Mutex::new(None));
+        let node = FinalizingNode {
+            steps: Arc::new(Mutex::new(0)),
+            finalized: finalized.clone(),
+        };
+        let started = Instant::now();
+        Server::<()>::builder()
+            .transport(transport)
+            .mock_store(store.clone())
+            .build()
+            .serve_with::<FinalizingNode, serde_json::Value, _>(|_, _| Ok(node))
+            .unwrap();
+
+        assert_eq!(*finalized.lock().unwrap(), Some(2));
+        assert_eq!(store.value("lin-kv", "steps"), Some(serde_json::json!(2)));
+        assert!(started.elapsed() < FINALIZE_TIMEOUT);
+    }
+
+    // Input that stays open without ever sending init, timed out on a mock
+    // clock so the hour-long window passes at once.
+    #[test]
+    fn init_timeout_gives_up_on_input_that_never_sends_init() {
+        let (pipe, writer) = std::io::pipe().unwrap();
+        let (transport, output) = Transport::in_memory("");
+        let clock = Arc::new(crate::clock::MockClock::new());
+        let window = Duration::from_secs(3600);
+        let served = std::sync::atomic::AtomicBool::new(false);
+        let result = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !served.load(std::sync::atomic::Ordering::Relaxed) {
+                    clock.advance(window);
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            });
+            let result = Server::<()>::builder()
+                .transport(transport.with_reader(BufReader::new(pipe)))
+                .clock(clock.clone())
+                .init_timeout(window)
+                .build()
+                .serve::<MembersNode, serde_json::Value>();
+            served.store(true, std::sync::atomic::Ordering::Relaxed);
+            result
+        });
+
+        let e = result.unwrap_err();
+        assert!(
+            e.chain()
+                .any(|e| matches!(e.downcast_ref::<Error>(), Some(Error::Timeout(_)))),
+            "{:#}",
+            e
+        );
+        assert!(output.lines().is_empty());
+        drop(writer);
+    }
+
+    #[test]
+    fn idle_shutdown_waits_on_the_network_clock() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+        // Held open until the end, so the input never reaches EOF.
+        let (pipe, _writer) = std::io::pipe().unwrap();
+        let input = std::io::Cursor::new(format!("{}\n", init)).chain(BufReader::new(pipe));
+        let (transport, output) = Transport::in_memory("");
+        let clock = Arc::new(crate::clock::MockClock::new());
+        // Far longer than the test could wait out in real time.
+        let window = Duration::from_secs(3600);
+        let served = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !served.load(std::sync::atomic::Ordering::Relaxed) {
+                    clock.advance(window);
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            });
+            let result = Server::<()>::builder()
+                .transport(transport.with_reader(input))
+                .clock(clock.clone())
+                .idle_shutdown(window)
+                .build()
+                .serve::<MembersNode, serde_json::Value>();
+            served.store(true, std::sync::atomic::Ordering::Relaxed);
+            result.unwrap();
+        });
+
+        assert_eq!(output.lines().len(), 1);
+    }
+}
@@ -1,9 +1,15 @@
-use std::fmt::Debug;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::Context;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{network::Network, Body, Message};
+use crate::{
+    error::Error, limiter::Priority, network::Network, protocol::ErrorCode, retry::Retry, Message,
+};
 
 pub type Entry = usize;
 
@@ -15,7 +21,14 @@ pub enum StorageType {
 
 pub const LINEAR_STORE_ADDRESS: &str = "lin-kv";
 pub const SEQUENTIAL_STORE_ADDRESS: &str = "seq-kv";
-pub const STORAGE_ADDRESSES: [&str; 2] = [LINEAR_STORE_ADDRESS, SEQUENTIAL_STORE_ADDRESS];
+pub const LWW_STORE_ADDRESS: &str = "lww-kv";
+pub const TIMESTAMP_ORACLE_ADDRESS: &str = "lin-tso";
+pub const STORAGE_ADDRESSES: [&str; 4] = [
+    LINEAR_STORE_ADDRESS,
+    SEQUENTIAL_STORE_ADDRESS,
+    LWW_STORE_ADDRESS,
+    TIMESTAMP_ORACLE_ADDRESS,
+];
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
@@ -39,12 +52,105 @@ pub enum StoragePayload {
         create_if_not_exists: Option<bool>,
     },
     CasOk,
+    Ts,
+    TsOk {
+        ts: u64,
+    },
     Error {
         code: usize,
         text: String,
     },
 }
 
+// What a storage reply means once the wire variant has been interpreted.
+#[derive(Debug, Clone)]
+pub enum StorageOutcome {
+    Read(serde_json::Value),
+    Written,
+    Swapped,
+    Timestamp(u64),
+}
+
+impl StoragePayload {
+    pub fn into_result(self) -> Result<StorageOutcome, Error> {
+        match self {
+            Self::ReadOk { value } => Ok(StorageOutcome::Read(value)),
+            Self::WriteOk => Ok(StorageOutcome::Written),
+            Self::CasOk => Ok(StorageOutcome::Swapped),
+            Self::TsOk { ts } => Ok(StorageOutcome::Timestamp(ts)),
+            Self::Error { code, text } => Err(Error::from((ErrorCode::from(code), text))),
+            request => Err(Error::protocol(format!(
+                "expected a storage reply, got {:?}",
+                request
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdempotencyToken(u64);
+
+impl Default for IdempotencyToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdempotencyToken {
+    pub fn new() -> Self {
+        Self(rand::random())
+    }
+}
+
+const RECENT_TOKENS_PER_KEY: usize = 32;
+
+// What the idempotent writes keep under a key: the value, plus the tokens of
+// the latest writes that produced it. The tokens are written in the same CAS
+// as the value, so a retry finds its token there whenever an earlier attempt
+// was applied, even one whose acknowledgement was lost.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IdempotentValue<T> {
+    pub value: T,
+    pub tokens: VecDeque<IdempotencyToken>,
+}
+
+impl<T> IdempotentValue<T> {
+    // `value` as first written, by the write carrying `token`.
+    pub fn new(value: T, token: IdempotencyToken) -> Self {
+        Self {
+            value,
+            tokens: VecDeque::from([token]),
+        }
+    }
+
+    pub fn applied(&self, token: IdempotencyToken) -> bool {
+        self.tokens.contains(&token)
+    }
+
+    // `value` as written by the write carrying `token`.
+    fn then(&self, value: T, token: IdempotencyToken) -> Self {
+        let mut tokens = self.tokens.clone();
+        if tokens.len() == RECENT_TOKENS_PER_KEY {
+            tokens.pop_front();
+        }
+        tokens.push_back(token);
+        Self { value, tokens }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CasConflict<T> {
+    pub current: T,
+}
+
+// The key a `multi_cas` lost on. `unrolled` lists the keys it had already
+// swapped but failed to swap back, which still hold what it wrote.
+#[derive(Debug, Clone)]
+pub struct KeyConflict {
+    pub key: String,
+    pub unrolled: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct SequentialStore {
     _node_id: String,
@@ -56,7 +162,10 @@ impl SequentialStore {
     }
 }
 
-impl Storage<()> for SequentialStore {
+impl<IP> Storage<IP> for SequentialStore
+where
+    IP: Send + Debug + Clone + 'static,
+{
     fn node_id(&self) -> String {
         self._node_id.clone()
     }
@@ -77,7 +186,10 @@ impl LinearStore {
     }
 }
 
-impl Storage<()> for LinearStore {
+impl<IP> Storage<IP> for LinearStore
+where
+    IP: Send + Debug + Clone + 'static,
+{
     fn node_id(&self) -> String {
         self._node_id.clone()
     }
@@ -87,6 +199,569 @@ impl Storage<()> for LinearStore {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct LwwStore {
+    _node_id: String,
+}
+
+impl LwwStore {
+    pub fn new(node_id: String) -> Self {
+        Self { _node_id: node_id }
+    }
+}
+
+impl<IP> Storage<IP> for LwwStore
+where
+    IP: Send + Debug + Clone + 'static,
+{
+    fn node_id(&self) -> String {
+        self._node_id.clone()
+    }
+
+    fn address(&self) -> String {
+        LWW_STORE_ADDRESS.to_string()
+    }
+}
+
+// Remembers what each key read as through this store and forgets a key as
+// soon as this store writes or CASes it, so this node's reads observe its own
+// mutations. Once a mutation is acknowledged the value it wrote is cached in
+// turn. Writes made by other nodes are never seen until the key is
+// invalidated by hand, so only wrap keys this node alone mutates, or ones where
+// reading stale data is harmless.
+#[derive(Debug, Clone)]
+pub struct CachedStore<S> {
+    inner: S,
+    cache: Arc<RwLock<ReadCache>>,
+}
+
+// Keys whose stamps are kept before they're all dropped for `floor`.
+const STAMPED_KEYS: usize = 1024;
+
+// Every invalidation stamps the key with the next generation. A read only
+// caches what it got back if the key hasn't been stamped since the read was
+// issued, so a read racing a mutation can't leave the old value cached behind
+// it. Keys whose stamps were dropped count as stamped at `floor`, which only
+// costs reads already in flight their caching.
+#[derive(Debug, Default)]
+struct ReadCache {
+    values: HashMap<String, serde_json::Value>,
+    generation: u64,
+    stamps: HashMap<String, u64>,
+    floor: u64,
+}
+
+impl ReadCache {
+    // Returns the key's new stamp.
+    fn invalidate(&mut self, key: &str) -> u64 {
+        self.values.remove(key);
+        self.generation += 1;
+        if self.stamps.len() >= STAMPED_KEYS && !self.stamps.contains_key(key) {
+            self.stamps.clear();
+            self.floor = self.generation;
+        }
+        self.stamps.insert(key.to_string(), self.generation);
+        self.generation
+    }
+
+    fn stamp(&self, key: &str) -> u64 {
+        self.stamps.get(key).copied().unwrap_or(self.floor)
+    }
+
+    // Stamps `key` again once the mutation that stamped it `stamp` is
+    // acknowledged, so reads that went out while it was in flight don't
+    // cache what they saw. What it wrote is cached if nothing else stamped
+    // the key in between.
+    fn acknowledged(&mut self, key: &str, stamp: u64, written: Option<serde_json::Value>) {
+        let uncontended = self.stamp(key) == stamp;
+        self.invalidate(key);
+        if let (true, Some(value)) = (uncontended, written) {
+            self.values.insert(key.to_string(), value);
+        }
+    }
+}
+
+impl<S> CachedStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(RwLock::new(ReadCache::default())),
+        }
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.cache.write().unwrap().invalidate(key);
+    }
+
+    // The cached value, or the generation to hand to `remember` after reading.
+    fn cached(&self, key: &str) -> Result<serde_json::Value, u64> {
+        let cache = self.cache.read().unwrap();
+        match cache.values.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => Err(cache.generation),
+        }
+    }
+
+    fn remember(&self, key: String, generation: u64, value: serde_json::Value) {
+        let mut cache = self.cache.write().unwrap();
+        if cache.stamp(&key) <= generation {
+            cache.values.insert(key, value);
+        }
+    }
+
+    fn acknowledged(&self, key: &str, stamp: u64, written: Option<serde_json::Value>) {
+        self.cache
+            .write()
+            .unwrap()
+            .acknowledged(key, stamp, written);
+    }
+
+    fn mutating(&self, key: &str) -> u64 {
+        self.cache.write().unwrap().invalidate(key)
+    }
+}
+
+// Mutations invalidate before they go out and again once acknowledged.
+#[async_trait::async_trait]
+impl<S, IP> Storage<IP> for CachedStore<S>
+where
+    S: Storage<IP> + Sync,
+    IP: Send + Sync + Debug + Clone + 'static,
+{
+    fn node_id(&self) -> String {
+        self.inner.node_id()
+    }
+
+    fn address(&self) -> String {
+        self.inner.address()
+    }
+
+    async fn read_with_priority<T>(
+        &self,
+        key: String,
+        priority: Priority,
+        network: &Network<IP>,
+    ) -> Result<T, Error>
+    where
+        IP: Send + Debug + Clone + 'static,
+        T: DeserializeOwned,
+    {
+        let value = match self.cached(&key) {
+            Ok(value) => value,
+            Err(generation) => {
+                let value: serde_json::Value = self
+                    .inner
+                    .read_with_priority(key.clone(), priority, network)
+                    .await?;
+                self.remember(key, generation, value.clone());
+                value
+            }
+        };
+        serde_json::from_value(value).map_err(Error::deserialize)
+    }
+
+    async fn try_read<T>(&self, key: String, network: &Network<IP>) -> Result<Option<T>, Error>
+    where
+        IP: Send + Debug + Clone + 'static,
+        T: DeserializeOwned,
+    {
+        let value = match self.cached(&key) {
+            Ok(value) => value,
+            Err(generation) => {
+                let Some(value) = self
+                    .inner
+                    .try_read::<serde_json::Value>(key.clone(), network)
+                    .await?
+                else {
+                    return Ok(None);
+                };
+                self.remember(key, generation, value.clone());
+                value
+            }
+        };
+        serde_json::from_value(value)
+            .map(Some)
+            .map_err(Error::deserialize)
+    }
+
+    // Waits for the acknowledgement on a task of its own, so this still
+    // returns once the write is sent. Outside a runtime there's nothing to
+    // wait on, and the key is invalidated at once.
+    fn write<T>(&self, key: String, value: T, network: &Network<IP>) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let stamp = self.mutating(&key);
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            let result = self.inner.write(key.clone(), value, network);
+            self.invalidate(&key);
+            return result;
+        };
+
+        let value = serde_json::to_value(value).expect("failed to serialize value");
+        let message = self.construct_message(
+            self.node_id(),
+            StoragePayload::Write {
+                key: key.clone(),
+                value: value.clone(),
+            },
+        );
+        let cache = self.cache.clone();
+        let network = network.clone();
+        runtime.spawn(async move {
+            let written = match network.request(message).await {
+                Ok(reply) => match reply.body.payload.into_result() {
+                    Ok(StorageOutcome::Written) => Some(value),
+                    _ => None,
+                },
+                Err(_) => None,
+            };
+            cache.write().unwrap().acknowledged(&key, stamp, written);
+        });
+        Ok(())
+    }
+
+    async fn compare_and_store<T>(
+        &self,
+        key: String,
+        from: T,
+        to: T,
+        network: &Network<IP>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + Send,
+    {
+        let stamp = self.mutating(&key);
+        let written = serde_json::to_value(&to).ok();
+        let result = self
+            .inner
+            .compare_and_store(key.clone(), from, to, network)
+            .await;
+        self.acknowledged(&key, stamp, written.filter(|_| result.is_ok()));
+        result
+    }
+
+    async fn compare_and_store_opts<T>(
+        &self,
+        key: String,
+        from: T,
+        to: T,
+        create: bool,
+        network: &Network<IP>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + Send,
+    {
+        let stamp = self.mutating(&key);
+        let written = serde_json::to_value(&to).ok();
+        let result = self
+            .inner
+            .compare_and_store_opts(key.clone(), from, to, create, network)
+            .await;
+        self.acknowledged(&key, stamp, written.filter(|_| result.is_ok()));
+        result
+    }
+
+    async fn cas_or_current<T>(
+        &self,
+        key: String,
+        from: T,
+        to: T,
+        network: &Network<IP>,
+    ) -> Result<Result<(), CasConflict<T>>, Error>
+    where
+        T: Serialize + DeserializeOwned + Send,
+    {
+        let stamp = self.mutating(&key);
+        let written = serde_json::to_value(&to).ok();
+        let result = self
+            .inner
+            .cas_or_current(key.clone(), from, to, network)
+            .await;
+        let swapped = matches!(result, Ok(Ok(())));
+        self.acknowledged(&key, stamp, written.filter(|_| swapped));
+        result
+    }
+
+    async fn write_idempotent<T>(
+        &self,
+        key: String,
+        value: T,
+        token: IdempotencyToken,
+        network: &Network<IP>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    {
+        let stamp = self.mutating(&key);
+        let result = self
+            .inner
+            .write_idempotent(key.clone(), value, token, network)
+            .await;
+        self.acknowledged(&key, stamp, None);
+        result
+    }
+
+    async fn compare_and_store_idempotent<T>(
+        &self,
+        key: String,
+        from: T,
+        to: T,
+        token: IdempotencyToken,
+        network: &Network<IP>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    {
+        let stamp = self.mutating(&key);
+        let result = self
+            .inner
+            .compare_and_store_idempotent(key.clone(), from, to, token, network)
+            .await;
+        self.acknowledged(&key, stamp, None);
+        result
+    }
+}
+
+// What `VersionedStore` keeps under each key: the version, and the key its
+// value was written to. Only this is compared by the CAS, however large the
+// value.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct VersionRecord {
+    version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_key: Option<String>,
+}
+
+// What a value key holds: the value, until the record no longer points at it
+// and it's reclaimed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+enum StoredValue<T> {
+    Live(T),
+    Reclaimed,
+}
+
+// Stores each value with a version that every successful `write_if` bumps, so
+// a writer names the version it computed from instead of carrying the whole
+// old value around. The value goes to a key of its own, and the key itself
+// holds only a `VersionRecord` pointing at it, so the CAS compares a version
+// and a key name rather than the value. The last record and value seen for
+// each key are kept locally, so a writer at the current version doesn't read
+// first and a read only fetches a value it hasn't seen.
+//
+// Maelstrom's stores can't delete, so a value no record points at any more is
+// overwritten with `StoredValue::Reclaimed`: the old value once a write moves
+// the record past it, and a lost write's own value once its CAS fails. Each
+// write still leaves one small key behind, and a write whose CAS never
+// answers leaves its whole value, since it can't know whether it won.
+#[derive(Debug, Clone)]
+pub struct VersionedStore<S, T> {
+    inner: S,
+    seen: Arc<RwLock<HashMap<String, Seen<T>>>>,
+}
+
+// A key's newest record, with its value if that's been seen too.
+type Seen<T> = (VersionRecord, Option<T>);
+
+impl<S, T> VersionedStore<S, T>
+where
+    T: Serialize + DeserializeOwned + Clone + Default + Send + Sync,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            seen: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    // Always reads the key's record from storage. A key that was never
+    // written reads as the default value at version 0.
+    pub async fn read<IP>(&self, key: String, network: &Network<IP>) -> Result<(T, u64), Error>
+    where
+        S: Storage<IP> + Sync,
+        IP: Send + Debug + Clone + 'static,
+    {
+        loop {
+            let record = self.fetch(key.clone(), network).await?;
+            let seen = self
+                .seen
+                .read()
+                .unwrap()
+                .get(&key)
+                .filter(|(seen, _)| *seen == record)
+                .and_then(|(_, value)| value.clone());
+            let value = match (seen, &record.value_key) {
+                (Some(value), _) => value,
+                (None, None) => T::default(),
+                (None, Some(value_key)) => {
+                    let stored: StoredValue<T> = self
+                        .inner
+                        .read(value_key.clone(), network)
+                        .await
+                        .context("reading versioned value")?;
+                    // Reclaimed only once the record moved on, so the next
+                    // fetch sees a newer one.
+                    let StoredValue::Live(value) = stored else {
+                        continue;
+                    };
+                    self.remember(key, record.clone(), Some(value.clone()));
+                    value
+                }
+            };
+            return Ok((value, record.version));
+        }
+    }
+
+    // Stores `value` as the version after `version` if the key is still at
+    // `version`, and returns the new version. When another write got there
+    // first this fails with `Error::CasConflict` in the chain, and the next
+    // `read` returns the winner.
+    pub async fn write_if<IP>(
+        &self,
+        key: String,
+        version: u64,
+        value: T,
+        network: &Network<IP>,
+    ) -> Result<u64, Error>
+    where
+        S: Storage<IP> + Sync,
+        IP: Send + Debug + Clone + 'static,
+    {
+        // Versions only grow, so a record we've seen past `version` already
+        // settles it.
+        let seen = self.seen.read().unwrap().get(&key).cloned();
+        let current = match seen {
+            Some((seen, _)) if seen.version >= version => seen,
+            _ => self.fetch(key.clone(), network).await?,
+        };
+        if current.version != version {
+            return Err(Error::CasConflict(format!(
+                "{} is at version {}",
+                key, current.version
+            )));
+        }
+
+        // Named by a random suffix as well as the version, so a write that
+        // loses can't overwrite the winner's value.
+        let value_key = format!("{}@{}.{:x}", key, version + 1, rand::random::<u64>());
+        let stored = StoredValue::Live(&value);
+        self.inner
+            .compare_and_store_opts(value_key.clone(), &stored, &stored, true, network)
+            .await
+            .context("storing versioned value")?;
+        let next = VersionRecord {
+            version: version + 1,
+            value_key: Some(value_key.clone()),
+        };
+        match self
+            .inner
+            .cas_or_current(key.clone(), current.clone(), next.clone(), network)
+            .await
+            .context("writing version")?
+        {
+            Ok(()) => {
+                self.remember(key, next, Some(value));
+                if let Some(replaced) = current.value_key {
+                    self.reclaim(replaced, network);
+                }
+                Ok(version + 1)
+            }
+            Err(CasConflict { current }) => {
+                let at = current.version;
+                self.remember(key.clone(), current, None);
+                self.reclaim(value_key, network);
+                Err(Error::CasConflict(format!("{} is at version {}", key, at)))
+            }
+        }
+    }
+
+    // Frees a value key no record points at any more. Best effort: a value
+    // left behind only costs space.
+    fn reclaim<IP>(&self, value_key: String, network: &Network<IP>)
+    where
+        S: Storage<IP> + Sync,
+        IP: Send + Debug + Clone + 'static,
+    {
+        if let Err(e) = self
+            .inner
+            .write(value_key.clone(), StoredValue::<T>::Reclaimed, network)
+        {
+            eprintln!("failed to reclaim {}: {:#}", value_key, e);
+        }
+    }
+
+    async fn fetch<IP>(&self, key: String, network: &Network<IP>) -> Result<VersionRecord, Error>
+    where
+        S: Storage<IP> + Sync,
+        IP: Send + Debug + Clone + 'static,
+    {
+        let record = self
+            .inner
+            .try_read::<VersionRecord>(key.clone(), network)
+            .await
+            .context("reading version")?
+            .unwrap_or_default();
+        self.remember(key, record.clone(), None);
+        Ok(record)
+    }
+
+    // Keeps the newest record, as reads and writes can finish out of order,
+    // and the value already held for it when none comes along.
+    fn remember(&self, key: String, record: VersionRecord, value: Option<T>) {
+        let mut seen = self.seen.write().unwrap();
+        match seen.get_mut(&key) {
+            Some((held, held_value)) if *held == record => {
+                if value.is_some() {
+                    *held_value = value;
+                }
+            }
+            Some((held, _)) if held.version > record.version => {}
+            _ => {
+                seen.insert(key, (record, value));
+            }
+        }
+    }
+}
+
+// Client for Maelstrom's `lin-tso`, which hands out strictly increasing
+// timestamps.
+#[derive(Debug, Clone)]
+pub struct TimestampOracle {
+    _node_id: String,
+}
+
+impl TimestampOracle {
+    pub fn new(node_id: String) -> Self {
+        Self { _node_id: node_id }
+    }
+
+    pub async fn timestamp<IP>(&self, network: &Network<IP>) -> Result<u64, Error>
+    where
+        IP: Send + Debug + Clone + 'static,
+    {
+        let message = Message::new(
+            self._node_id.clone(),
+            TIMESTAMP_ORACLE_ADDRESS,
+            StoragePayload::Ts,
+        );
+
+        let response = network
+            .request(message)
+            .await
+            .context("fetching timestamp")?;
+
+        match response.body.payload.into_result() {
+            Ok(StorageOutcome::Timestamp(ts)) => Ok(ts),
+            Ok(outcome) => Err(Error::protocol(format!(
+                "unexpected ts outcome {:?}",
+                outcome
+            ))),
+            Err(e) => Err(e.context("ts request failed")),
+        }
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Storage<IP>: Send
 where
@@ -95,26 +770,68 @@ where
     fn node_id(&self) -> String;
     fn address(&self) -> String; // Should be static but needs a receiver to implement Send.
 
-    async fn read<T>(&self, key: String, network: &Network<IP>) -> anyhow::Result<T>
+    async fn read<T>(&self, key: String, network: &Network<IP>) -> Result<T, Error>
+    where
+        IP: Send + Debug + Clone + 'static,
+        T: DeserializeOwned,
+    {
+        self.read_with_priority(key, Priority::High, network).await
+    }
+
+    async fn read_with_priority<T>(
+        &self,
+        key: String,
+        priority: Priority,
+        network: &Network<IP>,
+    ) -> Result<T, Error>
     where
         IP: Send + Debug + Clone + 'static,
         T: DeserializeOwned,
     {
         let message = self.construct_message(self.node_id().clone(), StoragePayload::Read { key });
         let response = network
-            .request(message)
+            .request_with_priority(message, priority)
             .await
             .context("fetching value for key")?;
 
-        match response.body.payload {
-            StoragePayload::ReadOk { value } => {
-                serde_json::from_value(value).context("deserializing read value")
+        match response.body.payload.into_result() {
+            Ok(StorageOutcome::Read(value)) => {
+                serde_json::from_value(value).map_err(Error::deserialize)
             }
-            _ => Err(anyhow::anyhow!("error returned from read request")),
+            Ok(outcome) => Err(Error::protocol(format!(
+                "unexpected read outcome {:?}",
+                outcome
+            ))),
+            Err(e) => Err(e.context("read request failed")),
         }
     }
 
-    fn write<T>(&self, key: String, value: T, network: &Network<IP>) -> anyhow::Result<()>
+    // Like `read`, but a missing key is `Ok(None)` rather than an error.
+    async fn try_read<T>(&self, key: String, network: &Network<IP>) -> Result<Option<T>, Error>
+    where
+        IP: Send + Debug + Clone + 'static,
+        T: DeserializeOwned,
+    {
+        let message = self.construct_message(self.node_id().clone(), StoragePayload::Read { key });
+        let response = network
+            .request_with_priority(message, Priority::High)
+            .await
+            .context("fetching value for key")?;
+
+        match response.body.payload.into_result() {
+            Ok(StorageOutcome::Read(value)) => serde_json::from_value(value)
+                .map(Some)
+                .map_err(Error::deserialize),
+            Err(Error::KeyNotFound(_)) => Ok(None),
+            Ok(outcome) => Err(Error::protocol(format!(
+                "unexpected read outcome {:?}",
+                outcome
+            ))),
+            Err(e) => Err(e.context("read request failed")),
+        }
+    }
+
+    fn write<T>(&self, key: String, value: T, network: &Network<IP>) -> Result<(), Error>
     where
         T: Serialize,
     {
@@ -130,13 +847,31 @@ where
         Ok(())
     }
 
+    // Creates the key when it's missing, whatever `from` is.
     async fn compare_and_store<T>(
         &self,
         key: String,
         from: T,
         to: T,
         network: &Network<IP>,
-    ) -> anyhow::Result<()>
+    ) -> Result<(), Error>
+    where
+        T: Serialize + Send,
+    {
+        self.compare_and_store_opts(key, from, to, true, network)
+            .await
+    }
+
+    // With `create` off, a missing key fails with `KeyDoesNotExist` rather
+    // than being created.
+    async fn compare_and_store_opts<T>(
+        &self,
+        key: String,
+        from: T,
+        to: T,
+        create: bool,
+        network: &Network<IP>,
+    ) -> Result<(), Error>
     where
         T: Serialize + Send,
     {
@@ -146,6 +881,43 @@ where
                 key,
                 from: serde_json::to_value(from).expect("failed to serialize from"),
                 to: serde_json::to_value(to).expect("failed to serialize to"),
+                create_if_not_exists: Some(create),
+            },
+        );
+
+        let response = network
+            .request(message)
+            .await
+            .context("writing value for key")?;
+
+        match response.body.payload.into_result() {
+            Ok(StorageOutcome::Swapped) => Ok(()),
+            Ok(outcome) => Err(Error::protocol(format!(
+                "unexpected cas outcome {:?}",
+                outcome
+            ))),
+            Err(e) => Err(e.context("cas request failed")),
+        }
+    }
+
+    // Like `compare_and_store`, but a precondition failure reads back the value
+    // that beat us so the caller can recompute from it directly.
+    async fn cas_or_current<T>(
+        &self,
+        key: String,
+        from: T,
+        to: T,
+        network: &Network<IP>,
+    ) -> Result<Result<(), CasConflict<T>>, Error>
+    where
+        T: Serialize + DeserializeOwned + Send,
+    {
+        let message = self.construct_message(
+            self.node_id().clone(),
+            StoragePayload::Cas {
+                key: key.clone(),
+                from: serde_json::to_value(from).expect("failed to serialize from"),
+                to: serde_json::to_value(to).expect("failed to serialize to"),
                 create_if_not_exists: Some(true),
             },
         );
@@ -155,21 +927,266 @@ where
             .await
             .context("writing value for key")?;
 
-        match response.body.payload {
-            StoragePayload::CasOk => Ok(()),
-            _ => Err(anyhow::anyhow!("error returned from cas request")),
+        match response.body.payload.into_result() {
+            Ok(StorageOutcome::Swapped) => Ok(Ok(())),
+            Err(Error::CasConflict(_)) => {
+                let current = self
+                    .read(key, network)
+                    .await
+                    .context("reading current value after cas conflict")?;
+                Ok(Err(CasConflict { current }))
+            }
+            Ok(outcome) => Err(Error::protocol(format!(
+                "unexpected cas outcome {:?}",
+                outcome
+            ))),
+            Err(e) => Err(e.context("cas request failed")),
+        }
+    }
+
+    // Runs read -> compute -> CAS until the CAS lands and returns the value
+    // committed. `compute` sees `None` while the key doesn't exist, in which
+    // case the CAS creates it. A conflict recomputes from the value that won;
+    // any other failure backs off and starts over with a fresh read. Both
+    // count against the network's retry policy, and a failure it doesn't
+    // retry is returned.
+    async fn read_cas<T, F>(
+        &self,
+        key: String,
+        compute: F,
+        network: &Network<IP>,
+    ) -> Result<T, Error>
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync,
+        F: Fn(Option<T>) -> T + Send + Sync,
+    {
+        let mut retry = Retry::with_policy(network.retry_policy().clone());
+        let mut current = None;
+        // Reads fail like any other request, so they're retried by the same
+        // policy as the CAS.
+        let mut stale = true;
+        loop {
+            if stale {
+                match self.try_read::<T>(key.clone(), network).await {
+                    Ok(read) => {
+                        current = read;
+                        stale = false;
+                    }
+                    Err(e) => {
+                        if !retry.should_retry(&e) {
+                            return Err(e.context("reading value to update"));
+                        }
+                        retry.backoff().await;
+                        continue;
+                    }
+                }
+            }
+
+            let next = compute(current.clone());
+            // A missing key is created whatever `from` is. Should another
+            // writer create it first, `from` must not match what it stored,
+            // or the CAS would succeed without `compute` having seen it.
+            let from = match &current {
+                Some(current) => serde_json::to_value(current).expect("failed to serialize from"),
+                None => serde_json::json!({ "never_stored": rand::random::<u64>() }),
+            };
+            let to = serde_json::to_value(&next).expect("failed to serialize to");
+
+            match self.cas_or_current(key.clone(), from, to, network).await {
+                Ok(Ok(())) => return Ok(next),
+                Ok(Err(CasConflict { current: winner })) => {
+                    let conflict = Error::CasConflict(key.clone());
+                    if !retry.should_retry(&conflict) {
+                        return Err(conflict);
+                    }
+                    current = Some(serde_json::from_value(winner).map_err(Error::deserialize)?);
+                }
+                Err(e) => {
+                    dbg!("READ CAS FAILED", &key, format!("{:#}", e));
+                    if !retry.should_retry(&e) {
+                        return Err(e.context("updating value"));
+                    }
+                    stale = true;
+                }
+            }
+            retry.backoff().await;
+        }
+    }
+
+    // CASes each `(key, from, to)` in order. The keys are independent, so this
+    // isn't atomic: when one CAS fails the ones already applied are rolled back
+    // (best effort). A lost race comes back as the conflicting key, any other
+    // failure as the error; either way only after the rollback, which names
+    // the keys it couldn't restore. Readers can observe an aborted attempt's
+    // writes until the rollback lands.
+    async fn multi_cas<T>(
+        &self,
+        pairs: Vec<(String, T, T)>,
+        network: &Network<IP>,
+    ) -> Result<Result<(), KeyConflict>, Error>
+    where
+        T: Serialize + Clone + Send + Sync,
+    {
+        let mut applied = Vec::with_capacity(pairs.len());
+        for (key, from, to) in pairs {
+            let error = match self
+                .compare_and_store(key.clone(), from.clone(), to.clone(), network)
+                .await
+            {
+                Ok(()) => {
+                    applied.push((key, from, to));
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            let mut unrolled = Vec::new();
+            for (applied_key, from, to) in applied.into_iter().rev() {
+                if let Err(e) = self
+                    .compare_and_store(applied_key.clone(), to, from, network)
+                    .await
+                {
+                    eprintln!("failed to roll back {}: {:#}", applied_key, e);
+                    unrolled.push(applied_key);
+                }
+            }
+
+            return match error {
+                Error::CasConflict(_) => Ok(Err(KeyConflict { key, unrolled })),
+                e if unrolled.is_empty() => Err(e.context(format!("cas on {}", key))),
+                e => Err(e.context(format!("cas on {}, leaving {:?} unrolled", key, unrolled))),
+            };
+        }
+
+        Ok(Ok(()))
+    }
+
+    // Writes `value` as an `IdempotentValue`, waiting for the write to be
+    // acknowledged. Retrying with the same token after an attempt that was
+    // applied is a no-op, whether or not its acknowledgement arrived.
+    async fn write_idempotent<T>(
+        &self,
+        key: String,
+        value: T,
+        token: IdempotencyToken,
+        network: &Network<IP>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    {
+        let mut retry = Retry::with_policy(network.retry_policy().clone());
+        let mut current = self
+            .try_read::<IdempotentValue<T>>(key.clone(), network)
+            .await
+            .context("reading value to write")?;
+        loop {
+            if current.as_ref().is_some_and(|stored| stored.applied(token)) {
+                return Ok(());
+            }
+            let next = match &current {
+                Some(stored) => stored.then(value.clone(), token),
+                None => IdempotentValue::new(value.clone(), token),
+            };
+            // A missing key is created whatever `from` is.
+            let from = current.unwrap_or_else(|| next.clone());
+
+            match self.cas_or_current(key.clone(), from, next, network).await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(CasConflict { current: winner })) => {
+                    let conflict = Error::CasConflict(key.clone());
+                    if !retry.should_retry(&conflict) {
+                        return Err(conflict);
+                    }
+                    current = Some(winner);
+                }
+                Err(e) => {
+                    if !retry.should_retry(&e) {
+                        return Err(e.context("write request failed"));
+                    }
+                    current = self
+                        .try_read::<IdempotentValue<T>>(key.clone(), network)
+                        .await
+                        .context("re-reading value to write")?;
+                }
+            }
+            retry.backoff().await;
+        }
+    }
+
+    // Swaps the `IdempotentValue` under `key` from `from` to `to`, creating it
+    // when it's missing. Retrying with the same token after an attempt that
+    // was applied is a no-op, even though the value no longer matches `from`.
+    async fn compare_and_store_idempotent<T>(
+        &self,
+        key: String,
+        from: T,
+        to: T,
+        token: IdempotencyToken,
+        network: &Network<IP>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    {
+        let from = serde_json::to_value(from).expect("failed to serialize from");
+        let mut retry = Retry::with_policy(network.retry_policy().clone());
+        let mut current = self
+            .try_read::<IdempotentValue<T>>(key.clone(), network)
+            .await
+            .context("reading value to swap")?;
+        loop {
+            let next = match &current {
+                Some(stored) if stored.applied(token) => return Ok(()),
+                Some(stored) if serde_json::to_value(&stored.value).ok() != Some(from.clone()) => {
+                    return Err(Error::CasConflict(key));
+                }
+                Some(stored) => stored.then(to.clone(), token),
+                None => IdempotentValue::new(to.clone(), token),
+            };
+            let expected = current.unwrap_or_else(|| next.clone());
+
+            // Only a racing write to the same key lands here, and it may have
+            // been our own earlier attempt, so the winner is checked again.
+            match self
+                .cas_or_current(key.clone(), expected, next, network)
+                .await
+            {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(CasConflict { current: winner })) => current = Some(winner),
+                Err(e) => {
+                    if !retry.should_retry(&e) {
+                        return Err(e.context("cas request failed"));
+                    }
+                    retry.backoff().await;
+                    current = self
+                        .try_read::<IdempotentValue<T>>(key.clone(), network)
+                        .await
+                        .context("re-reading value to swap")?;
+                }
+            }
         }
     }
 
     fn construct_message<PAYLOAD>(&self, node_id: String, payload: PAYLOAD) -> Message<PAYLOAD> {
-        Message {
-            src: node_id,
-            dst: self.address(),
-            body: Body {
-                id: None,
-                in_reply_to: None,
-                payload,
-            },
+        Message::new(node_id, self.address(), payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_cache_stamps_stay_bounded() {
+        let mut cache = ReadCache::default();
+        let generation = cache.generation;
+        for key in 0..10 * STAMPED_KEYS {
+            cache.invalidate(&key.to_string());
         }
+        assert!(cache.stamps.len() <= STAMPED_KEYS);
+        // A read issued before the stamps were dropped still counts as racing
+        // every key whose stamp went with them.
+        let dropped = "0";
+        assert!(!cache.stamps.contains_key(dropped));
+        assert!(cache.stamp(dropped) > generation);
     }
 }
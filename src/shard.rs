@@ -0,0 +1,123 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+const VIRTUAL_NODES: usize = 16;
+
+// How keys are spread over classes. `Modulo` sends numeric key `k` to class
+// `k % n` and hashes anything else. `Hash` hashes every key, which evens out
+// skewed ids. `Consistent` places each class at `VIRTUAL_NODES` points on a
+// hash ring and gives a key to the first point at or after its hash, so
+// changing the number of classes only moves the keys next to the changed
+// points.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClassStrategy {
+    Modulo,
+    #[default]
+    Hash,
+    Consistent,
+}
+
+#[derive(Debug, Clone)]
+pub struct Classes {
+    strategy: ClassStrategy,
+    n_classes: usize,
+    // `(point, class)` pairs sorted by point, for `ClassStrategy::Consistent`.
+    ring: Vec<(u64, usize)>,
+}
+
+impl Classes {
+    pub fn new(strategy: ClassStrategy, n_classes: usize) -> Self {
+        assert!(n_classes > 0, "at least one class is required");
+        let mut ring = Vec::new();
+        if strategy == ClassStrategy::Consistent {
+            ring = (0..n_classes)
+                .flat_map(|class| (0..VIRTUAL_NODES).map(move |i| (hash_of(&(class, i)), class)))
+                .collect();
+            ring.sort();
+        }
+
+        Self {
+            strategy,
+            n_classes,
+            ring,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.n_classes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_classes == 0
+    }
+
+    pub fn class_of(&self, key: &str) -> usize {
+        let n_classes = self.n_classes as u64;
+        match self.strategy {
+            ClassStrategy::Modulo => match key.parse::<u64>() {
+                Ok(id) => (id % n_classes) as usize,
+                Err(_) => (hash_of(key) % n_classes) as usize,
+            },
+            ClassStrategy::Hash => (hash_of(key) % n_classes) as usize,
+            ClassStrategy::Consistent => {
+                let point = hash_of(key);
+                let i = self.ring.partition_point(|(p, _)| *p < point);
+                self.ring[i % self.ring.len()].1
+            }
+        }
+    }
+}
+
+fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // How many of `keys` each class gets.
+    fn loads(classes: &Classes, keys: &[String]) -> Vec<usize> {
+        let mut loads = vec![0; classes.len()];
+        for key in keys {
+            loads[classes.class_of(key)] += 1;
+        }
+        loads
+    }
+
+    // Ids that are all multiples of the class count pile onto one class by
+    // modulo but spread evenly by hash.
+    #[test]
+    fn hashing_balances_skewed_ids() {
+        let keys: Vec<String> = (0..6000).map(|i| (i * 6).to_string()).collect();
+
+        let modulo = loads(&Classes::new(ClassStrategy::Modulo, 6), &keys);
+        assert_eq!(modulo, [6000, 0, 0, 0, 0, 0]);
+
+        for strategy in [ClassStrategy::Hash, ClassStrategy::Consistent] {
+            let loads = loads(&Classes::new(strategy, 6), &keys);
+            let (least, most) = (loads.iter().min().unwrap(), loads.iter().max().unwrap());
+            assert!(*least > 500 && *most < 1700, "{:?}: {:?}", strategy, loads);
+        }
+    }
+
+    // Adding a sixth class only moves keys onto it under consistent hashing,
+    // where plain hashing reshuffles most of them.
+    #[test]
+    fn consistent_hashing_moves_few_keys_when_a_class_is_added() {
+        let keys: Vec<String> = (0..6000).map(|i| format!("topic-{}", i)).collect();
+        let moved = |strategy| {
+            let (before, after) = (Classes::new(strategy, 5), Classes::new(strategy, 6));
+            keys.iter()
+                .filter(|key| before.class_of(key) != after.class_of(key))
+                .map(|key| after.class_of(key))
+                .collect::<Vec<_>>()
+        };
+
+        let consistent = moved(ClassStrategy::Consistent);
+        assert!(consistent.iter().all(|class| *class == 5));
+        assert!(consistent.len() < 2000, "{} moved", consistent.len());
+        assert!(moved(ClassStrategy::Hash).len() > 4000);
+    }
+}
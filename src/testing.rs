@@ -0,0 +1,189 @@
+use std::{collections::BTreeMap, fmt::Debug, marker::PhantomData, time::Duration};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    network::{Network, Received},
+    protocol::{Init, UntypedMessage},
+    transport::{Output, Transport},
+    Message, NetworkEvent, Node,
+};
+
+// Idle passes `settle` waits through before deciding nothing more will move.
+const SETTLE_PASSES: usize = 5;
+
+// Runs a cluster of nodes in-process for tests, each on a network with an
+// in-memory transport. Every node steps its events as `serve` would, but
+// messages between nodes only move in `settle`, and anything addressed
+// outside the cluster, e.g. to a client, is kept for the test to inspect.
+// Needs a tokio runtime, which runs the nodes.
+pub struct Cluster<N, P, IP = ()> {
+    members: BTreeMap<String, Member<N, IP>>,
+    outside: Vec<UntypedMessage>,
+    _payload: PhantomData<P>,
+}
+
+struct Member<N, IP> {
+    node: N,
+    network: Network<IP>,
+    output: Output,
+    dispatcher: tokio::task::JoinHandle<()>,
+}
+
+impl<N, P, IP> Cluster<N, P, IP>
+where
+    N: Node<P, IP> + Clone + Send + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Debug + Clone + Send + Sync + 'static,
+{
+    pub fn start<F>(node_ids: &[&str], construct: F) -> anyhow::Result<Self>
+    where
+        F: Fn(Init, &Network<IP>) -> anyhow::Result<N>,
+    {
+        Self::start_with(node_ids, |_, network| network, construct)
+    }
+
+    // `configure` sees each node's id and its network before the node is
+    // built, e.g. to set a clock or install a mock store.
+    pub fn start_with<C, F>(node_ids: &[&str], configure: C, construct: F) -> anyhow::Result<Self>
+    where
+        C: Fn(&str, Network<IP>) -> Network<IP>,
+        F: Fn(Init, &Network<IP>) -> anyhow::Result<N>,
+    {
+        let cluster: Vec<String> = node_ids.iter().map(|id| id.to_string()).collect();
+        let mut members = BTreeMap::new();
+        for node_id in &cluster {
+            let (transport, output) = Transport::in_memory("");
+            let mut network = configure(node_id, Network::with_transport(transport));
+            network.set_handled_types(N::handled_types());
+            let init = Init {
+                node_id: node_id.clone(),
+                node_ids: cluster.clone(),
+            };
+            network.set_init(init.clone())?;
+            let node = construct(init, &network).context(format!("constructing {}", node_id))?;
+            let dispatcher = tokio::spawn(dispatch::<N, P, IP>(node.clone(), network.clone()));
+            members.insert(
+                node_id.clone(),
+                Member {
+                    node,
+                    network,
+                    output,
+                    dispatcher,
+                },
+            );
+        }
+
+        Ok(Self {
+            members,
+            outside: Vec::new(),
+            _payload: PhantomData,
+        })
+    }
+
+    // The node every step is cloned from, so state it shares between clones
+    // shows what the steps did.
+    pub fn node(&self, node_id: &str) -> &N {
+        &self.member(node_id).node
+    }
+
+    pub fn network(&self, node_id: &str) -> &Network<IP> {
+        &self.member(node_id).network
+    }
+
+    // Delivers `message` to the node it's addressed to, as if from outside.
+    pub fn send<Q: Serialize>(&self, message: Message<Q>) {
+        let message = UntypedMessage::from(message);
+        let network = self.network(&message.dst);
+        if network.tx.send(NetworkEvent::Message(message)).is_err() {
+            panic!("node stopped receiving");
+        }
+    }
+
+    // Routes messages between nodes until none have been written for a few
+    // passes. Returns how many were routed, including ones sent outside.
+    pub async fn settle(&mut self) -> usize {
+        let mut routed = 0;
+        let mut idle = 0;
+        while idle < SETTLE_PASSES {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            match self.route() {
+                0 => {
+                    idle += 1;
+                    // Gives work on other threads, e.g. a mock store's
+                    // delayed replies, a chance to land.
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                n => {
+                    routed += n;
+                    idle = 0;
+                }
+            }
+        }
+        routed
+    }
+
+    // Everything sent outside the cluster since the last call, in order.
+    pub fn take_outside(&mut self) -> Vec<UntypedMessage> {
+        self.route();
+        std::mem::take(&mut self.outside)
+    }
+
+    fn route(&mut self) -> usize {
+        let written: Vec<UntypedMessage> = self
+            .members
+            .values()
+            .flat_map(|member| member.output.take_lines())
+            .map(|line| serde_json::from_str(&line).expect("nodes write JSON messages"))
+            .collect();
+        let routed = written.len();
+        for message in written {
+            match self.members.get(&message.dst) {
+                Some(member) => {
+                    // A stopped node drops what's sent to it, as a crashed one would.
+                    let _ = member.network.tx.send(NetworkEvent::Message(message));
+                }
+                None => self.outside.push(message),
+            }
+        }
+        routed
+    }
+
+    fn member(&self, node_id: &str) -> &Member<N, IP> {
+        self.members
+            .get(node_id)
+            .unwrap_or_else(|| panic!("{} is not in the cluster", node_id))
+    }
+}
+
+impl<N, P, IP> Drop for Cluster<N, P, IP> {
+    fn drop(&mut self) {
+        for member in self.members.values() {
+            member.dispatcher.abort();
+        }
+    }
+}
+
+// The serve loop without the server: every event gets a step of its own.
+async fn dispatch<N, P, IP>(node: N, network: Network<IP>)
+where
+    N: Node<P, IP> + Clone + Send + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Debug + Clone + Send + Sync + 'static,
+{
+    while let Some(received) = network.recv_received::<P>().await {
+        let Received::Event(_, event) = received else {
+            continue;
+        };
+        let mut node = node.clone();
+        let network = network.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::deliver(&mut node, event, &network).await {
+                eprintln!("step failed: {:#}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    fmt::Debug,
+    io::{BufRead, BufReader, Cursor, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+type Reader = Box<dyn BufRead + Send>;
+
+// Keeps whatever part of a line was read before an I/O error so that a retry
+// picks up where the failed read stopped.
+struct LineReader {
+    inner: Reader,
+    partial: String,
+}
+
+impl LineReader {
+    fn new(inner: Reader) -> Self {
+        Self {
+            inner,
+            partial: String::new(),
+        }
+    }
+}
+type Writer = Box<dyn Write + Send>;
+
+// Gossip and poll payloads can run to tens of kilobytes per line.
+pub const DEFAULT_READ_CAPACITY: usize = 64 * 1024;
+
+// Recordings are the wire lines prefixed with the direction they travelled.
+const INBOUND_MARKER: &str = "< ";
+const OUTBOUND_MARKER: &str = "> ";
+
+// Collects everything written to a `Transport::in_memory`, for tests and
+// local simulations that need to see what a node sent.
+#[derive(Clone, Default)]
+pub struct Output {
+    written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Output")
+            .field("lines", &self.lines().len())
+            .finish()
+    }
+}
+
+impl Output {
+    // Every complete line written so far.
+    pub fn lines(&self) -> Vec<String> {
+        let written = self.written.lock().unwrap();
+        String::from_utf8_lossy(&written)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    // Like `lines`, but clears them, so the next call only sees newer ones.
+    pub fn take_lines(&self) -> Vec<String> {
+        let lines = self.lines();
+        self.written.lock().unwrap().clear();
+        lines
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Line-delimited JSON in and out. Maelstrom talks over stdin/stdout, but any
+// reader/writer pair works as long as every message sits on its own line.
+#[derive(Clone)]
+pub struct Transport {
+    reader: Arc<Mutex<LineReader>>,
+    writer: Arc<Mutex<Writer>>,
+    recorder: Option<Arc<Mutex<Writer>>>,
+}
+
+impl Debug for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transport").finish_non_exhaustive()
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::stdio()
+    }
+}
+
+impl Transport {
+    pub fn stdio() -> Self {
+        Self::stdio_with_capacity(DEFAULT_READ_CAPACITY)
+    }
+
+    pub fn stdio_with_capacity(capacity: usize) -> Self {
+        Self::from_io(
+            BufReader::with_capacity(capacity, std::io::stdin()),
+            std::io::stdout(),
+        )
+    }
+
+    pub fn from_io<R, W>(reader: R, writer: W) -> Self
+    where
+        R: BufRead + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        Self {
+            reader: Arc::new(Mutex::new(LineReader::new(Box::new(reader)))),
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            recorder: None,
+        }
+    }
+
+    // Reads `input` and keeps whatever is written in the returned `Output`.
+    pub fn in_memory(input: impl Into<String>) -> (Self, Output) {
+        let output = Output::default();
+        let reader = Cursor::new(input.into().into_bytes());
+        (Self::from_io(reader, output.clone()), output)
+    }
+
+    pub fn with_reader<R>(mut self, reader: R) -> Self
+    where
+        R: BufRead + Send + 'static,
+    {
+        self.reader = Arc::new(Mutex::new(LineReader::new(Box::new(reader))));
+        self
+    }
+
+    // Tees every inbound and outbound line into `recorder`.
+    pub fn with_recorder<W>(mut self, recorder: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        self.recorder = Some(Arc::new(Mutex::new(Box::new(recorder))));
+        self
+    }
+
+    // Extracts the inbound half of a recording so it can be fed back through
+    // `with_reader` in place of live input.
+    pub fn load_recording(path: &Path) -> anyhow::Result<Cursor<Vec<u8>>> {
+        let recording = std::fs::read_to_string(path)
+            .context(format!("reading recording {}", path.display()))?;
+
+        let mut inbound = String::new();
+        for line in recording.lines() {
+            if let Some(line) = line.strip_prefix(INBOUND_MARKER) {
+                inbound.push_str(line);
+                inbound.push('\n');
+            }
+        }
+
+        Ok(Cursor::new(inbound.into_bytes()))
+    }
+
+    pub fn connect(addr: SocketAddr) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr).context(format!("connecting to {}", addr))?;
+        Self::from_tcp(stream)
+    }
+
+    pub fn listen(addr: SocketAddr) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).context(format!("binding to {}", addr))?;
+        let (stream, _) = listener
+            .accept()
+            .context(format!("accepting connection on {}", addr))?;
+        Self::from_tcp(stream)
+    }
+
+    fn from_tcp(stream: TcpStream) -> anyhow::Result<Self> {
+        let reader = stream.try_clone().context("cloning tcp stream")?;
+        Ok(Self::from_io(BufReader::new(reader), stream))
+    }
+
+    // Returns `None` once the input is exhausted.
+    pub fn read_line(&self) -> std::io::Result<Option<String>> {
+        let mut line = {
+            let mut reader = self.reader.lock().unwrap();
+            let LineReader { inner, partial } = &mut *reader;
+            if inner.read_line(partial)? == 0 && partial.is_empty() {
+                return Ok(None);
+            }
+            std::mem::take(partial)
+        };
+
+        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(trimmed);
+        self.record(INBOUND_MARKER, &line)?;
+        Ok(Some(line))
+    }
+
+    pub fn write_line(&self, line: &str) -> std::io::Result<()> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            writeln!(writer, "{}", line)?;
+            writer.flush()?;
+        }
+        self.record(OUTBOUND_MARKER, line)
+    }
+
+    // Tolerates a poisoned lock so it can still run while unwinding.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .flush()?;
+
+        if let Some(recorder) = &self.recorder {
+            recorder
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .flush()?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, marker: &str, line: &str) -> std::io::Result<()> {
+        let Some(recorder) = &self.recorder else {
+            return Ok(());
+        };
+
+        let mut recorder = recorder.lock().unwrap();
+        writeln!(recorder, "{}{}", marker, line)?;
+        recorder.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    // Counts the reads that get past the buffer.
+    struct CountingReader {
+        inner: Cursor<Vec<u8>>,
+        reads: Arc<Mutex<usize>>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            *self.reads.lock().unwrap() += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    fn reads_for(input: &[u8], capacity: usize) -> usize {
+        let reads = Arc::new(Mutex::new(0));
+        let reader = CountingReader {
+            inner: Cursor::new(input.to_vec()),
+            reads: reads.clone(),
+        };
+        let transport =
+            Transport::from_io(BufReader::with_capacity(capacity, reader), std::io::sink());
+        while transport.read_line().unwrap().is_some() {}
+        let reads = *reads.lock().unwrap();
+        reads
+    }
+
+    #[test]
+    fn default_capacity_reads_large_lines_in_fewer_reads() {
+        let line = format!("{}\n", "x".repeat(100 * 1024));
+        let input = line.repeat(10);
+
+        let std_reads = reads_for(input.as_bytes(), 8 * 1024);
+        let reads = reads_for(input.as_bytes(), DEFAULT_READ_CAPACITY);
+        assert!(
+            reads * 4 < std_reads,
+            "{} reads, {} with std's",
+            reads,
+            std_reads
+        );
+    }
+}
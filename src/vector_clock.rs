@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+// One counter per node; a missing node counts as zero.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct VectorClock(HashMap<String, usize>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node: &str) -> usize {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, node: &str) {
+        *self.0.entry(node.to_string()).or_default() += 1;
+    }
+
+    // Component-wise max, so the result has seen everything either side had.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, count) in &other.0 {
+            let ours = self.0.entry(node.clone()).or_default();
+            *ours = (*ours).max(*count);
+        }
+    }
+
+    // True when every component is at most `other`'s and at least one is
+    // strictly smaller.
+    pub fn happens_before(&self, other: &VectorClock) -> bool {
+        let nodes = self.0.keys().chain(other.0.keys());
+        let mut strictly = false;
+        for node in nodes {
+            let (ours, theirs) = (self.get(node), other.get(node));
+            if ours > theirs {
+                return false;
+            }
+            strictly |= ours < theirs;
+        }
+        strictly
+    }
+
+    pub fn concurrent_with(&self, other: &VectorClock) -> bool {
+        !self.happens_before(other) && !other.happens_before(self) && self != other
+    }
+}
+
+// Equal when every node's counter is, so `{a: 0}` equals `{}`.
+impl PartialEq for VectorClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .keys()
+            .chain(other.0.keys())
+            .all(|node| self.get(node) == other.get(node))
+    }
+}
+
+impl Eq for VectorClock {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(counts: &[(&str, usize)]) -> VectorClock {
+        VectorClock(
+            counts
+                .iter()
+                .map(|(node, count)| (node.to_string(), *count))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn happens_before_needs_every_component_at_most_and_one_below() {
+        let a = clock(&[("n1", 1)]);
+        let b = clock(&[("n1", 1), ("n2", 1)]);
+        assert!(a.happens_before(&b));
+        assert!(!b.happens_before(&a));
+        assert!(!a.happens_before(&a));
+        assert!(VectorClock::new().happens_before(&a));
+    }
+
+    #[test]
+    fn clocks_that_each_saw_something_new_are_concurrent() {
+        let a = clock(&[("n1", 2), ("n2", 1)]);
+        let b = clock(&[("n1", 1), ("n2", 2)]);
+        assert!(a.concurrent_with(&b));
+        assert!(b.concurrent_with(&a));
+
+        let mut merged = a.clone();
+        merged.merge(&b);
+        assert_eq!(merged, clock(&[("n1", 2), ("n2", 2)]));
+        assert!(a.happens_before(&merged) && b.happens_before(&merged));
+    }
+
+    #[test]
+    fn zero_counters_are_the_same_as_missing_ones() {
+        let zeroed = clock(&[("n1", 0)]);
+        assert_eq!(zeroed, VectorClock::new());
+        assert!(!zeroed.concurrent_with(&VectorClock::new()));
+        assert!(!zeroed.happens_before(&VectorClock::new()));
+    }
+
+    #[test]
+    fn increment_moves_a_clock_past_its_old_self() {
+        let before = clock(&[("n1", 3)]);
+        let mut after = before.clone();
+        after.increment("n2");
+        assert!(before.happens_before(&after));
+        assert_eq!(after.get("n2"), 1);
+    }
+}